@@ -0,0 +1,128 @@
+use std::num::Float;
+use std::f64::consts::PI;
+
+/**
+ * Smallest power of two greater than or equal to n.
+ */
+pub fn fft_size(n: uint) -> uint {
+    let mut size = 1u;
+    while size < n {
+        size <<= 1;
+    }
+    size
+}
+
+/**
+ * Power spectrum of a frame, Hann-windowed and zero-padded to size (a power
+ * of two). Returns size / 2 + 1 bins, bin k being at k * rate / size Hz.
+ */
+pub fn power_spectrum(frame: &[f64], size: uint) -> Vec<f64> {
+    let mut re = Vec::from_elem(size, 0f64);
+    let mut im = Vec::from_elem(size, 0f64);
+    let n = frame.len();
+    for i in range(0, n) {
+        let w = if n > 1 { 0.5 - 0.5 * (2.0 * PI * i as f64 / (n - 1) as f64).cos() } else { 1.0 };
+        re[i] = frame[i] * w;
+    }
+    fft(re.as_mut_slice(), im.as_mut_slice());
+    range(0, size / 2 + 1).map(|k| re[k] * re[k] + im[k] * im[k]).collect()
+}
+
+/**
+ * In-place iterative radix-2 FFT. The length must be a power of two.
+ */
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    // bit-reversal permutation
+    let mut j = 0u;
+    for i in range(1, n) {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2u;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        let (wr, wi) = (angle.cos(), angle.sin());
+        let mut start = 0u;
+        while start < n {
+            let (mut cr, mut ci) = (1f64, 0f64);
+            for k in range(0, len / 2) {
+                let a = start + k;
+                let b = a + len / 2;
+                let tr = re[b] * cr - im[b] * ci;
+                let ti = re[b] * ci + im[b] * cr;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+                let next_cr = cr * wr - ci * wi;
+                ci = cr * wi + ci * wr;
+                cr = next_cr;
+            }
+            start += len;
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+    use std::f64::consts::PI;
+    use super::{fft, fft_size, power_spectrum};
+
+    fn dft(re: &[f64], im: &[f64]) -> (Vec<f64>, Vec<f64>) {
+        let n = re.len();
+        let mut out_re = Vec::from_elem(n, 0f64);
+        let mut out_im = Vec::from_elem(n, 0f64);
+        for k in range(0, n) {
+            for t in range(0, n) {
+                let angle = -2.0 * PI * (k * t) as f64 / n as f64;
+                out_re[k] += re[t] * angle.cos() - im[t] * angle.sin();
+                out_im[k] += re[t] * angle.sin() + im[t] * angle.cos();
+            }
+        }
+        (out_re, out_im)
+    }
+
+    #[test]
+    fn fft_matches_the_dft() {
+        for &n in [1u, 2, 8, 64, 256].iter() {
+            let re: Vec<f64> = range(0, n).map(|i| ((i * 7919) % 101) as f64 / 50.0 - 1.0).collect();
+            let im: Vec<f64> = range(0, n).map(|i| ((i * 104729) % 37) as f64 / 18.0 - 1.0).collect();
+            let (expected_re, expected_im) = dft(re.as_slice(), im.as_slice());
+            let (mut fft_re, mut fft_im) = (re.clone(), im.clone());
+            fft(fft_re.as_mut_slice(), fft_im.as_mut_slice());
+            for k in range(0, n) {
+                assert!((fft_re[k] - expected_re[k]).abs() < 1e-9, "n={} k={}", n, k);
+                assert!((fft_im[k] - expected_im[k]).abs() < 1e-9, "n={} k={}", n, k);
+            }
+        }
+    }
+
+    #[test]
+    fn sizes_are_powers_of_two() {
+        assert_eq!(fft_size(1), 1);
+        assert_eq!(fft_size(160), 256);
+        assert_eq!(fft_size(256), 256);
+        assert_eq!(fft_size(257), 512);
+    }
+
+    #[test]
+    fn tone_peaks_at_its_bin() {
+        // 1kHz at 16kHz, in a 10ms frame: bin 1000 * 256 / 16000 = 16
+        let frame: Vec<f64> = range(0, 160u).map(|i| (2.0 * PI * 1000.0 * i as f64 / 16000.0).sin()).collect();
+        let spectrum = power_spectrum(frame.as_slice(), 256);
+        assert_eq!(spectrum.len(), 129);
+        let peak = range(0, spectrum.len()).max_by(|&k| (spectrum[k] * 1e6) as u64).unwrap();
+        assert_eq!(peak, 16);
+    }
+}
@@ -0,0 +1,27 @@
+use std::num::Float;
+
+pub mod fft;
+
+/**
+ * Level of a frame of samples in [-1, 1], in dBFS (-100 for silence).
+ */
+pub fn dbfs(frame: &[f64]) -> f64 {
+    let power = frame.iter().fold(0f64, |acc, &x| acc + x * x) / frame.len() as f64;
+    if power <= 1e-10 { -100.0 } else { 10.0 * power.log10() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+    use std::f64::consts::PI;
+    use super::dbfs;
+
+    #[test]
+    fn levels_in_dbfs() {
+        // 10 periods of a full-scale sine
+        let sine: Vec<f64> = range(0, 160u).map(|i| (2.0 * PI * 1000.0 * i as f64 / 16000.0).sin()).collect();
+        assert!((dbfs(sine.as_slice()) + 3.01).abs() < 0.01);
+        assert!((dbfs([0.5f64, -0.5, 0.5, -0.5].as_slice()) + 6.02).abs() < 0.01);
+        assert_eq!(dbfs([0f64, 0.0, 0.0].as_slice()), -100.0);
+    }
+}
@@ -5,15 +5,95 @@
 
 struct wit_context;
 typedef void (*wit_resp_callback)(char *);
+typedef void (*wit_log_callback)(unsigned int level, const char *module, const char *message, void *user_data);
+typedef void (*wit_level_callback)(double rms, double peak, unsigned long clips, void *user_data);
+
+/**
+ * Timing of a query, in milliseconds from its start, -1 for the steps that didn't
+ * happen (no capture for text queries, no speech detected...). For voice queries
+ * stopped with wit_voice_query_stop, the end of speech is when it was called.
+ */
+typedef struct {
+    double capture_start_ms;
+    double first_speech_ms;
+    double end_of_speech_ms;
+    double upload_done_ms;
+    double first_byte_ms;
+    double total_ms;
+    double audio_ms;
+    unsigned long long bytes_uploaded;
+} wit_query_metrics;
+
+/*
+ * Environment
+ *
+ * wit_init reads these variables, for the whole life of the context:
+ *
+ *   WIT_API_URL             base url of the API instead of https://api.wit.ai, e.g. to
+ *                           use wit-stub-server
+ *   WIT_CAPTURE_DIR         save the audio of each voice query there as a WAV file,
+ *                           next to the JSON response
+ *   WIT_CAPTURE_MAX_BYTES   size limit of that directory, oldest sessions deleted first
+ *   WIT_CASSETTE            file the requests and responses are recorded to, or
+ *                           replayed from with no network access
+ *   WIT_CASSETTE_MODE       "record", or "replay" (default)
+ *   WIT_VAD                 end-of-speech detector: energy (default), spectral or gmm
+ *   WIT_VAD_CALIBRATION     file holding a calibration (see wit_calibrate)
+ *   WIT_PREPROCESS          processing of the audio before the end-of-speech detection
+ *                           and the upload: comma-separated highpass, ns (noise
+ *                           suppression) and agc
+ *   WIT_CODEC               encoding of the uploaded audio: raw (default), ulaw, flac,
+ *                           or opus when libwit is built with the opus feature
+ *   WIT_READ_SIZE           samples read from the device at once
+ *   WIT_BUFFER_BYTES        size of the buffer between the device and the upload
+ *   WIT_BACKPRESSURE        when that buffer is full: block (default), drop-oldest or fail
+ *   WIT_CHUNK_SIZE          size of the chunks the audio is uploaded in
+ *   WIT_SOX_LIBRARY         path of libsox, instead of the usual names
+ *
+ * Unknown names in WIT_VAD, WIT_CODEC and WIT_BACKPRESSURE are logged, and the
+ * default used instead.
+ */
 
 /**
  * Initialize the resources for audio recording and Wit API requests.
  * This function returns a context object used by all the other functions
- * in the library.
+ * in the library. Its options are read from the environment (see Environment above).
  * The resources can be released using wit_close.
+ *
+ * libsox is loaded at this point. If it can't be found, text queries still work
+ * but voice queries return NULL.
  */
 struct wit_context *wit_init(const char *device_opt, unsigned int verbosity);
 
+/**
+ * Redirect the library's log messages to the given callback, instead of stderr.
+ * The level goes from 1 (error) to 4 (debug), like the verbosity given to wit_init.
+ * The strings passed to the callback are only valid during the call.
+ * user_data is passed back as is. Passing NULL as the callback restores logging to stderr.
+ * The callback may call other libwit functions; what they log is passed to it as well.
+ *
+ * The callback is called from the library's threads, possibly from several at once:
+ * it must be thread-safe. user_data must stay valid until the callback is replaced,
+ * by another call to wit_set_log_callback.
+ */
+void wit_set_log_callback(wit_log_callback cb, void *user_data);
+
+/**
+ * Enable (non-zero) or disable (zero) the privacy mode. In privacy mode, transcripts and
+ * entity values are replaced with a hash in the logs, at any verbosity.
+ * Access tokens are always masked.
+ */
+void wit_set_privacy_mode(unsigned int enabled);
+
+/**
+ * Call cb with the level of the input every interval_ms milliseconds during the following
+ * recording sessions, e.g. for a VU meter. rms and peak are in dBFS (-100 for silence), and
+ * clips is the number of samples clipped by the device during the interval.
+ * The callback is called from another thread. It replaces the previous one; passing NULL
+ * stops the callbacks.
+ */
+void wit_set_level_callback(struct wit_context *context, unsigned int interval_ms, wit_level_callback cb, void *user_data);
+
 /**
  * Release the resources allocated by wit_init.
  * The context object should not be used for any other purpose after this function
@@ -78,4 +158,32 @@ char *wit_voice_query_stop(struct wit_context *context);
  */
 void wit_voice_query_stop_async(struct wit_context *context, wit_resp_callback cb);
 
+/**
+ * Same as wit_text_query, wit_voice_query_auto and wit_voice_query_stop. When metrics
+ * isn't NULL, it is filled with the timing of the query, also when the query fails
+ * (e.g. to tell whether the upload finished). A query that never started leaves -1 in
+ * the times and 0 in the durations and bytes_uploaded.
+ */
+char *wit_text_query_with_metrics(struct wit_context *context, const char *text, const char *access_token, wit_query_metrics *metrics);
+char *wit_voice_query_auto_with_metrics(struct wit_context *context, const char *access_token, wit_query_metrics *metrics);
+char *wit_voice_query_stop_with_metrics(struct wit_context *context, wit_query_metrics *metrics);
+
+/**
+ * Measure the background noise during duration_ms milliseconds, while the user stays
+ * silent. This function is blocking, and returns the calibration as a JSON string, or
+ * NULL on failure (e.g. during a recording session).
+ *
+ * The following voice queries start from this measurement instead of learning the noise
+ * during their first frames, so the user can speak right away. The string can be saved
+ * and given to wit_set_calibration in a later session.
+ */
+char *wit_calibrate(struct wit_context *context, unsigned int duration_ms);
+
+/**
+ * Use a calibration returned by wit_calibrate for the following voice queries. Passing
+ * NULL goes back to learning the noise in each session. Returns 0 on success, and -1 if
+ * the calibration can't be read.
+ */
+int wit_set_calibration(struct wit_context *context, const char *calibration);
+
 #endif
@@ -1,3 +1,7 @@
+use std::os;
+
 fn main() {
-    println!("cargo:rustc-flags=-l sox");
+    if os::getenv("CARGO_FEATURE_STATIC").is_some() {
+        println!("cargo:rustc-flags=-l sox");
+    }
 }
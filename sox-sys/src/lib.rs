@@ -185,15 +185,54 @@ pub struct SoxFormatT {
     pub _priv: *mut c_void
 }
 
+pub const SOX_SEEK_SET: c_int = 0;
+
+// Opaque types, only handled through pointers
+pub enum SoxEffectHandlerT {}
+pub enum SoxEffectT {}
+pub enum SoxEffectsChainT {}
+
+pub type SoxOverwriteCallbackT = extern "C" fn(filename: *const c_char) -> SoxBool;
+pub type SoxFlowEffectsCallbackT = extern "C" fn(all_done: SoxBool, client_data: *mut c_void) -> c_int;
+
+// Only available when linking libsox at build time. Otherwise, these
+// functions have to be resolved at runtime (see libwit's sox module).
+#[cfg(feature = "static")]
 extern {
     pub fn sox_version() -> *const c_char;
     pub fn sox_format_init() -> SoxErrorT;
+    pub fn sox_quit() -> SoxErrorT;
     pub fn sox_open_read(
         path: *const c_char,
         signal: *const SoxSignalInfoT,
         encoding: *const SoxEncodingInfoT,
         filetype: *const c_char) -> *const SoxFormatT;
-    pub fn sox_read(ft: *const SoxFormatT, buf: *const i32, len: size_t) -> size_t;
+    pub fn sox_open_write(
+        path: *const c_char,
+        signal: *const SoxSignalInfoT,
+        encoding: *const SoxEncodingInfoT,
+        filetype: *const c_char,
+        oob: *const SoxOobT,
+        overwrite_permitted: Option<SoxOverwriteCallbackT>) -> *const SoxFormatT;
+    pub fn sox_read(ft: *const SoxFormatT, buf: *mut i32, len: size_t) -> size_t;
+    pub fn sox_write(ft: *const SoxFormatT, buf: *const i32, len: size_t) -> size_t;
+    pub fn sox_seek(ft: *const SoxFormatT, offset: u64, whence: c_int) -> SoxErrorT;
     pub fn sox_close(ft: *const SoxFormatT) -> SoxErrorT;
-    //fn sox_quit() -> SoxErrorT;
+
+    pub fn sox_create_effects_chain(
+        in_enc: *const SoxEncodingInfoT,
+        out_enc: *const SoxEncodingInfoT) -> *mut SoxEffectsChainT;
+    pub fn sox_delete_effects_chain(chain: *mut SoxEffectsChainT);
+    pub fn sox_find_effect(name: *const c_char) -> *const SoxEffectHandlerT;
+    pub fn sox_create_effect(handler: *const SoxEffectHandlerT) -> *mut SoxEffectT;
+    pub fn sox_effect_options(effect: *mut SoxEffectT, argc: c_int, argv: *const *const c_char) -> SoxErrorT;
+    pub fn sox_add_effect(
+        chain: *mut SoxEffectsChainT,
+        effect: *mut SoxEffectT,
+        in_signal: *mut SoxSignalInfoT,
+        out_signal: *const SoxSignalInfoT) -> SoxErrorT;
+    pub fn sox_flow_effects(
+        chain: *mut SoxEffectsChainT,
+        callback: Option<SoxFlowEffectsCallbackT>,
+        client_data: *mut c_void) -> SoxErrorT;
 }
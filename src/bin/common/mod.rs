@@ -0,0 +1,87 @@
+/*!
+ * Helpers shared by the command-line tools: access token lookup and
+ * reading the outcomes of a response.
+ */
+
+#![allow(dead_code)]
+
+use std::io;
+use std::os;
+use getopts::Matches;
+use serialize::json::Json;
+
+/**
+ * The access token given with --token, or else $WIT_ACCESS_TOKEN.
+ */
+pub fn access_token(matches: &Matches) -> Option<String> {
+    matches.opt_str("token").or_else(|| os::getenv("WIT_ACCESS_TOKEN"))
+}
+
+/**
+ * Like `access_token`, but a missing token is a usage error.
+ */
+pub fn require_access_token(matches: &Matches) -> Option<String> {
+    let token = access_token(matches);
+    if token.is_none() {
+        let _ = writeln!(&mut io::stderr(), "missing access token: use --token or set WIT_ACCESS_TOKEN");
+        os::set_exit_status(2);
+    }
+    token
+}
+
+/**
+ * A JSON value as shown to the user: strings without their quotes.
+ */
+pub fn json_value_string(json: &Json) -> String {
+    match *json {
+        Json::String(ref s) => s.clone(),
+        ref other => other.to_string()
+    }
+}
+
+pub fn outcomes(response: &Json) -> &[Json] {
+    match response.find("outcomes").and_then(|o| o.as_array()) {
+        Some(outcomes) => outcomes.as_slice(),
+        None => &[]
+    }
+}
+
+pub fn first_outcome(response: &Json) -> Option<&Json> {
+    outcomes(response).get(0)
+}
+
+/**
+ * The intent and confidence of an outcome, "?" and 0 when missing.
+ */
+pub fn intent(outcome: &Json) -> (&str, f64) {
+    let intent = outcome.find("intent").and_then(|i| i.as_string()).unwrap_or("?");
+    let confidence = outcome.find("confidence").and_then(|c| c.as_f64()).unwrap_or(0.0);
+    (intent, confidence)
+}
+
+/**
+ * The values of an entity, whether the API sent one or several.
+ */
+pub fn entity_values(entity: &Json) -> Vec<Json> {
+    match entity.as_array() {
+        Some(values) => values.clone(),
+        None => vec![entity.clone()]
+    }
+}
+
+/**
+ * The entities of an outcome as (name, value) pairs, in name order.
+ */
+pub fn entities(outcome: &Json) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    match outcome.find("entities").and_then(|e| e.as_object()) {
+        Some(map) => for (name, values) in map.iter() {
+            for value in entity_values(values).iter() {
+                let shown = value.find("value").map_or(value.to_string(), json_value_string);
+                pairs.push((name.clone(), shown));
+            }
+        },
+        None => ()
+    }
+    pairs
+}
@@ -0,0 +1,540 @@
+/*!
+ * Daemon owning one libwit handle and the capture device, shared by local
+ * processes through JSON-RPC 2.0.
+ *
+ * On the Unix socket, requests and responses are JSON objects, one per line.
+ * Methods:
+ *
+ *   text          {"text": "...", "token": "..."}   text query
+ *   voice_auto    {"token": "..."}                  voice query, until the end of speech
+ *   voice_start   {"token": "..."}                  start a voice query
+ *   voice_stop    {}                                stop it, and get the response
+ *   set_context   {"context": {...}}                context sent with the next queries
+ *   subscribe     {}                                receive events on this connection
+ *
+ * The token can be omitted when the daemon was started with --token.
+ * Events are sent as notifications: {"jsonrpc": "2.0", "method": "event", "params": {"event": "SpeechStarted"}}.
+ *
+ * There is one capture device, so one voice query at a time: while a client
+ * runs voice_auto, or between its voice_start and voice_stop, the others get
+ * a "busy" error. Only the client that started a query can stop it, and the
+ * query is stopped when that client disconnects.
+ *
+ * The socket is created with the permissions given by --socket-mode (660 by
+ * default). With --http PORT, text and voice_auto requests can also be POSTed
+ * to http://127.0.0.1:PORT/rpc, with Content-Type: application/json. The
+ * endpoint is open to any local user, so each HTTP request must carry its own
+ * token: the --token default is only used on the socket. Requests carrying an
+ * Origin header are refused, so that web pages cannot reach the daemon.
+ */
+
+extern crate getopts;
+extern crate serialize;
+extern crate libc;
+extern crate wit;
+
+use std::os;
+use std::io::{mod, BufferedReader, IoResult};
+use std::io::fs::{mod, PathExtensions};
+use std::io::net::pipe::{UnixListener, UnixAcceptor, UnixStream};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::{Acceptor, Listener};
+use std::num::from_str_radix;
+use std::collections::BTreeMap;
+use std::ascii::AsciiExt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUint, SeqCst};
+use getopts::{optopt, optflagmulti, optflag, getopts, usage};
+use serialize::json::{mod, Json};
+use wit::cmd::{mod, AudioInput, Options, WitHandle, WitEvent};
+
+mod common;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const QUERY_ERROR: i64 = -32000;
+const BUSY: i64 = -32001;
+
+#[deriving(Clone)]
+struct Daemon {
+    handle: WitHandle,
+    token: Option<String>,
+    // the caller running the voice query, if any
+    voice_owner: Arc<Mutex<Option<uint>>>,
+    next_caller: Arc<AtomicUint>
+}
+
+/**
+ * Who sent a request: a socket connection, or one HTTP request.
+ */
+#[deriving(Clone, PartialEq)]
+enum Caller {
+    Socket(uint),
+    Http(uint)
+}
+
+impl Caller {
+    fn id(&self) -> uint {
+        match *self {
+            Caller::Socket(id) | Caller::Http(id) => id
+        }
+    }
+}
+
+enum Reply {
+    Result(Json),
+    Error(i64, String),
+    Subscribe
+}
+
+fn object(pairs: Vec<(&str, Json)>) -> Json {
+    let mut obj = BTreeMap::new();
+    for (key, value) in pairs.into_iter() {
+        obj.insert(key.to_string(), value);
+    }
+    Json::Object(obj)
+}
+
+fn response(id: Json, reply: Reply) -> Json {
+    match reply {
+        Reply::Result(result) => object(vec![
+            ("jsonrpc", Json::String("2.0".to_string())),
+            ("id", id),
+            ("result", result)]),
+        Reply::Error(code, message) => object(vec![
+            ("jsonrpc", Json::String("2.0".to_string())),
+            ("id", id),
+            ("error", object(vec![("code", Json::I64(code)), ("message", Json::String(message))]))]),
+        Reply::Subscribe => object(vec![
+            ("jsonrpc", Json::String("2.0".to_string())),
+            ("id", id),
+            ("result", Json::Boolean(true))])
+    }
+}
+
+fn event_notification(event: WitEvent) -> Json {
+    object(vec![
+        ("jsonrpc", Json::String("2.0".to_string())),
+        ("method", Json::String("event".to_string())),
+        ("params", object(vec![("event", Json::String(format!("{}", event)))]))])
+}
+
+impl Daemon {
+    fn new_caller(&self) -> uint {
+        self.next_caller.fetch_add(1, SeqCst)
+    }
+
+    /**
+     * The token of the request. The default token is only for the socket:
+     * anyone on this machine can reach the HTTP endpoint.
+     */
+    fn token(&self, caller: &Caller, params: &Json) -> Result<String, Reply> {
+        let default = match *caller {
+            Caller::Socket(_) => self.token.clone(),
+            Caller::Http(_) => None
+        };
+        match params.find("token").and_then(|t| t.as_string()).map(|t| t.to_string()).or(default) {
+            Some(token) => Ok(token),
+            None => Err(Reply::Error(INVALID_PARAMS, "missing token".to_string()))
+        }
+    }
+
+    /**
+     * Make the caller the owner of the voice query, unless another one is.
+     */
+    fn claim_voice(&self, caller: &Caller) -> Result<(), Reply> {
+        let mut owner = self.voice_owner.lock();
+        match *owner {
+            Some(_) => Err(Reply::Error(BUSY, "busy: a voice query is already running".to_string())),
+            None => {
+                *owner = Some(caller.id());
+                Ok(())
+            }
+        }
+    }
+
+    fn release_voice(&self, caller: &Caller) {
+        let mut owner = self.voice_owner.lock();
+        if *owner == Some(caller.id()) {
+            *owner = None;
+        }
+    }
+
+    fn owns_voice(&self, caller: &Caller) -> bool {
+        *self.voice_owner.lock() == Some(caller.id())
+    }
+
+    /**
+     * Stop the voice query of a client that went away.
+     */
+    fn disconnected(&self, caller: &Caller) {
+        if self.owns_voice(caller) {
+            let _ = cmd::voice_query_stop(&self.handle);
+            self.release_voice(caller);
+        }
+    }
+
+    fn query_reply(result: Result<Json, cmd::RequestError>) -> Reply {
+        match result {
+            Ok(json) => Reply::Result(json),
+            Err(e) => Reply::Error(QUERY_ERROR, format!("{}", e))
+        }
+    }
+
+    /**
+     * Handle one request, and return its id with the reply.
+     */
+    fn call(&self, caller: &Caller, request: &Json) -> (Json, Reply) {
+        let id = request.find("id").map_or(Json::Null, |id| id.clone());
+        let method = match request.find("method").and_then(|m| m.as_string()) {
+            Some(method) => method,
+            None => return (id, Reply::Error(INVALID_REQUEST, "missing method".to_string()))
+        };
+        let no_params = Json::Object(BTreeMap::new());
+        let params = request.find("params").unwrap_or(&no_params);
+        let http = match *caller {
+            Caller::Http(_) => true,
+            Caller::Socket(_) => false
+        };
+        let reply = match method {
+            "text" => match (params.find("text").and_then(|t| t.as_string()), self.token(caller, params)) {
+                (Some(text), Ok(token)) => Daemon::query_reply(cmd::text_query(&self.handle, text.to_string(), token)),
+                (None, _) => Reply::Error(INVALID_PARAMS, "missing text".to_string()),
+                (_, Err(reply)) => reply
+            },
+            "voice_auto" => match self.token(caller, params).and_then(|token| self.claim_voice(caller).map(|_| token)) {
+                Ok(token) => {
+                    let result = cmd::voice_query_auto(&self.handle, token);
+                    self.release_voice(caller);
+                    Daemon::query_reply(result)
+                }
+                Err(reply) => reply
+            },
+            // HTTP requests have no connection to hold the query, and set_context
+            // changes the queries of every client
+            "voice_start" | "voice_stop" | "set_context" | "subscribe" if http => {
+                Reply::Error(METHOD_NOT_FOUND, format!("{} is only available on the socket", method))
+            }
+            "voice_start" => match self.token(caller, params).and_then(|token| self.claim_voice(caller).map(|_| token)) {
+                Ok(token) => {
+                    cmd::voice_query_start(&self.handle, token);
+                    Reply::Result(Json::Boolean(true))
+                }
+                Err(reply) => reply
+            },
+            "voice_stop" => if self.owns_voice(caller) {
+                let result = cmd::voice_query_stop(&self.handle);
+                self.release_voice(caller);
+                Daemon::query_reply(result)
+            } else {
+                Reply::Error(INVALID_REQUEST, "no voice query started by this client".to_string())
+            },
+            "set_context" => {
+                let context = match params.find("context") {
+                    Some(&Json::Null) | None => None,
+                    Some(context) => Some(context.clone())
+                };
+                cmd::set_context(&self.handle, context);
+                Reply::Result(Json::Boolean(true))
+            }
+            "subscribe" => Reply::Subscribe,
+            _ => Reply::Error(METHOD_NOT_FOUND, format!("unknown method {}", method))
+        };
+        (id, reply)
+    }
+}
+
+/**
+ * Serve one socket client. Everything written to the client goes through
+ * one writer task, so that responses and events don't interleave.
+ */
+fn serve_socket_client(daemon: Daemon, stream: UnixStream) {
+    let caller = Caller::Socket(daemon.new_caller());
+    let (out_tx, out_rx) = channel::<String>();
+    let mut writer = stream.clone();
+    spawn(proc() {
+        for line in out_rx.iter() {
+            if writer.write_line(line.as_slice()).is_err() {
+                break;
+            }
+        }
+    });
+    let mut subscribed = false;
+    for line in BufferedReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break
+        };
+        if line.as_slice().trim().is_empty() {
+            continue;
+        }
+        let reply = match json::from_str(line.as_slice()) {
+            Ok(request) => {
+                // a client can run a blocking query while receiving events
+                let (id, reply) = daemon.call(&caller, &request);
+                match reply {
+                    Reply::Subscribe if !subscribed => {
+                        subscribed = true;
+                        let events = cmd::subscribe(&daemon.handle);
+                        let event_tx = out_tx.clone();
+                        spawn(proc() {
+                            for event in events.iter() {
+                                if event_tx.send_opt(json::encode(&event_notification(event))).is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                        response(id, Reply::Subscribe)
+                    }
+                    reply => response(id, reply)
+                }
+            }
+            Err(e) => response(Json::Null, Reply::Error(PARSE_ERROR, format!("{}", e)))
+        };
+        if out_tx.send_opt(json::encode(&reply)).is_err() {
+            break;
+        }
+    }
+    daemon.disconnected(&caller);
+}
+
+// the requests are small JSON objects
+const MAX_HTTP_BODY: uint = 64 * 1024;
+
+struct HttpRequest {
+    request_line: String,
+    content_type: Option<String>,
+    has_origin: bool,
+    content_length: uint
+}
+
+fn read_http_head(reader: &mut BufferedReader<TcpStream>) -> IoResult<HttpRequest> {
+    let request_line = try!(reader.read_line());
+    let mut request = HttpRequest {
+        request_line: request_line.as_slice().trim().to_string(),
+        content_type: None,
+        has_origin: false,
+        content_length: 0
+    };
+    loop {
+        let line = try!(reader.read_line());
+        let line = line.as_slice().trim();
+        if line.is_empty() {
+            break;
+        }
+        let i = match line.find(':') {
+            Some(i) => i,
+            None => continue
+        };
+        let value = line.slice_from(i + 1).trim();
+        match line.slice_to(i).trim().to_ascii_lower().as_slice() {
+            "content-length" => request.content_length = from_str_radix(value, 10).unwrap_or(0),
+            "content-type" => request.content_type = Some(value.to_ascii_lower()),
+            "origin" => request.has_origin = true,
+            _ => ()
+        }
+    }
+    Ok(request)
+}
+
+/**
+ * Why a request is refused, if it is. Browsers add an Origin header to the
+ * requests of web pages, and can only send application/json after a CORS
+ * preflight that is never answered: pages visited on this machine cannot use the daemon.
+ */
+fn check_http_request(request: &HttpRequest) -> Option<(uint, &'static str)> {
+    let json = request.content_type.as_ref().map_or(false, |t| {
+        t.as_slice().split(';').next().map_or(false, |t| t.trim() == "application/json")
+    });
+    if !request.request_line.as_slice().starts_with("POST /rpc ") {
+        Some((404, "POST requests to /rpc"))
+    } else if request.has_origin {
+        Some((403, "requests from web pages are not allowed"))
+    } else if !json {
+        Some((415, "the Content-Type must be application/json"))
+    } else if request.content_length > MAX_HTTP_BODY {
+        Some((413, "request too large"))
+    } else {
+        None
+    }
+}
+
+fn status_text(status: uint) -> &'static str {
+    match status {
+        200 => "OK",
+        403 => "Forbidden",
+        404 => "Not Found",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        _ => "Error"
+    }
+}
+
+fn serve_http_client(daemon: Daemon, stream: TcpStream) {
+    let mut writer = stream.clone();
+    let mut reader = BufferedReader::new(stream);
+    let request = match read_http_head(&mut reader) {
+        Ok(request) => request,
+        Err(_) => return
+    };
+    let (status, body) = match check_http_request(&request) {
+        Some((status, message)) => {
+            (status, json::encode(&response(Json::Null, Reply::Error(INVALID_REQUEST, message.to_string()))))
+        }
+        None => {
+            let body = match reader.read_exact(request.content_length) {
+                Ok(body) => body,
+                Err(_) => return
+            };
+            let parsed = String::from_utf8(body).ok().and_then(|b| json::from_str(b.as_slice()).ok());
+            let reply = match parsed {
+                Some(request) => {
+                    let (id, reply) = daemon.call(&Caller::Http(daemon.new_caller()), &request);
+                    response(id, reply)
+                }
+                None => response(Json::Null, Reply::Error(PARSE_ERROR, "invalid JSON".to_string()))
+            };
+            (200u, json::encode(&reply))
+        }
+    };
+    let _ = write!(&mut writer, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   status, status_text(status), body.len(), body);
+}
+
+fn serve_http(daemon: Daemon, port: u16) {
+    let mut acceptor = match TcpListener::bind(("127.0.0.1", port)).listen() {
+        Ok(acceptor) => acceptor,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "failed to listen on port {}: {}", port, e);
+            return;
+        }
+    };
+    println!("listening on http://127.0.0.1:{}/rpc", port);
+    for stream in acceptor.incoming() {
+        match stream {
+            Ok(stream) => {
+                let daemon = daemon.clone();
+                spawn(proc() serve_http_client(daemon, stream))
+            }
+            Err(e) => { let _ = writeln!(&mut io::stderr(), "failed to accept connection: {}", e); }
+        }
+    }
+}
+
+/**
+ * Create the socket with the given permissions. The umask keeps everyone
+ * else out until then: between bind and chmod, another user could connect.
+ */
+fn listen_private(path: &Path, mode: u32) -> Result<UnixAcceptor, String> {
+    let previous = unsafe { libc::umask(0o177) };
+    let listened = UnixListener::bind(path).listen();
+    unsafe { libc::umask(previous) };
+    let acceptor = try!(listened.map_err(|e| format!("failed to listen on {}: {}", path.display(), e)));
+    match fs::chmod(path, io::FilePermission::from_bits_truncate(mode)) {
+        Ok(()) => Ok(acceptor),
+        Err(e) => {
+            drop(acceptor);
+            let _ = fs::unlink(path);
+            Err(format!("failed to set the permissions of {}: {}", path.display(), e))
+        }
+    }
+}
+
+/**
+ * Remove the socket a previous instance left behind. Anything else at the
+ * path, including the socket of a daemon still running, is left alone.
+ */
+fn remove_stale_socket(path: &Path) -> Result<(), String> {
+    let stat = match fs::lstat(path) {
+        Ok(stat) => stat,
+        Err(ref e) if e.kind == io::FileNotFound => return Ok(()),
+        Err(e) => return Err(format!("cannot check {}: {}", path.display(), e))
+    };
+    // sockets have no FileType of their own
+    if stat.kind != io::FileType::Unknown {
+        return Err(format!("{} exists and is not a socket", path.display()));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(format!("another daemon is listening on {}", path.display()));
+    }
+    fs::unlink(path).map_err(|e| format!("cannot remove the stale socket {}: {}", path.display(), e))
+}
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("s", "socket", "path of the Unix socket (default: /tmp/wit.sock)", "PATH"),
+        optopt("m", "socket-mode", "permissions of the socket, in octal (default: 660)", "MODE"),
+        optopt("", "http", "also serve JSON-RPC on http://127.0.0.1:PORT/rpc", "PORT"),
+        optopt("t", "token", "default Wit access token (default: $WIT_ACCESS_TOKEN)", "TOKEN"),
+        optopt("d", "device", "capture device", "DEVICE"),
+        optflagmulti("v", "verbose", "log more details (repeat for more)"),
+        optflag("h", "help", "print this help")
+    ];
+    let usage_str = usage(format!("Usage: {} [options]", args[0]).as_slice(), &opts);
+    let matches = match getopts(args.tail(), &opts) {
+        Ok(m) => m,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "{}\n{}", e, usage_str);
+            os::set_exit_status(2);
+            return;
+        }
+    };
+    if matches.opt_present("h") {
+        println!("{}", usage_str);
+        return;
+    }
+    let socket_path = Path::new(matches.opt_str("s").unwrap_or("/tmp/wit.sock".to_string()));
+    let mode = match from_str_radix::<u32>(matches.opt_str("m").unwrap_or("660".to_string()).as_slice(), 8) {
+        Some(mode) => mode,
+        None => {
+            let _ = writeln!(&mut io::stderr(), "invalid socket mode\n{}", usage_str);
+            os::set_exit_status(2);
+            return;
+        }
+    };
+
+    // before libwit starts its threads, as the umask is shared with them
+    let listened = remove_stale_socket(&socket_path).and_then(|_| listen_private(&socket_path, mode));
+    let mut acceptor = match listened {
+        Ok(acceptor) => acceptor,
+        Err(message) => {
+            let _ = writeln!(&mut io::stderr(), "{}", message);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+    println!("listening on {}", socket_path.display());
+
+    let handle = cmd::init_with_options(Options {
+        input: AudioInput::Device(matches.opt_str("d")),
+        verbosity: 1 + matches.opt_count("v"),
+        ..cmd::options_from_env()
+    });
+    let daemon = Daemon {
+        handle: handle,
+        token: common::access_token(&matches),
+        voice_owner: Arc::new(Mutex::new(None)),
+        next_caller: Arc::new(AtomicUint::new(0))
+    };
+
+    match matches.opt_str("http").and_then(|p| from_str::<u16>(p.as_slice())) {
+        Some(port) => {
+            let daemon = daemon.clone();
+            spawn(proc() serve_http(daemon, port))
+        }
+        None => ()
+    }
+
+    for stream in acceptor.incoming() {
+        match stream {
+            Ok(stream) => {
+                let daemon = daemon.clone();
+                spawn(proc() serve_socket_client(daemon, stream))
+            }
+            Err(e) => { let _ = writeln!(&mut io::stderr(), "failed to accept connection: {}", e); }
+        }
+    }
+}
@@ -0,0 +1,409 @@
+/*!
+ * Batch evaluation of a Wit app against labeled utterances.
+ *
+ * The input is either JSONL, one utterance per line:
+ *
+ *   {"text": "turn on the lights", "intent": "lights_on", "entities": {"room": "kitchen"}}
+ *   {"audio": "samples/lights.wav", "intent": "lights_on"}
+ *
+ * or CSV (.csv extension) with a header line naming the columns text, audio,
+ * intent and entities, entities being written as name=value;name=value.
+ *
+ * The report has per-intent precision/recall, a confusion matrix, entity
+ * match rates and latency percentiles.
+ */
+
+extern crate getopts;
+extern crate serialize;
+extern crate time;
+extern crate wit;
+
+use std::os;
+use std::io::{mod, BufferedReader, File};
+use std::sync::{Arc, Mutex};
+use std::iter::AdditiveIterator;
+use std::collections::{BTreeMap, BTreeSet};
+use getopts::{optopt, optflag, optflagmulti, getopts, usage};
+use serialize::json::{mod, Json};
+use wit::cmd::{mod, AudioInput, Options, RequestError};
+
+mod common;
+
+const NO_INTENT: &'static str = "<none>";
+
+#[deriving(Clone)]
+enum Query {
+    Text(String),
+    Audio(Path)
+}
+
+#[deriving(Clone)]
+struct Utterance {
+    query: Query,
+    intent: String,
+    entities: Vec<(String, String)>
+}
+
+struct Outcome {
+    intent: String,
+    // expected entities, and whether they were found
+    entities: Vec<(String, bool)>,
+    latency_ms: f64,
+    error: Option<RequestError>
+}
+
+fn parse_json_line(line: &str) -> Result<Utterance, String> {
+    let json = try!(json::from_str(line).map_err(|e| format!("{}", e)));
+    let query = match (json.find("text").and_then(|t| t.as_string()), json.find("audio").and_then(|a| a.as_string())) {
+        (Some(text), _) => Query::Text(text.to_string()),
+        (None, Some(path)) => Query::Audio(Path::new(path)),
+        (None, None) => return Err("missing text or audio".to_string())
+    };
+    let intent = json.find("intent").and_then(|i| i.as_string()).unwrap_or(NO_INTENT).to_string();
+    let entities = match json.find("entities").and_then(|e| e.as_object()) {
+        Some(entities) => entities.iter().map(|(name, value)| (name.clone(), common::json_value_string(value))).collect(),
+        None => Vec::new()
+    };
+    Ok(Utterance {
+        query: query,
+        intent: intent,
+        entities: entities
+    })
+}
+
+/**
+ * Split a CSV line, handling quoted fields and doubled quotes.
+ */
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    loop {
+        match chars.next() {
+            Some('"') if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            Some('"') if field.is_empty() => quoted = true,
+            Some(',') if !quoted => {
+                fields.push(field.clone());
+                field.clear();
+            }
+            Some(c) => field.push(c),
+            None => break
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn parse_csv_line(header: &[String], line: &str) -> Result<Utterance, String> {
+    let fields = split_csv_line(line);
+    let column = |name: &str| -> Option<String> {
+        header.iter().position(|h| h.as_slice() == name)
+            .and_then(|i| fields.get(i))
+            .map(|f| f.trim().to_string())
+            .and_then(|f| if f.is_empty() { None } else { Some(f) })
+    };
+    let query = match (column("text"), column("audio")) {
+        (Some(text), _) => Query::Text(text),
+        (None, Some(path)) => Query::Audio(Path::new(path)),
+        (None, None) => return Err("missing text or audio".to_string())
+    };
+    let entities = column("entities").map(|entities| {
+        entities.as_slice().split(';').filter_map(|pair| {
+            let mut parts = pair.splitn(1, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => Some((name.trim().to_string(), value.trim().to_string())),
+                _ => None
+            }
+        }).collect()
+    }).unwrap_or(Vec::new());
+    Ok(Utterance {
+        query: query,
+        intent: column("intent").unwrap_or(NO_INTENT.to_string()),
+        entities: entities
+    })
+}
+
+fn load_utterances(path: &Path) -> Result<Vec<Utterance>, String> {
+    let file = try!(File::open(path).map_err(|e| format!("{}", e)));
+    let lines: Vec<String> = BufferedReader::new(file).lines()
+        .filter_map(|l| l.ok())
+        .map(|l| l.as_slice().trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect();
+    let csv = path.extension_str() == Some("csv");
+    let header = if csv {
+        match lines.get(0) {
+            Some(h) => split_csv_line(h.as_slice()).into_iter().map(|c| c.as_slice().trim().to_string()).collect(),
+            None => return Ok(Vec::new())
+        }
+    } else {
+        Vec::new()
+    };
+    let mut utterances = Vec::new();
+    for (i, line) in lines.iter().enumerate().skip(if csv { 1 } else { 0 }) {
+        let parsed = if csv {
+            parse_csv_line(header.as_slice(), line.as_slice())
+        } else {
+            parse_json_line(line.as_slice())
+        };
+        match parsed {
+            Ok(utterance) => utterances.push(utterance),
+            Err(e) => return Err(format!("line {}: {}", i + 1, e))
+        }
+    }
+    Ok(utterances)
+}
+
+/**
+ * Compare the first outcome of a response with what was expected.
+ */
+fn check_response(utterance: &Utterance, response: &Json) -> (String, Vec<(String, bool)>) {
+    let outcome = common::first_outcome(response);
+    let intent = outcome.and_then(|o| o.find("intent")).and_then(|i| i.as_string()).unwrap_or(NO_INTENT).to_string();
+    let entities = utterance.entities.iter().map(|&(ref name, ref expected)| {
+        let found = outcome.and_then(|o| o.find("entities")).and_then(|e| e.find(name.as_slice()));
+        let values = found.map_or(Vec::new(), common::entity_values);
+        let matched = values.iter().any(|v| {
+            v.find("value").map_or(false, |v| common::json_value_string(v) == *expected)
+        });
+        (name.clone(), matched)
+    }).collect();
+    (intent, entities)
+}
+
+fn run_query(handle: &cmd::WitHandle, utterance: &Utterance, token: &str) -> Result<Json, RequestError> {
+    match utterance.query {
+        Query::Text(ref text) => cmd::text_query(handle, text.clone(), token.to_string()),
+        Query::Audio(ref path) => {
+            // the whole file: the end-of-speech detection would stop at the first pause
+            cmd::set_input(handle, AudioInput::File(path.clone()));
+            cmd::voice_query_to_end(handle, token.to_string())
+        }
+    }
+}
+
+/**
+ * Run the utterances on `concurrency` workers, each with its own handle.
+ * The handles are created and released here, before and after all the queries.
+ * Returns the outcomes with the index of their utterance: a worker that died
+ * leaves some out.
+ */
+fn evaluate(utterances: &[Utterance], opts: Options, token: String, concurrency: uint) -> Vec<(uint, Outcome)> {
+    let queue = Arc::new(Mutex::new(utterances.iter().map(|u| u.clone()).enumerate().collect::<Vec<(uint, Utterance)>>()));
+    queue.lock().reverse();
+    let handles: Vec<cmd::WitHandle> = range(0, concurrency).map(|_| cmd::init_with_options(opts.clone())).collect();
+    let (tx, rx) = channel();
+    for handle in handles.iter() {
+        let handle = handle.clone();
+        let queue = queue.clone();
+        let tx = tx.clone();
+        let token = token.clone();
+        spawn(proc() {
+            loop {
+                let next = queue.lock().pop();
+                let (i, utterance) = match next {
+                    Some(next) => next,
+                    None => break
+                };
+                let start = time::precise_time_ns();
+                let result = run_query(&handle, &utterance, token.as_slice());
+                let latency_ms = (time::precise_time_ns() - start) as f64 / 1e6;
+                let outcome = match result {
+                    Ok(ref response) => {
+                        let (intent, entities) = check_response(&utterance, response);
+                        Outcome { intent: intent, entities: entities, latency_ms: latency_ms, error: None }
+                    }
+                    Err(e) => Outcome {
+                        intent: NO_INTENT.to_string(),
+                        entities: utterance.entities.iter().map(|&(ref name, _)| (name.clone(), false)).collect(),
+                        latency_ms: latency_ms,
+                        error: Some(e)
+                    }
+                };
+                tx.send((i, outcome));
+            }
+        });
+    }
+    drop(tx);
+    let outcomes: Vec<(uint, Outcome)> = rx.iter().collect();
+    for handle in handles.iter() {
+        cmd::cleanup(handle);
+    }
+    outcomes
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * (sorted.len() - 1) as f64).round() as uint;
+    sorted[rank]
+}
+
+fn ratio(num: uint, den: uint) -> f64 {
+    if den == 0 { 0.0 } else { num as f64 / den as f64 }
+}
+
+/**
+ * Print the report, and return the intent accuracy. Outcomes are matched to
+ * their utterance by index; an utterance without one counts as an error.
+ */
+fn report(utterances: &[Utterance], outcomes: &[(uint, Outcome)]) -> f64 {
+    let mut by_index: Vec<Option<&Outcome>> = Vec::from_elem(utterances.len(), None);
+    for &(i, ref outcome) in outcomes.iter() {
+        by_index[i] = Some(outcome);
+    }
+    let mut intents = BTreeSet::new();
+    // (expected, predicted) -> count
+    let mut confusion: BTreeMap<(String, String), uint> = BTreeMap::new();
+    // name -> (matched, total)
+    let mut entity_counts: BTreeMap<String, (uint, uint)> = BTreeMap::new();
+    let mut correct = 0u;
+    let mut errors = 0u;
+    let mut missing = 0u;
+    for (utterance, outcome) in utterances.iter().zip(by_index.into_iter()) {
+        let predicted = match outcome {
+            Some(outcome) => outcome.intent.clone(),
+            None => NO_INTENT.to_string()
+        };
+        intents.insert(utterance.intent.clone());
+        intents.insert(predicted.clone());
+        if utterance.intent == predicted {
+            correct += 1;
+        }
+        let key = (utterance.intent.clone(), predicted);
+        let n = confusion.get(&key).map_or(0, |&n| n);
+        confusion.insert(key, n + 1);
+        let entities = match outcome {
+            Some(outcome) => {
+                if outcome.error.is_some() {
+                    errors += 1;
+                }
+                outcome.entities.clone()
+            }
+            None => {
+                errors += 1;
+                missing += 1;
+                utterance.entities.iter().map(|&(ref name, _)| (name.clone(), false)).collect()
+            }
+        };
+        for &(ref name, matched) in entities.iter() {
+            let (m, total) = entity_counts.get(name).map_or((0, 0), |&c| c);
+            entity_counts.insert(name.clone(), (if matched { m + 1 } else { m }, total + 1));
+        }
+    }
+    let count = |expected: &str, predicted: &str| -> uint {
+        confusion.get(&(expected.to_string(), predicted.to_string())).map_or(0, |&c| c)
+    };
+
+    let accuracy = ratio(correct, utterances.len());
+    println!("utterances: {}, errors: {} ({} without a result), intent accuracy: {:.3}\n",
+             utterances.len(), errors, missing, accuracy);
+
+    println!("{:<24} {:>9} {:>9} {:>9}", "intent", "precision", "recall", "support");
+    for intent in intents.iter() {
+        let tp = count(intent.as_slice(), intent.as_slice());
+        let predicted = intents.iter().map(|e| count(e.as_slice(), intent.as_slice())).sum();
+        let expected = intents.iter().map(|p| count(intent.as_slice(), p.as_slice())).sum();
+        println!("{:<24} {:>9.3} {:>9.3} {:>9}", intent, ratio(tp, predicted), ratio(tp, expected), expected);
+    }
+
+    println!("\nconfusion matrix (rows: expected, columns: predicted)");
+    let labels: Vec<&String> = intents.iter().collect();
+    print!("{:>4}", "");
+    for i in range(0, labels.len()) {
+        print!(" {:>5}", i);
+    }
+    println!("");
+    for (i, expected) in labels.iter().enumerate() {
+        print!("{:>4}", i);
+        for predicted in labels.iter() {
+            print!(" {:>5}", count(expected.as_slice(), predicted.as_slice()));
+        }
+        println!("  {}", expected);
+    }
+
+    if !entity_counts.is_empty() {
+        println!("\n{:<24} {:>9} {:>9}", "entity", "match", "total");
+        let (mut all_matched, mut all_total) = (0u, 0u);
+        for (name, &(matched, total)) in entity_counts.iter() {
+            println!("{:<24} {:>9.3} {:>9}", name, ratio(matched, total), total);
+            all_matched += matched;
+            all_total += total;
+        }
+        println!("{:<24} {:>9.3} {:>9}", "(all)", ratio(all_matched, all_total), all_total);
+    }
+
+    let mut latencies: Vec<f64> = outcomes.iter().map(|&(_, ref o)| o.latency_ms).collect();
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    println!("\nlatency (ms): p50 {:.0}, p90 {:.0}, p95 {:.0}, p99 {:.0}, max {:.0}",
+             percentile(latencies.as_slice(), 50.0),
+             percentile(latencies.as_slice(), 90.0),
+             percentile(latencies.as_slice(), 95.0),
+             percentile(latencies.as_slice(), 99.0),
+             latencies.last().map_or(0.0, |&l| l));
+    accuracy
+}
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("t", "token", "Wit access token (default: $WIT_ACCESS_TOKEN)", "TOKEN"),
+        optopt("c", "concurrency", "number of queries in flight (default: 4)", "N"),
+        optopt("", "min-accuracy", "exit with status 1 if the intent accuracy is lower", "RATIO"),
+        optflagmulti("v", "verbose", "log more details (repeat for more)"),
+        optflag("h", "help", "print this help")
+    ];
+    let usage_str = usage(format!("Usage: {} [options] UTTERANCES.jsonl|UTTERANCES.csv", args[0]).as_slice(), &opts);
+    let matches = match getopts(args.tail(), &opts) {
+        Ok(m) => m,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "{}\n{}", e, usage_str);
+            os::set_exit_status(2);
+            return;
+        }
+    };
+    if matches.opt_present("h") {
+        println!("{}", usage_str);
+        return;
+    }
+    if matches.free.len() != 1 {
+        let _ = writeln!(&mut io::stderr(), "{}", usage_str);
+        os::set_exit_status(2);
+        return;
+    }
+    let token = match common::require_access_token(&matches) {
+        Some(token) => token,
+        None => return
+    };
+    let concurrency = matches.opt_str("c").and_then(|c| from_str(c.as_slice())).unwrap_or(4u);
+    let utterances = match load_utterances(&Path::new(matches.free[0].as_slice())) {
+        Ok(utterances) => utterances,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "failed to load utterances: {}", e);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+    let options = Options {
+        verbosity: 1 + matches.opt_count("v"),
+        ..cmd::options_from_env()
+    };
+    let outcomes = evaluate(utterances.as_slice(), options, token, if concurrency == 0 { 1 } else { concurrency });
+    let accuracy = report(utterances.as_slice(), outcomes.as_slice());
+    match matches.opt_str("min-accuracy").and_then(|m| from_str::<f64>(m.as_slice())) {
+        Some(min) if accuracy < min => {
+            let _ = writeln!(&mut io::stderr(), "\nintent accuracy {:.3} is below {:.3}", accuracy, min);
+            os::set_exit_status(1);
+        }
+        _ => ()
+    }
+}
@@ -0,0 +1,223 @@
+/*!
+ * Interactive shell for exploring a Wit app.
+ *
+ * Any line is sent as a text query. Commands:
+ *
+ *   :listen        voice query, until the end of speech
+ *   :ctx [JSON]    show or set the context sent with the queries (:ctx clear removes it)
+ *   :history       list the previous queries and their outcomes
+ *   :save PATH     write the session to PATH, as JSONL
+ *   :help, :quit
+ */
+
+extern crate getopts;
+extern crate serialize;
+extern crate wit;
+
+use std::os;
+use std::io::{mod, File};
+use std::collections::BTreeMap;
+use getopts::{optopt, optflag, optflagmulti, getopts, usage};
+use serialize::json::{mod, Json};
+use wit::cmd::{mod, AudioInput, Options, RequestError, WitHandle};
+
+mod common;
+
+struct Exchange {
+    query: Option<String>,
+    context: Option<Json>,
+    result: Result<Json, RequestError>
+}
+
+impl Exchange {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("query".to_string(), match self.query {
+            Some(ref text) => Json::String(text.clone()),
+            None => Json::Null
+        });
+        obj.insert("voice".to_string(), Json::Boolean(self.query.is_none()));
+        obj.insert("context".to_string(), self.context.clone().unwrap_or(Json::Null));
+        match self.result {
+            Ok(ref response) => obj.insert("response".to_string(), response.clone()),
+            Err(ref e) => obj.insert("error".to_string(), Json::String(format!("{}", e)))
+        };
+        Json::Object(obj)
+    }
+}
+
+/**
+ * Outcomes as rows of intent, confidence and entities.
+ */
+fn print_outcomes(result: &Result<Json, RequestError>) {
+    let response = match *result {
+        Ok(ref response) => response,
+        Err(ref e) => {
+            println!("  error: {}", e);
+            return;
+        }
+    };
+    match response.find("_text").and_then(|t| t.as_string()) {
+        Some(text) => println!("  \"{}\"", text),
+        None => ()
+    }
+    let outcomes = common::outcomes(response);
+    if outcomes.is_empty() {
+        println!("  (no outcome)");
+        return;
+    }
+    println!("  {:<24} {:>6}  {}", "intent", "conf", "entities");
+    for outcome in outcomes.iter() {
+        let (intent, confidence) = common::intent(outcome);
+        let entities: Vec<String> = common::entities(outcome).into_iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        println!("  {:<24} {:>6.3}  {}", intent, confidence, entities.connect(", "));
+    }
+}
+
+fn summary(exchange: &Exchange) -> String {
+    let query = exchange.query.clone().unwrap_or("<voice>".to_string());
+    let outcome = match exchange.result {
+        Ok(ref response) => {
+            let (intent, confidence) = common::first_outcome(response).map_or(("?", 0.0), common::intent);
+            format!("{} ({:.3})", intent, confidence)
+        }
+        Err(ref e) => format!("error: {}", e)
+    };
+    format!("{} -> {}", query, outcome)
+}
+
+fn save(history: &[Exchange], path: &Path) -> io::IoResult<()> {
+    let mut file = try!(File::create(path));
+    for exchange in history.iter() {
+        try!(file.write_line(json::encode(&exchange.to_json()).as_slice()));
+    }
+    Ok(())
+}
+
+fn print_help() {
+    println!("  TEXT          send a text query");
+    println!("  :listen       send a voice query, until the end of speech");
+    println!("  :ctx [JSON]   show or set the context (:ctx clear removes it)");
+    println!("  :history      list the previous queries");
+    println!("  :save PATH    write the session as JSONL");
+    println!("  :quit         exit");
+}
+
+fn run(handle: &WitHandle, token: String) {
+    let mut history: Vec<Exchange> = Vec::new();
+    let mut context: Option<Json> = None;
+    let mut stdin = io::stdin();
+    loop {
+        print!("wit> ");
+        let _ = io::stdio::flush();
+        let line = match stdin.read_line() {
+            Ok(line) => line,
+            Err(_) => break
+        };
+        let line = line.as_slice().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (command, arg) = match line.find(' ') {
+            Some(i) => (line.slice_to(i), line.slice_from(i + 1).trim()),
+            None => (line, "")
+        };
+        let query = match command {
+            ":quit" | ":q" => break,
+            ":help" | ":h" => {
+                print_help();
+                continue;
+            }
+            ":history" => {
+                for (i, exchange) in history.iter().enumerate() {
+                    println!("  {:>3}  {}", i + 1, summary(exchange));
+                }
+                continue;
+            }
+            ":save" => {
+                if arg.is_empty() {
+                    println!("  usage: :save PATH");
+                } else {
+                    match save(history.as_slice(), &Path::new(arg)) {
+                        Ok(()) => println!("  saved {} queries to {}", history.len(), arg),
+                        Err(e) => println!("  failed to save: {}", e)
+                    }
+                }
+                continue;
+            }
+            ":ctx" => {
+                if arg.is_empty() {
+                    println!("  {}", context.as_ref().map_or("(no context)".to_string(), |c| c.to_string()));
+                } else if arg == "clear" {
+                    context = None;
+                    cmd::set_context(handle, None);
+                } else {
+                    match json::from_str(arg) {
+                        Ok(json) => {
+                            context = Some(json.clone());
+                            cmd::set_context(handle, Some(json));
+                        }
+                        Err(e) => println!("  invalid JSON: {}", e)
+                    }
+                }
+                continue;
+            }
+            ":listen" => {
+                println!("  listening...");
+                None
+            }
+            _ if command.starts_with(":") => {
+                println!("  unknown command {} (:help for the list)", command);
+                continue;
+            }
+            _ => Some(line.to_string())
+        };
+        let result = match query {
+            Some(ref text) => cmd::text_query(handle, text.clone(), token.clone()),
+            None => cmd::voice_query_auto(handle, token.clone())
+        };
+        print_outcomes(&result);
+        history.push(Exchange {
+            query: query,
+            context: context.clone(),
+            result: result
+        });
+    }
+}
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("t", "token", "Wit access token (default: $WIT_ACCESS_TOKEN)", "TOKEN"),
+        optopt("d", "device", "capture device for :listen", "DEVICE"),
+        optflagmulti("v", "verbose", "log more details (repeat for more)"),
+        optflag("h", "help", "print this help")
+    ];
+    let usage_str = usage(format!("Usage: {} [options]", args[0]).as_slice(), &opts);
+    let matches = match getopts(args.tail(), &opts) {
+        Ok(m) => m,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "{}\n{}", e, usage_str);
+            os::set_exit_status(2);
+            return;
+        }
+    };
+    if matches.opt_present("h") {
+        println!("{}", usage_str);
+        return;
+    }
+    let token = match common::require_access_token(&matches) {
+        Some(token) => token,
+        None => return
+    };
+    let handle = cmd::init_with_options(Options {
+        input: AudioInput::Device(matches.opt_str("d")),
+        verbosity: 1 + matches.opt_count("v"),
+        ..cmd::options_from_env()
+    });
+    println!("type a query, or :help");
+    run(&handle, token);
+    cmd::cleanup(&handle);
+}
@@ -0,0 +1,505 @@
+/*!
+ * Minimal implementation of the Wit HTTP API, for integration tests.
+ *
+ * Answers GET /message and POST /speech with fixtures read from a JSON file:
+ *
+ *   [{"text": "hello", "response": {...}},
+ *    {"min_audio_bytes": 16000, "max_audio_bytes": 64000, "response": {...}},
+ *    {"status": 500, "delay_ms": 3000, "response": {...}}]
+ *
+ * The first fixture that matches is used. A fixture with neither text nor
+ * audio bounds matches any request of both kinds. "status" defaults to 200,
+ * and "delay_ms" delays the response, to test timeouts.
+ *
+ * The uploaded audio is decoded as its Content-Type describes it. With
+ * --save-audio DIR, it is saved there as WAV files (FLAC and Ogg uploads as
+ * they are), to compare them with what was recorded. Bodies larger than 16MB
+ * are refused with a 413.
+ *
+ * Chunked uploads are timed: the arrival of each chunk is recorded, and the
+ * number of chunks, their spread and the largest gap are printed (with
+ * --show-chunks, every chunk), to check that the audio is really streamed.
+ */
+
+extern crate getopts;
+extern crate serialize;
+extern crate url;
+extern crate time;
+
+use std::os;
+use std::ascii::AsciiExt;
+use std::io::{mod, BufferedReader, File, IoResult, IoError};
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::{Acceptor, Listener};
+use std::io::timer;
+use std::time::Duration;
+use std::num::from_str_radix;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUint, SeqCst};
+use std::collections::BTreeMap;
+use getopts::{optopt, optflag, getopts, usage};
+use serialize::json::{mod, Json};
+
+const ACCEPT: &'static str = "application/vnd.wit.20141124+json";
+
+// far more than the few seconds of audio of a query
+const MAX_BODY: uint = 16 * 1024 * 1024;
+
+struct Fixture {
+    text: Option<String>,
+    min_audio_bytes: Option<u64>,
+    max_audio_bytes: Option<u64>,
+    status: u16,
+    delay_ms: i64,
+    response: Json
+}
+
+impl Fixture {
+    fn from_json(json: &Json) -> Option<Fixture> {
+        json.find("response").map(|response| Fixture {
+            text: json.find("text").and_then(|t| t.as_string()).map(|t| t.to_string()),
+            min_audio_bytes: json.find("min_audio_bytes").and_then(|n| n.as_u64()),
+            max_audio_bytes: json.find("max_audio_bytes").and_then(|n| n.as_u64()),
+            status: json.find("status").and_then(|s| s.as_u64()).unwrap_or(200) as u16,
+            delay_ms: json.find("delay_ms").and_then(|d| d.as_i64()).unwrap_or(0),
+            response: response.clone()
+        })
+    }
+
+    fn is_catch_all(&self) -> bool {
+        self.text.is_none() && self.min_audio_bytes.is_none() && self.max_audio_bytes.is_none()
+    }
+
+    fn matches_text(&self, text: &str) -> bool {
+        match self.text {
+            Some(ref t) => t.as_slice() == text,
+            None => self.is_catch_all()
+        }
+    }
+
+    fn matches_audio(&self, len: u64) -> bool {
+        if self.text.is_some() {
+            return false;
+        }
+        self.min_audio_bytes.map_or(true, |min| len >= min) &&
+            self.max_audio_bytes.map_or(true, |max| len <= max)
+    }
+}
+
+struct Config {
+    token: Option<String>,
+    fixtures: Vec<Fixture>,
+    audio_dir: Option<Path>,
+    uploads: AtomicUint,
+    show_chunks: bool
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // arrival of each chunk of a chunked body, in ms after the headers, and its size
+    chunks: Vec<(f64, uint)>
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&&(ref n, _)| n.as_slice() == name)
+            .map(|&(_, ref value)| value.as_slice())
+    }
+
+    fn query_param(&self, name: &str) -> Option<String> {
+        self.query.as_ref().and_then(|query| {
+            url::form_urlencoded::parse(query.as_bytes()).into_iter()
+                .find(|&(ref n, _)| n.as_slice() == name)
+                .map(|(_, value)| value)
+        })
+    }
+}
+
+fn read_error(e: IoError) -> (u16, &'static str) {
+    (400, e.desc)
+}
+
+fn read_request(reader: &mut BufferedReader<TcpStream>) -> Result<Request, (u16, &'static str)> {
+    let request_line = try!(reader.read_line().map_err(read_error));
+    let parts: Vec<&str> = request_line.as_slice().trim().split(' ').collect();
+    if parts.len() != 3 {
+        return Err((400, "invalid request line"));
+    }
+    let (path, query) = match parts[1].find('?') {
+        Some(i) => (parts[1].slice_to(i).to_string(), Some(parts[1].slice_from(i + 1).to_string())),
+        None => (parts[1].to_string(), None)
+    };
+    let mut headers = Vec::new();
+    loop {
+        let line = try!(reader.read_line().map_err(read_error));
+        let line = line.as_slice().trim();
+        if line.is_empty() {
+            break;
+        }
+        match line.find(':') {
+            // header names are case insensitive, keep them lowercase
+            Some(i) => headers.push((line.slice_to(i).trim().to_ascii_lower(), line.slice_from(i + 1).trim().to_string())),
+            None => return Err((400, "invalid header"))
+        }
+    }
+    let mut request = Request {
+        method: parts[0].to_string(),
+        path: path,
+        query: query,
+        headers: headers,
+        body: Vec::new(),
+        chunks: Vec::new()
+    };
+    let headers_received = time::precise_time_ns();
+    let chunked = request.header("transfer-encoding").map_or(false, |te| te.contains("chunked"));
+    if chunked {
+        loop {
+            let size_line = try!(reader.read_line().map_err(read_error));
+            let size_str = size_line.as_slice().trim().split(';').next().unwrap_or("");
+            let size: uint = match from_str_radix(size_str, 16) {
+                Some(size) => size,
+                None => return Err((400, "invalid chunk size"))
+            };
+            if size == 0 {
+                // skip the trailers
+                loop {
+                    let line = try!(reader.read_line().map_err(read_error));
+                    if line.as_slice().trim().is_empty() {
+                        break;
+                    }
+                }
+                break;
+            }
+            if size > MAX_BODY - request.body.len() {
+                return Err((413, "request too large"));
+            }
+            let chunk = try!(reader.read_exact(size).map_err(read_error));
+            request.chunks.push(((time::precise_time_ns() - headers_received) as f64 / 1e6, size));
+            request.body.push_all(chunk.as_slice());
+            try!(reader.read_line().map_err(read_error));
+        }
+    } else {
+        let len = request.header("content-length").and_then(|len| from_str::<uint>(len));
+        match len {
+            Some(len) if len > MAX_BODY => return Err((413, "request too large")),
+            Some(len) => request.body = try!(reader.read_exact(len).map_err(read_error)),
+            None => ()
+        }
+    }
+    Ok(request)
+}
+
+fn error_json(message: &str) -> Json {
+    let mut obj = std::collections::BTreeMap::new();
+    obj.insert("error".to_string(), Json::String(message.to_string()));
+    Json::Object(obj)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown"
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: u16, body: &Json) -> IoResult<()> {
+    let body = json::encode(body);
+    try!(write!(stream, "HTTP/1.1 {} {}\r\n", status, reason(status)));
+    try!(write!(stream, "Content-Type: application/json\r\n"));
+    try!(write!(stream, "Content-Length: {}\r\n", body.len()));
+    try!(write!(stream, "Connection: close\r\n\r\n"));
+    try!(stream.write_str(body.as_slice()));
+    stream.flush()
+}
+
+/**
+ * Check the headers set by the library on every request.
+ */
+fn check_common_headers(config: &Config, request: &Request) -> Result<(), (u16, &'static str)> {
+    let authorized = match (request.header("authorization"), &config.token) {
+        (Some(auth), &Some(ref token)) => auth == format!("Bearer {}", token).as_slice(),
+        (Some(auth), &None) => auth.starts_with("Bearer ") && auth.len() > "Bearer ".len(),
+        (None, _) => false
+    };
+    if !authorized {
+        return Err((401, "missing or invalid Authorization header"));
+    }
+    if request.header("accept") != Some(ACCEPT) {
+        return Err((406, "missing or invalid Accept header"));
+    }
+    Ok(())
+}
+
+/**
+ * Parameters of the audio Content-Type, e.g. audio/raw;encoding=...;bits=...;rate=...;endian=...
+ */
+fn audio_params(request: &Request) -> Result<(String, BTreeMap<String, String>), (u16, &'static str)> {
+    let content_type = match request.header("content-type") {
+        Some(ct) => ct,
+        None => return Err((415, "missing Content-Type header"))
+    };
+    let mut params = content_type.split(';').map(|p| p.trim());
+    let mime = params.next().unwrap_or("").to_string();
+    let mut map = BTreeMap::new();
+    for param in params {
+        let mut kv = param.splitn(1, '=');
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) => { map.insert(k.to_string(), v.to_string()); }
+            _ => return Err((415, "invalid Content-Type parameter"))
+        }
+    }
+    Ok((mime, map))
+}
+
+fn ulaw_decode(byte: u8) -> i16 {
+    let u = !byte;
+    let exponent = ((u >> 4) & 0x07) as uint;
+    let mantissa = (u & 0x0F) as i32;
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    (if u & 0x80 != 0 { -magnitude } else { magnitude }) as i16
+}
+
+/**
+ * Speech requests must send audio described the way the library does it:
+ * 16-bit signed-integer or 8-bit ulaw raw audio, in either byte order, or
+ * Ogg/Opus (audio/ogg) or FLAC (audio/flac), which describe themselves.
+ * Only raw audio is decoded. FLAC is accepted although the Wit API does not
+ * document it, to test the flac codec.
+ */
+fn decode_audio(request: &Request) -> Result<Option<(Vec<i16>, u32)>, (u16, &'static str)> {
+    let (mime, params) = try!(audio_params(request));
+    if mime.as_slice() == "audio/flac" || mime.as_slice() == "audio/ogg" {
+        return Ok(None);
+    }
+    if mime.as_slice() != "audio/raw" {
+        return Err((415, "unsupported Content-Type"));
+    }
+    let param = |name: &str| params.get(name).map(|v| v.as_slice());
+    let rate: u32 = match param("rate").and_then(|r| from_str(r)) {
+        Some(rate) => rate,
+        None => return Err((415, "incomplete audio/raw Content-Type"))
+    };
+    let body = request.body.as_slice();
+    let samples = match (param("encoding"), param("bits"), param("endian")) {
+        (Some("signed-integer"), Some("16"), Some(endian)) if endian == "little" || endian == "big" => {
+            if body.len() % 2 != 0 {
+                return Err((400, "odd number of bytes of 16-bit audio"));
+            }
+            body.chunks(2).map(|b| {
+                if endian == "little" { (b[0] as u16 | (b[1] as u16 << 8)) as i16 } else { (b[1] as u16 | (b[0] as u16 << 8)) as i16 }
+            }).collect()
+        }
+        (Some("ulaw"), Some("8"), Some(_)) => body.iter().map(|&b| ulaw_decode(b)).collect(),
+        (None, _, _) | (_, None, _) | (_, _, None) => return Err((415, "incomplete audio/raw Content-Type")),
+        _ => return Err((415, "unsupported audio/raw encoding"))
+    };
+    Ok(Some((samples, rate)))
+}
+
+fn write_wav(path: &Path, samples: &[i16], rate: u32) -> IoResult<()> {
+    let mut file = try!(File::create(path));
+    let data_bytes = samples.len() as u32 * 2;
+    try!(file.write(b"RIFF"));
+    try!(file.write_le_u32(36 + data_bytes));
+    try!(file.write(b"WAVEfmt "));
+    try!(file.write_le_u32(16));
+    try!(file.write_le_u16(1));
+    try!(file.write_le_u16(1));
+    try!(file.write_le_u32(rate));
+    try!(file.write_le_u32(rate * 2));
+    try!(file.write_le_u16(2));
+    try!(file.write_le_u16(16));
+    try!(file.write(b"data"));
+    try!(file.write_le_u32(data_bytes));
+    for &sample in samples.iter() {
+        try!(file.write_le_i16(sample));
+    }
+    Ok(())
+}
+
+fn save_audio(config: &Config, request: &Request, decoded: &Option<(Vec<i16>, u32)>) {
+    let dir = match config.audio_dir {
+        Some(ref dir) => dir,
+        None => return
+    };
+    let n = config.uploads.fetch_add(1, SeqCst);
+    let result = match *decoded {
+        Some((ref samples, rate)) => {
+            let path = dir.join(format!("speech-{}.wav", n));
+            write_wav(&path, samples.as_slice(), rate).map(|_| path)
+        }
+        None => {
+            let extension = if request.header("content-type").map_or(false, |t| t.starts_with("audio/ogg")) { "ogg" } else { "flac" };
+            let path = dir.join(format!("speech-{}.{}", n, extension));
+            File::create(&path).and_then(|mut f| f.write(request.body.as_slice())).map(|_| path)
+        }
+    };
+    match result {
+        Ok(path) => println!("saved the audio to {}", path.display()),
+        Err(e) => { let _ = writeln!(&mut io::stderr(), "failed to save the audio: {}", e); }
+    }
+}
+
+fn report_chunks(config: &Config, request: &Request) {
+    if request.chunks.is_empty() {
+        println!("the body was not chunked");
+        return;
+    }
+    let mut largest_gap = 0f64;
+    let mut last = 0f64;
+    for (i, &(t, size)) in request.chunks.iter().enumerate() {
+        if config.show_chunks {
+            println!("  chunk {}: {} bytes at {:.1}ms", i, size, t);
+        }
+        if t - last > largest_gap {
+            largest_gap = t - last;
+        }
+        last = t;
+    }
+    let (first, _) = request.chunks[0];
+    println!("{} chunks, from {:.0}ms to {:.0}ms after the headers, largest gap {:.0}ms",
+             request.chunks.len(), first, last, largest_gap);
+}
+
+fn handle(config: &Config, request: &Request) -> (u16, Json, i64) {
+    match check_common_headers(config, request) {
+        Ok(()) => (),
+        Err((status, message)) => return (status, error_json(message), 0)
+    }
+    let fixture = match (request.method.as_slice(), request.path.as_slice()) {
+        ("GET", "/message") => {
+            let text = match request.query_param("q") {
+                Some(text) => text,
+                None => return (400, error_json("missing q parameter"), 0)
+            };
+            println!("GET /message q={}", text);
+            config.fixtures.iter().find(|f| f.matches_text(text.as_slice()))
+        }
+        ("POST", "/speech") => {
+            let decoded = match decode_audio(request) {
+                Ok(decoded) => decoded,
+                Err((status, message)) => return (status, error_json(message), 0)
+            };
+            let len = request.body.len() as u64;
+            match decoded {
+                Some((ref samples, rate)) => println!("POST /speech {} bytes of audio, {} samples ({:.2}s at {}Hz)",
+                                                      len, samples.len(), samples.len() as f64 / rate as f64, rate),
+                None => println!("POST /speech {} bytes of encoded audio", len)
+            }
+            report_chunks(config, request);
+            save_audio(config, request, &decoded);
+            config.fixtures.iter().find(|f| f.matches_audio(len))
+        }
+        (_, "/message") | (_, "/speech") => return (405, error_json("method not allowed"), 0),
+        _ => return (404, error_json("unknown endpoint"), 0)
+    };
+    match fixture {
+        Some(f) => (f.status, f.response.clone(), f.delay_ms),
+        None => (404, error_json("no matching fixture"), 0)
+    }
+}
+
+fn serve(config: Arc<Config>, stream: TcpStream) {
+    let mut writer = stream.clone();
+    let mut reader = BufferedReader::new(stream);
+    let (status, body, delay_ms) = match read_request(&mut reader) {
+        Ok(request) => handle(&*config, &request),
+        Err((status, message)) => (status, error_json(message), 0)
+    };
+    if delay_ms > 0 {
+        timer::sleep(Duration::milliseconds(delay_ms));
+    }
+    println!("-> {}", status);
+    match write_response(&mut writer, status, &body) {
+        Ok(()) => (),
+        Err(e) => { let _ = writeln!(&mut io::stderr(), "failed to write response: {}", e); }
+    }
+}
+
+fn load_fixtures(path: &Path) -> Result<Vec<Fixture>, String> {
+    let contents = try!(File::open(path).and_then(|mut f| f.read_to_string()).map_err(|e| format!("{}", e)));
+    let json = try!(json::from_str(contents.as_slice()).map_err(|e| format!("{}", e)));
+    match json {
+        Json::Array(ref fixtures) => Ok(fixtures.iter().filter_map(Fixture::from_json).collect()),
+        _ => Err("fixtures must be a JSON array".to_string())
+    }
+}
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("p", "port", "port to listen on (default: 8087)", "PORT"),
+        optopt("t", "token", "only accept this access token", "TOKEN"),
+        optopt("", "save-audio", "save the uploaded audio to this directory", "DIR"),
+        optflag("", "show-chunks", "print the arrival time of every chunk of the uploads"),
+        optflag("h", "help", "print this help")
+    ];
+    let usage_str = usage(format!("Usage: {} [options] FIXTURES.json", args[0]).as_slice(), &opts);
+    let matches = match getopts(args.tail(), &opts) {
+        Ok(m) => m,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "{}\n{}", e, usage_str);
+            os::set_exit_status(2);
+            return;
+        }
+    };
+    if matches.opt_present("h") {
+        println!("{}", usage_str);
+        return;
+    }
+    if matches.free.len() != 1 {
+        let _ = writeln!(&mut io::stderr(), "{}", usage_str);
+        os::set_exit_status(2);
+        return;
+    }
+    let port: u16 = matches.opt_str("p").and_then(|p| from_str(p.as_slice())).unwrap_or(8087);
+    let fixtures = match load_fixtures(&Path::new(matches.free[0].as_slice())) {
+        Ok(fixtures) => fixtures,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "failed to load fixtures: {}", e);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+    println!("loaded {} fixtures", fixtures.len());
+    let config = Arc::new(Config {
+        token: matches.opt_str("t"),
+        fixtures: fixtures,
+        audio_dir: matches.opt_str("save-audio").map(|dir| Path::new(dir)),
+        uploads: AtomicUint::new(0),
+        show_chunks: matches.opt_present("show-chunks")
+    });
+
+    let mut acceptor = match TcpListener::bind(("127.0.0.1", port)).listen() {
+        Ok(acceptor) => acceptor,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "failed to listen on port {}: {}", port, e);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+    println!("listening on http://127.0.0.1:{}", port);
+    for stream in acceptor.incoming() {
+        match stream {
+            Ok(stream) => {
+                let config = config.clone();
+                spawn(proc() serve(config, stream))
+            }
+            Err(e) => { let _ = writeln!(&mut io::stderr(), "failed to accept connection: {}", e); }
+        }
+    }
+}
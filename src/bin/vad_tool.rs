@@ -0,0 +1,39 @@
+/*!
+ * Offline analysis of the VAD on an audio file (WAV, or FLAC and any format
+ * sox can read).
+ *
+ * Prints the speech segments seen by the detector (--algorithm energy, spectral
+ * or gmm), and when it would end a voice query with end-of-speech detection.
+ * --trace writes the energy and noise floor of every frame as CSV, and
+ * --sweep runs the detector for a range of thresholds.
+ *
+ * With --dataset, the detector runs on every audio file of a directory that has
+ * a .txt file of labeled speech intervals next to it (see read_labels). The report
+ * has the frame-level precision/recall, the delay between the end of speech and
+ * the endpoint, the files cut off before the end of speech, and the throughput.
+ */
+
+extern crate getopts;
+extern crate time;
+#[cfg(feature = "vad")]
+extern crate vad;
+extern crate wit;
+
+#[cfg(not(feature = "vad"))]
+use std::{io, os};
+
+#[cfg(feature = "vad")]
+#[path = "vad_tool/analysis.rs"]
+mod analysis;
+
+#[cfg(feature = "vad")]
+fn main() {
+    analysis::main()
+}
+
+// without the vad crate there is no detector to analyze
+#[cfg(not(feature = "vad"))]
+fn main() {
+    let _ = writeln!(&mut io::stderr(), "wit-vad needs the vad feature: cargo build --features vad");
+    os::set_exit_status(1);
+}
@@ -0,0 +1,389 @@
+use std::os;
+use std::io::{mod, File};
+use std::io::fs::{mod, PathExtensions};
+use std::cmp;
+use time;
+use getopts::{optopt, optflag, getopts, usage};
+use vad::{mod, DetectorConfig, Algorithm};
+use wit::source::{mod, AudioInput};
+
+struct Frame {
+    time: f64,
+    // in dBFS
+    energy: f64,
+    noise_floor: f64,
+    talking: bool
+}
+
+struct Analysis {
+    frames: Vec<Frame>,
+    // (start, end) in seconds, end is None if speech goes on until the end of the file
+    segments: Vec<(f64, Option<f64>)>,
+    // when still_talking first returned false
+    endpoint: Option<f64>
+}
+
+fn read_samples(path: &Path) -> Result<(Vec<i16>, u32), String> {
+    source::init();
+    let mut audio = try!(source::open(&AudioInput::File(path.clone())).map_err(|e| format!("{}", e)));
+    let rate = audio.rate();
+    let mut samples = Vec::new();
+    let mut buf = Vec::from_elem(4096, 0i16);
+    loop {
+        match audio.read(buf.as_mut_slice()) {
+            Ok(n) => samples.push_all(buf.slice_to(n)),
+            Err(ref e) if e.kind == io::EndOfFile => break,
+            Err(e) => return Err(format!("{}", e))
+        }
+    }
+    Ok((samples, rate))
+}
+
+/**
+ * Feed the samples to the detector 10ms at a time, like the mic does,
+ * and record the state after every frame.
+ */
+fn analyze(samples: &[i16], rate: u32, config: &DetectorConfig) -> Analysis {
+    let mut detector = vad::new_detector(config, rate);
+    let chunk = cmp::max(rate as uint / 100, 1);
+    let mut analysis = Analysis {
+        frames: Vec::new(),
+        segments: Vec::new(),
+        endpoint: None
+    };
+    let mut frames_seen = 0;
+    let mut talking = false;
+    for (i, samples) in samples.chunks(chunk).enumerate() {
+        let still_talking = detector.still_talking(samples);
+        let time = ((i + 1) * chunk) as f64 / rate as f64;
+        if !still_talking && analysis.endpoint.is_none() {
+            analysis.endpoint = Some(time);
+        }
+        let frames = detector.frames();
+        let now_talking = detector.talking();
+        if frames != frames_seen {
+            frames_seen = frames;
+            let (energy, noise_floor) = detector.levels();
+            analysis.frames.push(Frame {
+                time: time,
+                energy: energy,
+                noise_floor: noise_floor,
+                talking: now_talking
+            });
+        }
+        if now_talking && !talking {
+            analysis.segments.push((time, None));
+        } else if !now_talking && talking {
+            match analysis.segments.last_mut() {
+                Some(segment) => segment.1 = Some(time),
+                None => ()
+            }
+        }
+        talking = now_talking;
+    }
+    analysis
+}
+
+fn speech_duration(analysis: &Analysis, total: f64) -> f64 {
+    analysis.segments.iter().map(|&(start, end)| end.unwrap_or(total) - start).fold(0.0, |a, b| a + b)
+}
+
+fn print_analysis(analysis: &Analysis, total: f64) {
+    if analysis.segments.is_empty() {
+        println!("no speech detected");
+    }
+    for &(start, end) in analysis.segments.iter() {
+        match end {
+            Some(end) => println!("speech {:8.2}s - {:8.2}s", start, end),
+            None => println!("speech {:8.2}s - (end of file)", start)
+        }
+    }
+    match analysis.endpoint {
+        Some(time) => println!("end of speech detected at {:.2}s", time),
+        None => println!("no end of speech detected: the query would last until the end ({:.2}s)", total)
+    }
+}
+
+fn write_trace(analysis: &Analysis, path: &Path) -> io::IoResult<()> {
+    let mut file = try!(File::create(path));
+    try!(file.write_line("time_s,energy_dbfs,noise_floor_dbfs,talking"));
+    for frame in analysis.frames.iter() {
+        try!(file.write_line(format!("{:.3},{:.2},{:.2},{}", frame.time, frame.energy, frame.noise_floor,
+                                     if frame.talking { 1u } else { 0 }).as_slice()));
+    }
+    Ok(())
+}
+
+/**
+ * Parse MIN:MAX:STEP.
+ */
+fn parse_sweep(s: &str) -> Option<(f64, f64, f64)> {
+    let parts: Vec<f64> = s.split(':').filter_map(|p| from_str(p)).collect();
+    if parts.len() == 3 && parts[2] > 0.0 && parts[0] <= parts[1] {
+        Some((parts[0], parts[1], parts[2]))
+    } else {
+        None
+    }
+}
+
+fn run_file(path: &Path, configs: &[DetectorConfig], trace: Option<String>) {
+    let (samples, rate) = match read_samples(path) {
+        Ok(audio) => audio,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "failed to read {}: {}", path.display(), e);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+    let total = samples.len() as f64 / rate as f64;
+    println!("{:.2}s of audio at {}Hz", total, rate);
+
+    if configs.len() > 1 {
+        println!("{:>9} {:>8} {:>9} {:>9}", "threshold", "segments", "speech_s", "endpoint");
+        for config in configs.iter() {
+            let analysis = analyze(samples.as_slice(), rate, config);
+            println!("{:>9.2} {:>8} {:>9.2} {:>9}", config.threshold, analysis.segments.len(), speech_duration(&analysis, total),
+                     analysis.endpoint.map_or("-".to_string(), |t| format!("{:.2}", t)));
+        }
+        return;
+    }
+    let analysis = analyze(samples.as_slice(), rate, &configs[0]);
+    print_analysis(&analysis, total);
+    match trace {
+        Some(path) => match write_trace(&analysis, &Path::new(path.as_slice())) {
+            Ok(()) => println!("wrote {} frames to {}", analysis.frames.len(), path),
+            Err(e) => { let _ = writeln!(&mut io::stderr(), "failed to write {}: {}", path, e); }
+        },
+        None => ()
+    }
+}
+
+/**
+ * An audio file with its labeled speech intervals, in seconds.
+ */
+struct LabeledAudio {
+    name: String,
+    samples: Vec<i16>,
+    rate: u32,
+    speech: Vec<(f64, f64)>
+}
+
+impl LabeledAudio {
+    fn is_speech(&self, time: f64) -> bool {
+        self.speech.iter().any(|&(start, end)| time >= start && time < end)
+    }
+
+    fn end_of_speech(&self) -> Option<f64> {
+        self.speech.iter().map(|&(_, end)| end).fold(None, |max, end| match max {
+            Some(max) if max >= end => Some(max),
+            _ => Some(end)
+        })
+    }
+}
+
+/**
+ * Labels are read from a .txt file next to each audio file, with one
+ * "start end [label]" line (in seconds) per speech interval, as exported by Audacity.
+ */
+fn read_labels(path: &Path) -> Result<Vec<(f64, f64)>, String> {
+    let contents = try!(File::open(path).and_then(|mut f| f.read_to_string()).map_err(|e| format!("{}", e)));
+    let mut speech = Vec::new();
+    for line in contents.as_slice().lines() {
+        let fields: Vec<&str> = line.words().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        match (fields.get(0).and_then(|s| from_str::<f64>(*s)), fields.get(1).and_then(|s| from_str::<f64>(*s))) {
+            (Some(start), Some(end)) if start <= end => speech.push((start, end)),
+            _ => return Err(format!("invalid label line: {}", line))
+        }
+    }
+    Ok(speech)
+}
+
+fn load_dataset(dir: &Path) -> Result<Vec<LabeledAudio>, String> {
+    let mut paths = try!(fs::readdir(dir).map_err(|e| format!("{}", e)));
+    paths.sort();
+    let mut dataset = Vec::new();
+    for path in paths.iter() {
+        let labels = path.with_extension("txt");
+        if path.extension_str() == Some("txt") || !labels.exists() {
+            continue;
+        }
+        let (samples, rate) = try!(read_samples(path).map_err(|e| format!("{}: {}", path.display(), e)));
+        let speech = try!(read_labels(&labels).map_err(|e| format!("{}: {}", labels.display(), e)));
+        dataset.push(LabeledAudio {
+            name: path.filename_display().to_string(),
+            samples: samples,
+            rate: rate,
+            speech: speech
+        });
+    }
+    Ok(dataset)
+}
+
+struct DatasetReport {
+    // frame counts: speech detected and labeled, detected only, labeled only
+    true_positives: uint,
+    false_positives: uint,
+    false_negatives: uint,
+    // delay between the labeled end of speech and the endpoint, for files that ended after it
+    endpoint_delays: Vec<f64>,
+    // files where the query would end before the labeled end of speech
+    early_cutoffs: Vec<String>,
+    // files where the query would never end
+    missed_endpoints: uint,
+    samples: uint,
+    elapsed_s: f64
+}
+
+fn ratio(num: uint, den: uint) -> f64 {
+    if den == 0 { 0.0 } else { num as f64 / den as f64 }
+}
+
+fn evaluate_dataset(dataset: &[LabeledAudio], config: &DetectorConfig) -> DatasetReport {
+    let mut report = DatasetReport {
+        true_positives: 0,
+        false_positives: 0,
+        false_negatives: 0,
+        endpoint_delays: Vec::new(),
+        early_cutoffs: Vec::new(),
+        missed_endpoints: 0,
+        samples: 0,
+        elapsed_s: 0.0
+    };
+    for audio in dataset.iter() {
+        let start = time::precise_time_ns();
+        let analysis = analyze(audio.samples.as_slice(), audio.rate, config);
+        report.elapsed_s += (time::precise_time_ns() - start) as f64 / 1e9;
+        report.samples += audio.samples.len();
+
+        for frame in analysis.frames.iter() {
+            match (frame.talking, audio.is_speech(frame.time)) {
+                (true, true) => report.true_positives += 1,
+                (true, false) => report.false_positives += 1,
+                (false, true) => report.false_negatives += 1,
+                (false, false) => ()
+            }
+        }
+        match (analysis.endpoint, audio.end_of_speech()) {
+            (Some(endpoint), Some(end)) if endpoint < end => report.early_cutoffs.push(audio.name.clone()),
+            (Some(endpoint), Some(end)) => report.endpoint_delays.push(endpoint - end),
+            (None, Some(_)) => report.missed_endpoints += 1,
+            _ => ()
+        }
+    }
+    report
+}
+
+fn run_dataset(dir: &Path, configs: &[DetectorConfig]) {
+    let dataset = match load_dataset(dir) {
+        Ok(dataset) => dataset,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "failed to load the dataset: {}", e);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+    println!("{} labeled files", dataset.len());
+    println!("{:>9} {:>9} {:>9} {:>10} {:>10} {:>6} {:>7} {:>12}",
+             "threshold", "precision", "recall", "delay_p50", "delay_p90", "early", "no_end", "samples/s");
+    for config in configs.iter() {
+        let mut report = evaluate_dataset(dataset.as_slice(), config);
+        report.endpoint_delays.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let percentile = |p: f64| -> String {
+            if report.endpoint_delays.is_empty() {
+                return "-".to_string();
+            }
+            let rank = (p / 100.0 * (report.endpoint_delays.len() - 1) as f64).round() as uint;
+            format!("{:.2}", report.endpoint_delays[rank])
+        };
+        println!("{:>9.2} {:>9.3} {:>9.3} {:>10} {:>10} {:>6} {:>7} {:>12.0}",
+                 config.threshold,
+                 ratio(report.true_positives, report.true_positives + report.false_positives),
+                 ratio(report.true_positives, report.true_positives + report.false_negatives),
+                 percentile(50.0), percentile(90.0),
+                 report.early_cutoffs.len(), report.missed_endpoints,
+                 if report.elapsed_s > 0.0 { report.samples as f64 / report.elapsed_s } else { 0.0 });
+        for name in report.early_cutoffs.iter() {
+            println!("          cut off early: {}", name);
+        }
+    }
+}
+
+pub fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("a", "algorithm", "energy (default), spectral or gmm", "NAME"),
+        optopt("t", "threshold", "dB above the noise floor for energy and spectral, log-likelihood ratio for gmm", "VALUE"),
+        optopt("i", "init-frames", "frames (10ms) used to learn the noise floor (default: 30)", "N"),
+        optopt("e", "end-frames", "silent frames ending speech (default: 30, at least 10)", "N"),
+        optopt("", "trace", "write the energy and noise floor of each frame to a CSV file", "PATH"),
+        optopt("", "sweep", "run the detector for thresholds from MIN to MAX", "MIN:MAX:STEP"),
+        optopt("", "dataset", "evaluate on the audio files of DIR, labeled with .txt files", "DIR"),
+        optflag("h", "help", "print this help")
+    ];
+    let usage_str = usage(format!("Usage: {} [options] AUDIO_FILE\n       {} [options] --dataset DIR", args[0], args[0]).as_slice(), &opts);
+    let matches = match getopts(args.tail(), &opts) {
+        Ok(m) => m,
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "{}\n{}", e, usage_str);
+            os::set_exit_status(2);
+            return;
+        }
+    };
+    if matches.opt_present("h") {
+        println!("{}", usage_str);
+        return;
+    }
+    if matches.free.len() != 1 && !matches.opt_present("dataset") {
+        let _ = writeln!(&mut io::stderr(), "{}", usage_str);
+        os::set_exit_status(2);
+        return;
+    }
+    let algorithm = match matches.opt_str("a") {
+        Some(name) => match Algorithm::from_name(name.as_slice()) {
+            Some(algorithm) => algorithm,
+            None => {
+                let _ = writeln!(&mut io::stderr(), "unknown algorithm {}\n{}", name, usage_str);
+                os::set_exit_status(2);
+                return;
+            }
+        },
+        None => Algorithm::Energy
+    };
+    let mut config = DetectorConfig::new(algorithm);
+    match matches.opt_str("t").and_then(|t| from_str(t.as_slice())) {
+        Some(threshold) => config.threshold = threshold,
+        None => ()
+    }
+    config.init_frames = matches.opt_str("i").and_then(|i| from_str(i.as_slice())).unwrap_or(30);
+    config.end_frames = matches.opt_str("e").and_then(|e| from_str(e.as_slice())).unwrap_or(30);
+    let configs = match matches.opt_str("sweep") {
+        Some(sweep) => match parse_sweep(sweep.as_slice()) {
+            Some((min, max, step)) => {
+                let mut configs = Vec::new();
+                let mut threshold = min;
+                while threshold <= max + step / 2.0 {
+                    configs.push(DetectorConfig { threshold: threshold, ..config.clone() });
+                    threshold += step;
+                }
+                configs
+            }
+            None => {
+                let _ = writeln!(&mut io::stderr(), "invalid sweep {}, expected MIN:MAX:STEP", sweep);
+                os::set_exit_status(2);
+                return;
+            }
+        },
+        None => vec![config]
+    };
+
+    match matches.opt_str("dataset") {
+        Some(dir) => run_dataset(&Path::new(dir.as_slice()), configs.as_slice()),
+        None => match matches.free.get(0) {
+            Some(path) => run_file(&Path::new(path.as_slice()), configs.as_slice(), matches.opt_str("trace")),
+            None => println!("{}", usage_str)
+        }
+    }
+}
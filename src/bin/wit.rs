@@ -0,0 +1,208 @@
+/*!
+ * Command-line client for the Wit API, built on wit::cmd.
+ *
+ *   wit text "turn on the lights"
+ *   wit voice [--manual]
+ *   wit file query.wav
+ *   wit devices
+ *   wit calibrate [SECONDS] > calibration.json
+ */
+
+extern crate getopts;
+extern crate serialize;
+extern crate wit;
+
+use std::os;
+use std::io;
+use std::cmp;
+use getopts::{optopt, optflag, optflagmulti, getopts, usage};
+use std::time::Duration;
+use serialize::json::{Json, ToJson};
+use wit::cmd::{mod, AudioInput, Options, RequestError};
+
+mod common;
+
+enum Output {
+    Summary,
+    Json,
+    Pretty
+}
+
+fn usage_text(program: &str, opts: &[getopts::OptGroup]) -> String {
+    let brief = format!("Usage: {} [options] COMMAND\n\n\
+                         Commands:\n    \
+                         text TEXT        send a text query\n    \
+                         voice            record a voice query, until the end of speech\n    \
+                         voice --manual   record a voice query, until Enter is pressed\n    \
+                         file PATH        send the audio of a file\n    \
+                         devices          list the capture devices\n    \
+                         calibrate [SECS] measure the background noise (default: 2s)", program);
+    usage(brief.as_slice(), opts)
+}
+
+/**
+ * For usage errors: the usage goes to stderr, and the exit status is 2.
+ */
+fn usage_error(message: Option<String>, program: &str, opts: &[getopts::OptGroup]) {
+    let mut stderr = io::stderr();
+    match message {
+        Some(message) => { let _ = writeln!(&mut stderr, "{}", message); }
+        None => ()
+    }
+    let _ = writeln!(&mut stderr, "{}", usage_text(program, opts));
+    os::set_exit_status(2);
+}
+
+/**
+ * Show the first outcome of a response: text, intent, confidence and entities.
+ */
+fn print_summary(json: &Json) {
+    match json.find("_text").and_then(|t| t.as_string()) {
+        Some(text) => println!("text:       {}", text),
+        None => ()
+    }
+    let outcome = match common::first_outcome(json) {
+        Some(outcome) => outcome,
+        None => {
+            println!("no outcome");
+            return;
+        }
+    };
+    let (intent, confidence) = common::intent(outcome);
+    println!("intent:     {} ({:.3})", intent, confidence);
+    let entities = common::entities(outcome);
+    if !entities.is_empty() {
+        println!("entities:");
+        for &(ref name, ref value) in entities.iter() {
+            println!("    {}: {}", name, value);
+        }
+    }
+}
+
+fn print_result(result: Result<Json, RequestError>, output: Output) {
+    match result {
+        Ok(json) => match output {
+            Output::Summary => print_summary(&json),
+            Output::Json => println!("{}", json),
+            Output::Pretty => println!("{}", json.pretty())
+        },
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "error: {}", e);
+            os::set_exit_status(1);
+        }
+    }
+}
+
+fn main() {
+    let args = os::args();
+    let program = args[0].clone();
+    let opts = [
+        optopt("t", "token", "Wit access token (default: $WIT_ACCESS_TOKEN)", "TOKEN"),
+        optopt("d", "device", "capture device (see the devices command)", "DEVICE"),
+        optflag("m", "manual", "voice: stop recording when Enter is pressed"),
+        optflagmulti("v", "verbose", "log more details (repeat for more)"),
+        optflag("j", "json", "print the raw JSON response"),
+        optflag("p", "pretty", "print the indented JSON response"),
+        optflag("h", "help", "print this help")
+    ];
+    let matches = match getopts(args.tail(), &opts) {
+        Ok(m) => m,
+        Err(e) => {
+            usage_error(Some(format!("{}", e)), program.as_slice(), &opts);
+            return;
+        }
+    };
+    if matches.opt_present("help") {
+        println!("{}", usage_text(program.as_slice(), &opts));
+        return;
+    }
+    if matches.free.is_empty() {
+        usage_error(None, program.as_slice(), &opts);
+        return;
+    }
+    let output = if matches.opt_present("pretty") {
+        Output::Pretty
+    } else if matches.opt_present("json") {
+        Output::Json
+    } else {
+        Output::Summary
+    };
+    // errors only by default, up to debug
+    let verbosity = cmp::min(1 + matches.opt_count("verbose"), 4);
+
+    let command = matches.free[0].clone();
+    if command.as_slice() == "devices" {
+        for (name, description) in cmd::list_devices().into_iter() {
+            println!("{:<12} {}", name, description);
+        }
+        return;
+    }
+
+    if command.as_slice() == "calibrate" {
+        let seconds: f64 = match matches.free.get(1) {
+            Some(s) => match from_str(s.as_slice()) {
+                Some(seconds) => seconds,
+                None => {
+                    usage_error(Some(format!("invalid duration: {}", s)), program.as_slice(), &opts);
+                    return;
+                }
+            },
+            None => 2.0
+        };
+        let handle = cmd::init_with_options(Options {
+            input: AudioInput::Device(matches.opt_str("device")),
+            verbosity: verbosity,
+            ..cmd::options_from_env()
+        });
+        let _ = writeln!(&mut io::stderr(), "calibrating... please stay silent");
+        match cmd::calibrate(&handle, Duration::milliseconds((seconds * 1000.0) as i64)) {
+            Ok(calibration) => println!("{}", calibration.to_json()),
+            Err(e) => {
+                let _ = writeln!(&mut io::stderr(), "error: {}", e);
+                os::set_exit_status(1);
+            }
+        }
+        cmd::cleanup(&handle);
+        return;
+    }
+
+    let token = match common::require_access_token(&matches) {
+        Some(token) => token,
+        None => return
+    };
+
+    let input = match (command.as_slice(), matches.free.get(1)) {
+        ("text", Some(_)) | ("voice", _) => AudioInput::Device(matches.opt_str("device")),
+        ("file", Some(path)) => AudioInput::File(Path::new(path.as_slice())),
+        _ => {
+            usage_error(None, program.as_slice(), &opts);
+            return;
+        }
+    };
+    let handle = cmd::init_with_options(Options {
+        input: input,
+        verbosity: verbosity,
+        ..cmd::options_from_env()
+    });
+
+    let result = match command.as_slice() {
+        "text" => {
+            let text = matches.free.slice_from(1).connect(" ");
+            cmd::text_query(&handle, text, token)
+        }
+        "voice" if matches.opt_present("manual") => {
+            cmd::voice_query_start(&handle, token);
+            let _ = writeln!(&mut io::stderr(), "recording... press Enter to stop");
+            let _ = io::stdin().read_line();
+            cmd::voice_query_stop(&handle)
+        }
+        "voice" => {
+            let _ = writeln!(&mut io::stderr(), "recording... (stops at the end of speech)");
+            cmd::voice_query_auto(&handle, token)
+        }
+        // the whole file, whatever its pauses: the query ends with it
+        _ => cmd::voice_query_to_end(&handle, token)
+    };
+    print_result(result, output);
+    cmd::cleanup(&handle);
+}
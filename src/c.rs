@@ -1,15 +1,18 @@
 #![allow(non_camel_case_types)]
 
 use std::c_str::CString;
-use libc::{c_char, c_uint};
+use libc::{c_char, c_int, c_uint, c_ulong, c_ulonglong, c_double, c_void};
 use cmd;
 use cmd::WitHandle;
 use std::{mem, ptr, rt, io};
+use std::time::Duration;
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicBool, SeqCst, INIT_ATOMIC_BOOL};
 use client;
-use serialize::json;
+use serialize::json::{mod, ToJson};
 use std::io::MemWriter;
 use log;
+use log::{LogSink, LogRecord, StderrSink};
 use log::LogLevel::{Error, Warn, Debug};
 use rustrt::task::Task;
 use rustrt::local::Local;
@@ -46,14 +49,94 @@ macro_rules! c_fn(
 )
 
 struct WitContext {
-    handle: WitHandle
+    handle: WitHandle,
+    // dropped to stop the task of the current level callback
+    level_stop: Mutex<Option<Sender<()>>>
 }
 
 pub type wit_context_ptr = *const ();
 
+pub type wit_log_callback = extern "C" fn(c_uint, *const c_char, *const c_char, *mut c_void);
+
+pub type wit_level_callback = extern "C" fn(c_double, c_double, c_ulong, *mut c_void);
+
+#[repr(C)]
+pub struct wit_query_metrics {
+    capture_start_ms: c_double,
+    first_speech_ms: c_double,
+    end_of_speech_ms: c_double,
+    upload_done_ms: c_double,
+    first_byte_ms: c_double,
+    total_ms: c_double,
+    audio_ms: c_double,
+    bytes_uploaded: c_ulonglong
+}
+
+fn ms(d: Duration) -> c_double {
+    d.num_microseconds().unwrap_or(0) as c_double / 1000.0
+}
+
+fn ms_opt(d: Option<Duration>) -> c_double {
+    d.map_or(-1.0, ms)
+}
+
+/**
+ * Fill the C struct, if any, whether the query succeeded or not, and return
+ * the response. A query that never started has only -1 and 0 values.
+ */
+fn c_str_result_with_metrics(result: (Result<json::Json, client::RequestError>, Option<cmd::QueryMetrics>),
+                             out: *mut wit_query_metrics) -> *const c_char {
+    let (json_result, metrics) = result;
+    if !out.is_null() {
+        let filled = match metrics {
+            Some(metrics) => wit_query_metrics {
+                capture_start_ms: ms_opt(metrics.capture_start),
+                first_speech_ms: ms_opt(metrics.first_speech),
+                end_of_speech_ms: ms_opt(metrics.end_of_speech),
+                upload_done_ms: ms_opt(metrics.upload_done),
+                first_byte_ms: ms_opt(metrics.first_byte),
+                total_ms: ms(metrics.total),
+                audio_ms: ms(metrics.audio_duration),
+                bytes_uploaded: metrics.bytes_uploaded as c_ulonglong
+            },
+            None => wit_query_metrics {
+                capture_start_ms: -1.0,
+                first_speech_ms: -1.0,
+                end_of_speech_ms: -1.0,
+                upload_done_ms: -1.0,
+                first_byte_ms: -1.0,
+                total_ms: 0.0,
+                audio_ms: 0.0,
+                bytes_uploaded: 0
+            }
+        };
+        unsafe { *out = filled };
+    }
+    c_str_result(json_result)
+}
+
+struct CallbackSink {
+    cb: wit_log_callback,
+    user_data: *mut c_void
+}
+
+// SAFETY: records are logged from any thread, concurrently. The caller of
+// wit_set_log_callback guarantees that the callback is thread-safe, and that
+// user_data stays valid until the callback is replaced.
+unsafe impl Send for CallbackSink {}
+unsafe impl Sync for CallbackSink {}
+
+impl LogSink for CallbackSink {
+    fn log(&self, record: &LogRecord) {
+        let module = record.module().to_c_str();
+        let message = record.message.to_c_str();
+        (self.cb)(record.level.as_uint() as c_uint, module.as_ptr(), message.as_ptr(), self.user_data);
+    }
+}
+
 fn to_c_str_opt(json_result: Result<json::Json, client::RequestError>) -> Option<*const c_char> {
     let opt_str = json_result.ok().and_then(|json| {
-        wit_log!(Debug, "received response: {}", json);
+        wit_log!(Debug, "received response: {}", log::redact_json(&json));
         let mut s = MemWriter::new();
         json.to_writer(&mut s as &mut io::Writer).unwrap();
         String::from_utf8(s.into_inner()).ok()
@@ -102,32 +185,63 @@ c_fn!(wit_init(device_opt: *const c_char, verbosity: c_uint) -> wit_context_ptr
     let handle = cmd::init(device, verbosity as uint);
 
     let boxed = box WitContext {
-        handle: handle
+        handle: handle,
+        level_stop: Mutex::new(None)
     };
     let res: wit_context_ptr = mem::transmute(boxed);
     res
 })
 
+c_fn!(wit_set_log_callback(cb: Option<wit_log_callback>, user_data: *mut c_void) -> () {
+    match cb {
+        Some(f) => log::set_sink(box CallbackSink { cb: f, user_data: user_data }),
+        None => log::set_sink(box StderrSink)
+    }
+})
+
+c_fn!(wit_set_privacy_mode(enabled: c_uint) -> () {
+    cmd::set_privacy_mode(enabled != 0)
+})
+
+struct LevelCallback {
+    cb: wit_level_callback,
+    user_data: *mut c_void
+}
+
+c_fn!(wit_set_level_callback(context: wit_context_ptr, interval_ms: c_uint, cb: Option<wit_level_callback>, user_data: *mut c_void) -> () {
+    let context: &WitContext = mem::transmute(context);
+    let mut level_stop = context.level_stop.lock();
+    // stops the previous callback
+    *level_stop = None;
+    match cb {
+        Some(f) => {
+            let callback = LevelCallback { cb: f, user_data: user_data };
+            let levels = cmd::levels(&context.handle, Duration::milliseconds(interval_ms as i64));
+            let (stop_tx, stop_rx) = channel::<()>();
+            *level_stop = Some(stop_tx);
+            spawn(proc() {
+                loop {
+                    select! (
+                        _ = stop_rx.recv_opt() => break,
+                        level = levels.recv_opt() => match level {
+                            Ok(level) => (callback.cb)(level.rms, level.peak, level.clips as c_ulong, callback.user_data),
+                            Err(_) => break
+                        }
+                    )
+                }
+            });
+        }
+        None => ()
+    }
+})
+
 c_fn!(wit_close(context: wit_context_ptr) -> () {
     let context: &WitContext = mem::transmute(context);
     cmd::cleanup(&context.handle)
 })
 
 c_fn!(wit_text_query(context: wit_context_ptr, text: *const c_char, access_token: *const c_char) -> *const c_char {
-    let context: &WitContext = mem::transmute(context);
-    match from_c_string(access_token) {
-        Some(access_token) => {
-            match from_c_string(text) {
-                Some(text) => {
-                    let result = cmd::text_query(&context.handle, text, access_token);
-                    return c_str_result(result)
-                },
-                None => wit_log!(Error, "failed to read query text")
-            }
-        }
-        None => wit_log!(Error, "failed to read access token")
-    };
-    ptr::null()
+    wit_text_query_with_metrics(context, text, access_token, ptr::null_mut())
 })
 
 c_fn!(wit_text_query_async(context: wit_context_ptr, text: *const c_char, access_token: *const c_char, cb: Option<extern "C" fn(*const c_char)>) -> () {
@@ -147,15 +261,7 @@ c_fn!(wit_text_query_async(context: wit_context_ptr, text: *const c_char, access
 })
 
 c_fn!(wit_voice_query_auto(context: wit_context_ptr, access_token: *const c_char) -> *const c_char {
-    let context: &WitContext = mem::transmute(context);
-    match from_c_string(access_token) {
-        Some(access_token) => {
-            let result = cmd::voice_query_auto(&context.handle, access_token);
-            return c_str_result(result)
-        }
-        None => wit_log!(Error, "failed to read access token")
-    }
-    ptr::null()
+    wit_voice_query_auto_with_metrics(context, access_token, ptr::null_mut())
 })
 
 c_fn!(wit_voice_query_auto_async(context: wit_context_ptr, access_token: *const c_char, cb: Option<extern "C" fn(*const c_char)>) -> () {
@@ -178,9 +284,7 @@ c_fn!(wit_voice_query_start(context: wit_context_ptr, access_token: *const c_cha
 })
 
 c_fn!(wit_voice_query_stop(context: wit_context_ptr) -> *const c_char {
-    let context: &WitContext = mem::transmute(context);
-    let result = cmd::voice_query_stop(&context.handle);
-    c_str_result(result)
+    wit_voice_query_stop_with_metrics(context, ptr::null_mut())
 })
 
 c_fn!(wit_voice_query_stop_async(context: wit_context_ptr, cb: Option<extern "C" fn(*const c_char)>) -> () {
@@ -188,3 +292,67 @@ c_fn!(wit_voice_query_stop_async(context: wit_context_ptr, cb: Option<extern "C"
     let receiver = cmd::voice_query_stop_async(&context.handle);
     receive_with_callback(receiver, cb);
 })
+
+c_fn!(wit_text_query_with_metrics(context: wit_context_ptr, text: *const c_char, access_token: *const c_char, metrics: *mut wit_query_metrics) -> *const c_char {
+    let context: &WitContext = mem::transmute(context);
+    match from_c_string(access_token) {
+        Some(access_token) => {
+            match from_c_string(text) {
+                Some(text) => {
+                    let result = cmd::text_query_with_metrics(&context.handle, text, access_token);
+                    return c_str_result_with_metrics(result, metrics)
+                },
+                None => wit_log!(Error, "failed to read query text")
+            }
+        }
+        None => wit_log!(Error, "failed to read access token")
+    };
+    // the query never started
+    c_str_result_with_metrics((Err(client::RequestError::ClientError), None), metrics)
+})
+
+c_fn!(wit_voice_query_auto_with_metrics(context: wit_context_ptr, access_token: *const c_char, metrics: *mut wit_query_metrics) -> *const c_char {
+    let context: &WitContext = mem::transmute(context);
+    match from_c_string(access_token) {
+        Some(access_token) => {
+            let result = cmd::voice_query_auto_with_metrics(&context.handle, access_token);
+            return c_str_result_with_metrics(result, metrics)
+        }
+        None => wit_log!(Error, "failed to read access token")
+    }
+    // the query never started
+    c_str_result_with_metrics((Err(client::RequestError::ClientError), None), metrics)
+})
+
+c_fn!(wit_voice_query_stop_with_metrics(context: wit_context_ptr, metrics: *mut wit_query_metrics) -> *const c_char {
+    let context: &WitContext = mem::transmute(context);
+    let result = cmd::voice_query_stop_with_metrics(&context.handle);
+    c_str_result_with_metrics(result, metrics)
+})
+
+c_fn!(wit_calibrate(context: wit_context_ptr, duration_ms: c_uint) -> *const c_char {
+    let context: &WitContext = mem::transmute(context);
+    let result = cmd::calibrate(&context.handle, Duration::milliseconds(duration_ms as i64));
+    c_str_result(result.map(|calibration| calibration.to_json()))
+})
+
+c_fn!(wit_set_calibration(context: wit_context_ptr, calibration: *const c_char) -> c_int {
+    let context: &WitContext = mem::transmute(context);
+    if calibration.is_null() {
+        cmd::set_calibration(&context.handle, None);
+        return 0;
+    }
+    let parsed = from_c_string(calibration)
+        .and_then(|s| json::from_str(s.as_slice()).ok())
+        .and_then(|json| cmd::Calibration::from_json(&json));
+    match parsed {
+        Some(calibration) => {
+            cmd::set_calibration(&context.handle, Some(calibration));
+            0
+        }
+        None => {
+            wit_log!(Error, "failed to read the calibration");
+            -1
+        }
+    }
+})
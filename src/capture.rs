@@ -0,0 +1,320 @@
+use std::cmp;
+use std::io::{mod, File, IoResult};
+use std::io::fs::{mod, PathExtensions};
+use std::collections::BTreeMap;
+use serialize::json::{mod, Json};
+use time;
+
+use client::RequestError;
+use log;
+use log::LogLevel::{Error, Warn, Info, Debug};
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+/**
+ * Where to save the audio sent with each voice query, along with the response.
+ * When max_bytes is set, the oldest sessions are deleted to keep the directory under that size.
+ */
+#[deriving(Clone)]
+pub struct CaptureOptions {
+    pub dir: Path,
+    pub max_bytes: Option<u64>
+}
+
+/**
+ * Minimal writer for 16-bit mono PCM WAV files. The header sizes are
+ * patched when the writer is finished.
+ */
+pub struct WavWriter {
+    file: File,
+    data_bytes: u32
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, rate: u32) -> IoResult<WavWriter> {
+        let mut file = try!(File::create(path));
+        try!(file.write(b"RIFF"));
+        try!(file.write_le_u32(WAV_HEADER_SIZE - 8));
+        try!(file.write(b"WAVE"));
+        try!(file.write(b"fmt "));
+        try!(file.write_le_u32(16));
+        try!(file.write_le_u16(1)); // PCM
+        try!(file.write_le_u16(1)); // mono
+        try!(file.write_le_u32(rate));
+        try!(file.write_le_u32(rate * 2));
+        try!(file.write_le_u16(2));
+        try!(file.write_le_u16(16));
+        try!(file.write(b"data"));
+        try!(file.write_le_u32(0));
+        Ok(WavWriter {
+            file: file,
+            data_bytes: 0
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> IoResult<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples.iter() {
+            bytes.push(sample as u8);
+            bytes.push((sample >> 8) as u8);
+        }
+        try!(self.file.write(bytes.as_slice()));
+        self.data_bytes += bytes.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> IoResult<()> {
+        try!(self.file.seek(4, io::SeekSet));
+        try!(self.file.write_le_u32(WAV_HEADER_SIZE - 8 + self.data_bytes));
+        try!(self.file.seek(40, io::SeekSet));
+        try!(self.file.write_le_u32(self.data_bytes));
+        self.file.flush()
+    }
+}
+
+/**
+ * Create the capture directory if needed, and return the path of the WAV file
+ * for a new session, named after the current time.
+ */
+pub fn new_session(opts: &CaptureOptions) -> Option<Path> {
+    if !opts.dir.is_dir() {
+        match fs::mkdir_recursive(&opts.dir, io::USER_RWX) {
+            Ok(()) => (),
+            Err(e) => {
+                wit_log!(Error, "failed to create capture directory {}: {}", opts.dir.display(), e);
+                return None;
+            }
+        }
+    }
+    let now = time::get_time();
+    let name = format!("{}{:03}.wav", now.sec, now.nsec / 1000000);
+    Some(opts.dir.join(name))
+}
+
+/**
+ * Whether a msg_id can name files: it comes from the server (or a cassette),
+ * and must not be able to point outside the capture directory.
+ */
+fn is_safe_msg_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| {
+        (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || c == '_' || c == '-'
+    })
+}
+
+/**
+ * Save the response next to the captured audio. When the response has a msg_id
+ * made of letters, digits, '_' and '-', both files are renamed after it. Then
+ * apply the retention limit.
+ */
+pub fn save_response(opts: &CaptureOptions, wav_path: &Path, result: &Result<Json, RequestError>) {
+    let msg_id = match *result {
+        Ok(ref json) => json.find("msg_id").and_then(|id| id.as_string()).map(|id| id.to_string()),
+        Err(_) => None
+    };
+    let msg_id = msg_id.and_then(|id| {
+        if is_safe_msg_id(id.as_slice()) {
+            Some(id)
+        } else {
+            wit_log!(Warn, "not naming the capture after the msg_id {}", id);
+            None
+        }
+    });
+    let wav_path = match msg_id {
+        Some(id) => {
+            let renamed = opts.dir.join(format!("{}.wav", id));
+            match fs::rename(wav_path, &renamed) {
+                Ok(()) => renamed,
+                Err(e) => {
+                    wit_log!(Error, "failed to rename {}: {}", wav_path.display(), e);
+                    wav_path.clone()
+                }
+            }
+        }
+        None => wav_path.clone()
+    };
+    let json = match *result {
+        Ok(ref json) => json.clone(),
+        Err(ref e) => {
+            let mut obj = BTreeMap::new();
+            obj.insert("error".to_string(), Json::String(format!("{}", e)));
+            Json::Object(obj)
+        }
+    };
+    let json_path = wav_path.with_extension("json");
+    let written = File::create(&json_path).and_then(|mut f| {
+        f.write_str(json::encode(&json).as_slice())
+    });
+    match written {
+        Ok(()) => wit_log!(Info, "saved captured session to {}", wav_path.display()),
+        Err(e) => wit_log!(Error, "failed to write {}: {}", json_path.display(), e)
+    }
+    match opts.max_bytes {
+        Some(max_bytes) => enforce_retention(&opts.dir, max_bytes),
+        None => ()
+    }
+}
+
+fn enforce_retention(dir: &Path, max_bytes: u64) {
+    let paths = match fs::readdir(dir) {
+        Ok(paths) => paths,
+        Err(e) => {
+            wit_log!(Error, "failed to list capture directory {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    // (modification time, size, files) for each session
+    let mut sessions: Vec<(u64, u64, Vec<Path>)> = Vec::new();
+    let mut total = 0u64;
+    for wav in paths.iter().filter(|p| p.extension_str() == Some("wav")) {
+        let mut files = vec![wav.clone()];
+        let json = wav.with_extension("json");
+        if json.exists() {
+            files.push(json);
+        }
+        let mut size = 0u64;
+        let mut modified = 0u64;
+        for file in files.iter() {
+            match file.stat() {
+                Ok(stat) => {
+                    size += stat.size;
+                    modified = cmp::max(modified, stat.modified);
+                }
+                Err(_) => ()
+            }
+        }
+        total += size;
+        sessions.push((modified, size, files));
+    }
+    sessions.sort_by(|&(a, _, _), &(b, _, _)| a.cmp(&b));
+    for &(_, size, ref files) in sessions.iter() {
+        if total <= max_bytes {
+            break;
+        }
+        for file in files.iter() {
+            match fs::unlink(file) {
+                Ok(()) => wit_log!(Debug, "deleted old capture {}", file.display()),
+                Err(e) => wit_log!(Error, "failed to delete {}: {}", file.display(), e)
+            }
+        }
+        total -= size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{File, TempDir};
+    use std::io::fs::{mod, PathExtensions};
+    use serialize::json::{mod, Json};
+    use client::RequestError;
+    use super::{CaptureOptions, WavWriter, enforce_retention, save_response, is_safe_msg_id};
+
+    fn le_u32(bytes: &[u8], at: uint) -> u32 {
+        range(0u, 4).fold(0u32, |acc, i| acc | (bytes[at + i] as u32 << (8 * i)))
+    }
+
+    fn le_u16(bytes: &[u8], at: uint) -> u16 {
+        bytes[at] as u16 | (bytes[at + 1] as u16 << 8)
+    }
+
+    fn touch(path: &Path, size: uint, modified_ms: u64) {
+        File::create(path).write(Vec::from_elem(size, 0u8).as_slice()).unwrap();
+        fs::change_file_times(path, modified_ms, modified_ms).unwrap();
+    }
+
+    #[test]
+    fn wav_header_describes_the_samples() {
+        let dir = TempDir::new("wit-capture").unwrap();
+        let path = dir.path().join("session.wav");
+        let mut writer = WavWriter::create(&path, 16000).unwrap();
+        writer.write_samples(&[1, -1, 0x1234]).unwrap();
+        writer.write_samples(&[-32768]).unwrap();
+        writer.finish().unwrap();
+
+        let bytes = File::open(&path).read_to_end().unwrap();
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(bytes.slice(0, 4), b"RIFF");
+        assert_eq!(le_u32(bytes.as_slice(), 4), 36 + 8);
+        assert_eq!(bytes.slice(8, 16), b"WAVEfmt ");
+        assert_eq!(le_u32(bytes.as_slice(), 16), 16);
+        // PCM, mono, rate, bytes per second, block size, bits
+        assert_eq!(le_u16(bytes.as_slice(), 20), 1);
+        assert_eq!(le_u16(bytes.as_slice(), 22), 1);
+        assert_eq!(le_u32(bytes.as_slice(), 24), 16000);
+        assert_eq!(le_u32(bytes.as_slice(), 28), 32000);
+        assert_eq!(le_u16(bytes.as_slice(), 32), 2);
+        assert_eq!(le_u16(bytes.as_slice(), 34), 16);
+        assert_eq!(bytes.slice(36, 40), b"data");
+        assert_eq!(le_u32(bytes.as_slice(), 40), 8);
+        assert_eq!(bytes.slice_from(44), [0x01, 0x00, 0xff, 0xff, 0x34, 0x12, 0x00, 0x80].as_slice());
+    }
+
+    #[test]
+    fn retention_deletes_the_oldest_sessions() {
+        let dir = TempDir::new("wit-capture").unwrap();
+        let path = |name: &str| dir.path().join(name);
+        touch(&path("1.wav"), 100, 1000);
+        touch(&path("1.json"), 10, 1000);
+        touch(&path("2.wav"), 100, 2000);
+        touch(&path("2.json"), 10, 2000);
+        touch(&path("3.wav"), 100, 3000);
+        // not a session
+        touch(&path("notes.txt"), 1000, 0);
+
+        enforce_retention(dir.path(), 250);
+        assert!(!path("1.wav").exists() && !path("1.json").exists());
+        assert!(path("2.wav").exists() && path("2.json").exists());
+        assert!(path("3.wav").exists());
+        assert!(path("notes.txt").exists());
+
+        enforce_retention(dir.path(), 100);
+        assert!(!path("2.wav").exists() && !path("2.json").exists());
+        assert!(path("3.wav").exists());
+    }
+
+    #[test]
+    fn sessions_are_renamed_after_the_msg_id() {
+        let dir = TempDir::new("wit-capture").unwrap();
+        let opts = CaptureOptions { dir: dir.path().clone(), max_bytes: None };
+        let wav = dir.path().join("1234.wav");
+        touch(&wav, 44, 1000);
+        let response = json::from_str(r#"{"msg_id": "abc_DEF-42", "outcomes": []}"#).unwrap();
+        save_response(&opts, &wav, &Ok(response.clone()));
+        assert!(!wav.exists());
+        assert!(dir.path().join("abc_DEF-42.wav").exists());
+        let saved = File::open(&dir.path().join("abc_DEF-42.json")).read_to_string().unwrap();
+        assert_eq!(json::from_str(saved.as_slice()).unwrap(), response);
+    }
+
+    #[test]
+    fn unsafe_msg_ids_keep_the_timestamp_name() {
+        let dir = TempDir::new("wit-capture").unwrap();
+        let opts = CaptureOptions { dir: dir.path().join("captures"), max_bytes: None };
+        fs::mkdir(&opts.dir, ::std::io::USER_RWX).unwrap();
+        let wav = opts.dir.join("1234.wav");
+        touch(&wav, 44, 1000);
+        let response = json::from_str(r#"{"msg_id": "../outside"}"#).unwrap();
+        save_response(&opts, &wav, &Ok(response));
+        assert!(wav.exists());
+        assert!(opts.dir.join("1234.json").exists());
+        assert!(!dir.path().join("outside.wav").exists());
+        assert!(!dir.path().join("outside.json").exists());
+
+        // errors are saved too
+        let wav = opts.dir.join("5678.wav");
+        touch(&wav, 44, 2000);
+        save_response(&opts, &wav, &Err(RequestError::ClientError));
+        let saved: Json = json::from_str(File::open(&opts.dir.join("5678.json")).read_to_string().unwrap().as_slice()).unwrap();
+        assert!(saved.find("error").is_some());
+    }
+
+    #[test]
+    fn msg_ids_are_plain_names() {
+        assert!(is_safe_msg_id("8d0fc1cb-8ae4-4d9d-ab2b-66a6d09fd0c8"));
+        assert!(!is_safe_msg_id(""));
+        assert!(!is_safe_msg_id(".."));
+        assert!(!is_safe_msg_id("/etc/passwd"));
+        assert!(!is_safe_msg_id("a/b"));
+        assert!(!is_safe_msg_id("a.b"));
+        assert!(!is_safe_msg_id("café"));
+    }
+}
@@ -0,0 +1,470 @@
+use std::os;
+use std::io::{mod, File, IoResult};
+use std::num::FromPrimitive;
+use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use hyper::Url;
+use serialize::json::{mod, Json};
+use url;
+
+use client::RequestError;
+use transport::{HttpTransport, HttpHeaders, HttpResponse, Method};
+use log;
+use log::LogLevel::{Error, Info, Debug};
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum CassetteMode {
+    /// Send requests through the transport, and append each interaction to the cassette
+    Record,
+    /// Serve responses from the cassette, without any network access
+    Replay
+}
+
+/**
+ * A cassette file stores one request/response pair per line, as JSON.
+ */
+#[deriving(Clone)]
+pub struct CassetteOptions {
+    pub mode: CassetteMode,
+    pub path: Path
+}
+
+impl CassetteOptions {
+    /**
+     * Read the cassette options from the WIT_CASSETTE (path) and WIT_CASSETTE_MODE
+     * ("record" or "replay", replay by default) environment variables.
+     */
+    pub fn from_env() -> Option<CassetteOptions> {
+        os::getenv("WIT_CASSETTE").map(|path| {
+            let mode = match os::getenv("WIT_CASSETTE_MODE") {
+                Some(ref mode) if mode.as_slice() == "record" => CassetteMode::Record,
+                _ => CassetteMode::Replay
+            };
+            CassetteOptions {
+                mode: mode,
+                path: Path::new(path)
+            }
+        })
+    }
+
+    /**
+     * Wrap a transport according to the mode.
+     */
+    pub fn wrap(&self, inner: Arc<Box<HttpTransport + Send + Sync>>) -> Arc<Box<HttpTransport + Send + Sync>> {
+        wit_log!(Info, "using cassette {} ({})", self.path.display(), self.mode);
+        match self.mode {
+            CassetteMode::Record => Arc::new(box RecordingTransport::new(inner, self.path.clone()) as Box<HttpTransport + Send + Sync>),
+            CassetteMode::Replay => Arc::new(box ReplayTransport::load(&self.path) as Box<HttpTransport + Send + Sync>)
+        }
+    }
+}
+
+/**
+ * What identifies a request in a cassette. The host is left out, so that
+ * cassettes can be replayed against any base url.
+ */
+#[deriving(Clone, PartialEq, Show)]
+struct RequestKey {
+    method: Method,
+    path: String,
+    query: Option<String>,
+    content_type: Option<String>,
+    audio_hash: Option<String>
+}
+
+impl RequestKey {
+    fn new(method: Method, url: &str, headers: &HttpHeaders, body: Option<&[u8]>) -> RequestKey {
+        let (path, query) = match Url::parse(url) {
+            Ok(url) => (url.serialize_path().unwrap_or("/".to_string()), url.query.as_ref().map(|q| normalize_query(q.as_slice()))),
+            Err(_) => (url.to_string(), None)
+        };
+        let content_type = headers.iter()
+            .find(|&&(ref name, _)| name.as_slice() == "Content-Type")
+            .map(|&(_, ref value)| value.clone());
+        RequestKey {
+            method: method,
+            path: path,
+            query: query,
+            content_type: content_type,
+            audio_hash: body.map(hash_bytes)
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("method".to_string(), Json::String(format!("{}", self.method)));
+        obj.insert("path".to_string(), Json::String(self.path.clone()));
+        obj.insert("query".to_string(), opt_string_to_json(&self.query));
+        obj.insert("content_type".to_string(), opt_string_to_json(&self.content_type));
+        obj.insert("audio_hash".to_string(), opt_string_to_json(&self.audio_hash));
+        Json::Object(obj)
+    }
+
+    fn from_json(json: &Json) -> Option<RequestKey> {
+        let method = match json.find("method").and_then(|m| m.as_string()) {
+            Some("Get") => Method::Get,
+            Some("Post") => Method::Post,
+            _ => return None
+        };
+        let path = match json.find("path").and_then(|p| p.as_string()) {
+            Some(path) => path.to_string(),
+            None => return None
+        };
+        Some(RequestKey {
+            method: method,
+            path: path,
+            query: opt_string_from_json(json, "query").map(|q| normalize_query(q.as_slice())),
+            content_type: opt_string_from_json(json, "content_type"),
+            audio_hash: opt_string_from_json(json, "audio_hash")
+        })
+    }
+}
+
+fn opt_string_to_json(s: &Option<String>) -> Json {
+    match *s {
+        Some(ref s) => Json::String(s.clone()),
+        None => Json::Null
+    }
+}
+
+fn opt_string_from_json(json: &Json, key: &str) -> Option<String> {
+    json.find(key).and_then(|v| v.as_string()).map(|s| s.to_string())
+}
+
+/**
+ * The same query string, whatever the encoding of its spaces (+ or %20) and
+ * of the other characters, so that older cassettes keep matching.
+ */
+fn normalize_query(query: &str) -> String {
+    url::form_urlencoded::serialize_owned(url::form_urlencoded::parse(query.as_bytes()).as_slice())
+}
+
+/**
+ * 64-bit FNV-1a. Unlike the hashers of std, its output is specified, so that
+ * the audio hashes of a cassette stay valid across Rust releases.
+ */
+struct Fnv {
+    state: u64
+}
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv { state: 0xcbf29ce484222325 }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes.iter() {
+            self.state = (self.state ^ b as u64) * 0x100000001b3;
+        }
+    }
+
+    fn result(&self) -> String {
+        format!("{:016x}", self.state)
+    }
+}
+
+fn hash_bytes(bytes: &[u8]) -> String {
+    let mut fnv = Fnv::new();
+    fnv.write(bytes);
+    fnv.result()
+}
+
+fn response_to_json(response: &HttpResponse) -> Json {
+    let mut obj = BTreeMap::new();
+    obj.insert("status".to_string(), Json::U64(response.status as u64));
+    obj.insert("body".to_string(), Json::String(response.body.clone()));
+    Json::Object(obj)
+}
+
+fn response_from_json(json: &Json) -> Option<HttpResponse> {
+    let status = json.find("status").and_then(|s| s.as_u64()).and_then(|s| FromPrimitive::from_u64(s));
+    let body = json.find("body").and_then(|b| b.as_string());
+    match (status, body) {
+        (Some(status), Some(body)) => Some(HttpResponse {
+            status: status,
+            body: body.to_string(),
+            received_at: None
+        }),
+        _ => None
+    }
+}
+
+/**
+ * Reader counting and hashing what goes through it.
+ */
+struct HashingReader<'a> {
+    inner: &'a mut (Reader + 'a),
+    hash: Fnv
+}
+
+impl<'a> Reader for HashingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let n = try!(self.inner.read(buf));
+        self.hash.write(buf.slice_to(n));
+        Ok(n)
+    }
+}
+
+/**
+ * Transport recording every interaction with the inner transport to a cassette.
+ */
+pub struct RecordingTransport {
+    inner: Arc<Box<HttpTransport + Send + Sync>>,
+    path: Path,
+    lock: Mutex<()>
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Arc<Box<HttpTransport + Send + Sync>>, path: Path) -> RecordingTransport {
+        RecordingTransport {
+            inner: inner,
+            path: path,
+            lock: Mutex::new(())
+        }
+    }
+
+    fn record(&self, key: &RequestKey, result: &Result<HttpResponse, RequestError>) {
+        let response = match *result {
+            Ok(ref response) => response,
+            // only actual responses can be replayed
+            Err(_) => return
+        };
+        let mut obj = BTreeMap::new();
+        obj.insert("request".to_string(), key.to_json());
+        obj.insert("response".to_string(), response_to_json(response));
+        let line = json::encode(&Json::Object(obj));
+        let _guard = self.lock.lock();
+        let written = File::open_mode(&self.path, io::Append, io::Write).and_then(|mut f| {
+            f.write_line(line.as_slice())
+        });
+        match written {
+            Ok(()) => wit_log!(Debug, "recorded {} {} to cassette", key.method, key.path),
+            Err(e) => wit_log!(Error, "failed to write to cassette {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+impl HttpTransport for RecordingTransport {
+    fn get(&self, url: &str, headers: &HttpHeaders) -> Result<HttpResponse, RequestError> {
+        let result = self.inner.get(url, headers);
+        self.record(&RequestKey::new(Method::Get, url, headers, None), &result);
+        result
+    }
+
+    fn post(&self, url: &str, headers: &HttpHeaders, body: &mut Reader) -> Result<HttpResponse, RequestError> {
+        let mut hashing = HashingReader {
+            inner: body,
+            hash: Fnv::new()
+        };
+        let result = self.inner.post(url, headers, &mut hashing);
+        let mut key = RequestKey::new(Method::Post, url, headers, None);
+        key.audio_hash = Some(hashing.hash.result());
+        self.record(&key, &result);
+        result
+    }
+}
+
+/**
+ * Transport serving responses from a cassette. Each recorded interaction is
+ * used once, in order; when all the matching ones have been used, the last
+ * one is served again.
+ */
+pub struct ReplayTransport {
+    interactions: Vec<(RequestKey, HttpResponse)>,
+    used: Mutex<Vec<bool>>
+}
+
+impl ReplayTransport {
+    pub fn load(path: &Path) -> ReplayTransport {
+        let contents = File::open(path).and_then(|mut f| f.read_to_string());
+        let interactions: Vec<(RequestKey, HttpResponse)> = match contents {
+            Ok(contents) => contents.as_slice().lines().filter_map(|line| {
+                json::from_str(line).ok().and_then(|json| {
+                    let key = json.find("request").and_then(RequestKey::from_json);
+                    let response = json.find("response").and_then(response_from_json);
+                    match (key, response) {
+                        (Some(key), Some(response)) => Some((key, response)),
+                        _ => {
+                            wit_log!(Error, "ignoring invalid cassette entry");
+                            None
+                        }
+                    }
+                })
+            }).collect(),
+            Err(e) => {
+                wit_log!(Error, "failed to read cassette {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        wit_log!(Info, "loaded {} interactions from cassette", interactions.len());
+        let used = Vec::from_elem(interactions.len(), false);
+        ReplayTransport {
+            interactions: interactions,
+            used: Mutex::new(used)
+        }
+    }
+
+    fn replay(&self, key: RequestKey) -> Result<HttpResponse, RequestError> {
+        let mut used = self.used.lock();
+        let mut last_match = None;
+        for (i, &(ref recorded, ref response)) in self.interactions.iter().enumerate() {
+            if *recorded != key {
+                continue;
+            }
+            if !used[i] {
+                used[i] = true;
+                return Ok(response.clone());
+            }
+            last_match = Some(response);
+        }
+        match last_match {
+            Some(response) => Ok(response.clone()),
+            None => {
+                wit_log!(Error, "no cassette entry for {} {}", key.method, key.path);
+                Err(RequestError::ClientError)
+            }
+        }
+    }
+}
+
+impl HttpTransport for ReplayTransport {
+    fn get(&self, url: &str, headers: &HttpHeaders) -> Result<HttpResponse, RequestError> {
+        self.replay(RequestKey::new(Method::Get, url, headers, None))
+    }
+
+    fn post(&self, url: &str, headers: &HttpHeaders, body: &mut Reader) -> Result<HttpResponse, RequestError> {
+        let bytes = match body.read_to_end() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                wit_log!(Error, "failed to read request body: {}", e);
+                return Err(RequestError::ClientError);
+            }
+        };
+        self.replay(RequestKey::new(Method::Post, url, headers, Some(bytes.as_slice())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{File, MemReader, TempDir};
+    use std::sync::Arc;
+    use hyper::status::StatusCode;
+    use client::RequestError;
+    use transport::{HttpTransport, HttpHeaders, HttpResponse, MockTransport};
+    use super::{hash_bytes, normalize_query, RecordingTransport, ReplayTransport};
+
+    fn audio_headers() -> HttpHeaders {
+        vec![("Content-Type".to_string(), "audio/raw; encoding=signed-integer; bits=16; rate=16000; endian=little".to_string()),
+             ("Authorization".to_string(), "Bearer token".to_string())]
+    }
+
+    fn body(result: Result<HttpResponse, RequestError>) -> String {
+        match result {
+            Ok(response) => response.body,
+            Err(e) => panic!("unexpected error: {}", e)
+        }
+    }
+
+    fn post(transport: &HttpTransport, url: &str, audio: &[u8]) -> Result<HttpResponse, RequestError> {
+        transport.post(url, &audio_headers(), &mut MemReader::new(audio.to_vec()))
+    }
+
+    /**
+     * Record the given (query, response) pairs of text queries to a cassette.
+     */
+    fn record(dir: &TempDir, texts: &[(&str, &str)]) -> Path {
+        let path = dir.path().join("cassette.jsonl");
+        let mock = MockTransport::new();
+        for &(_, response) in texts.iter() {
+            mock.push_response(StatusCode::Ok, response);
+        }
+        let recording = RecordingTransport::new(Arc::new(box mock.clone() as Box<HttpTransport + Send + Sync>), path.clone());
+        for &(text, response) in texts.iter() {
+            let url = format!("https://api.wit.ai/message?q={}", text);
+            assert_eq!(body(recording.get(url.as_slice(), &Vec::new())), response.to_string());
+        }
+        assert_eq!(mock.requests().len(), texts.len());
+        path
+    }
+
+    #[test]
+    fn recorded_interactions_are_replayed() {
+        let dir = TempDir::new("wit-cassette").unwrap();
+        let path = dir.path().join("cassette.jsonl");
+        let mock = MockTransport::new();
+        mock.push_response(StatusCode::Ok, r#"{"_text": "hello"}"#);
+        mock.push_response(StatusCode::Ok, r#"{"_text": "lights on"}"#);
+        let recording = RecordingTransport::new(Arc::new(box mock.clone() as Box<HttpTransport + Send + Sync>), path.clone());
+        body(recording.get("https://api.wit.ai/message?q=hello", &Vec::new()));
+        body(post(&recording, "https://api.wit.ai/speech", &[1, 2, 3, 4]));
+        // the audio went through to the server
+        assert_eq!(mock.requests()[1].body, vec![1, 2, 3, 4]);
+
+        // against another host, with the spaces encoded differently
+        let replay = ReplayTransport::load(&path);
+        assert_eq!(body(replay.get("http://localhost:8080/message?q=hello", &Vec::new())).as_slice(), r#"{"_text": "hello"}"#);
+        assert_eq!(body(post(&replay, "http://localhost:8080/speech", &[1, 2, 3, 4])).as_slice(), r#"{"_text": "lights on"}"#);
+    }
+
+    #[test]
+    fn unknown_requests_fail() {
+        let dir = TempDir::new("wit-cassette").unwrap();
+        let path = record(&dir, &[("hello", "{}")]);
+        let replay = ReplayTransport::load(&path);
+        match replay.get("https://api.wit.ai/message?q=goodbye", &Vec::new()) {
+            Err(RequestError::ClientError) => (),
+            other => panic!("unexpected result: {}", other.map(|r| r.body))
+        }
+        // nor is audio recorded
+        match post(&replay, "https://api.wit.ai/speech", &[1, 2]) {
+            Err(RequestError::ClientError) => (),
+            other => panic!("unexpected result: {}", other.map(|r| r.body))
+        }
+    }
+
+    #[test]
+    fn entries_are_used_once_then_the_last_repeats() {
+        let dir = TempDir::new("wit-cassette").unwrap();
+        let path = record(&dir, &[("hello", "first"), ("other", "other"), ("hello", "second")]);
+        let replay = ReplayTransport::load(&path);
+        let hello = |replay: &ReplayTransport| body(replay.get("https://api.wit.ai/message?q=hello", &Vec::new()));
+        assert_eq!(hello(&replay).as_slice(), "first");
+        assert_eq!(hello(&replay).as_slice(), "second");
+        assert_eq!(hello(&replay).as_slice(), "second");
+        assert_eq!(body(replay.get("https://api.wit.ai/message?q=other", &Vec::new())).as_slice(), "other");
+    }
+
+    #[test]
+    fn malformed_entries_are_skipped() {
+        let dir = TempDir::new("wit-cassette").unwrap();
+        let path = record(&dir, &[("hello", "kept")]);
+        let recorded = File::open(&path).read_to_string().unwrap();
+        let mut f = File::create(&path).unwrap();
+        f.write_line("not json").unwrap();
+        f.write_line(r#"{"request": {"method": "Delete", "path": "/message"}, "response": {"status": 200, "body": ""}}"#).unwrap();
+        f.write_line(r#"{"request": {"method": "Get", "path": "/message"}}"#).unwrap();
+        f.write_str(recorded.as_slice()).unwrap();
+        drop(f);
+        let replay = ReplayTransport::load(&path);
+        assert_eq!(replay.interactions.len(), 1);
+        assert_eq!(body(replay.get("https://api.wit.ai/message?q=hello", &Vec::new())).as_slice(), "kept");
+
+        // a missing cassette replays nothing
+        let replay = ReplayTransport::load(&dir.path().join("missing.jsonl"));
+        assert!(replay.get("https://api.wit.ai/message?q=hello", &Vec::new()).is_err());
+    }
+
+    #[test]
+    fn audio_hash_is_fnv1a() {
+        // reference values of the FNV-1a specification
+        assert_eq!(hash_bytes(b"").as_slice(), "cbf29ce484222325");
+        assert_eq!(hash_bytes(b"a").as_slice(), "af63dc4c8601ec8c");
+        assert_eq!(hash_bytes(b"foobar").as_slice(), "85944171f73967e8");
+    }
+
+    #[test]
+    fn queries_match_whatever_the_encoding_of_spaces() {
+        assert_eq!(normalize_query("q=turn%20on%20the%20lights"), normalize_query("q=turn+on+the+lights"));
+        assert_eq!(normalize_query("q=caf%C3%A9&context=%7B%7D"), normalize_query("q=caf%c3%a9&context=%7b%7d"));
+    }
+}
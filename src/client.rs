@@ -1,25 +1,35 @@
-use std::io;
-use std::io::util::copy;
-use hyper::client::request::Request;
-use hyper::client::response::Response;
-use hyper::Url;
-use hyper::header::Headers;
-use hyper::header::common::{ContentType, Authorization, Accept};
-//use hyper::header::common::TransferEncoding;
-//use hyper::header::common::transfer_encoding::Encoding;
+use std::default::Default;
+use std::sync::Arc;
+use std::time::Duration;
 use hyper::status::{StatusCode, StatusClass};
-use mime::{Mime, TopLevel, SubLevel, Attr, Value};
+use mime::{Mime, TopLevel, SubLevel};
 use serialize::json::{mod, Json};
 use url;
 
-use mic;
+use mic::{mod, VadOptions, Calibration};
+use preprocess::PreprocessOptions;
+use codec::Codec;
+use pipe::PipelineOptions;
+use metrics::{QueryMetrics, Recorder, UploadReader};
+use source::{mod, AudioInput};
+use capture::{mod, CaptureOptions};
+use transport::{HttpTransport, HttpHeaders, HttpResponse, HyperTransport};
+use cassette::CassetteOptions;
+use events::{EventBus, LevelBus, WitEvent, Level};
 use log;
 use log::LogLevel::{Error, Warn, Info, Debug};
 
 pub enum WitCommand {
-    Text(String, String, Sender<Result<Json, RequestError>>),
-    Start(String, Option<Sender<Result<Json, RequestError>>>),
-    Stop(Sender<Result<Json, RequestError>>),
+    Text(String, String, Sender<Result<Json, RequestError>>, Option<Sender<QueryMetrics>>),
+    // the flag enables end-of-speech detection
+    Start(String, Option<Sender<Result<Json, RequestError>>>, bool, Option<Sender<QueryMetrics>>),
+    Stop(Sender<Result<Json, RequestError>>, Option<Sender<QueryMetrics>>),
+    SetContext(Option<Json>),
+    SetInput(AudioInput),
+    Subscribe(Sender<WitEvent>),
+    Levels(Duration, Sender<Level>),
+    Calibrate(Duration, Sender<Result<Calibration, RequestError>>),
+    SetCalibration(Option<Calibration>),
     Cleanup
 }
 
@@ -31,6 +41,15 @@ pub enum RequestError {
     ChannelClosedError,
     ClientError,
     InternalError,
+    // libsox is missing, or libwit was built without audio support
+    AudioUnavailable,
+    // the audio input (device, file or pipe) couldn't be opened or read, or
+    // was only digital silence when calibrating
+    DeviceError,
+    VadUnavailable,
+    // the codec needs a feature libwit was built without, or another sample rate
+    CodecUnavailable,
+    Busy,
     ParserError(json::ParserError),
     StatusError(StatusCode)
 }
@@ -41,139 +60,263 @@ enum State {
     Stopped
 }
 
+impl State {
+    fn name(&self) -> &'static str {
+        match *self {
+            State::Ongoing(_) => "recording",
+            State::Idle => "idle",
+            State::Stopped => "stopped"
+        }
+    }
+}
+
 struct Context {
-    http: Receiver<Result<Json,RequestError>>,
+    http: Receiver<(Result<Json,RequestError>, QueryMetrics)>,
     mic: Sender<bool>,
-    client: Option<Sender<Result<Json,RequestError>>>
+    client: Option<Sender<Result<Json,RequestError>>>,
+    metrics: Option<Sender<QueryMetrics>>
 }
 
+pub const DEFAULT_BASE_URL: &'static str = "https://api.wit.ai";
+
 #[deriving(Clone)]
 pub struct Options {
-    pub input_device: Option<String>,
-    pub verbosity: uint
+    pub input: AudioInput,
+    pub verbosity: uint,
+    pub capture: Option<CaptureOptions>,
+    pub transport: Arc<Box<HttpTransport + Send + Sync>>,
+    pub cassette: Option<CassetteOptions>,
+    pub base_url: String,
+    // sent with every query (see the Wit API documentation for its format)
+    pub context: Option<Json>,
+    // end-of-speech detection, for voice_query_auto
+    pub vad: VadOptions,
+    // applied to the audio before the VAD and the upload
+    pub preprocess: PreprocessOptions,
+    // of the uploaded audio
+    pub codec: Codec,
+    // buffering between the mic and the upload
+    pub pipeline: PipelineOptions
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            input: AudioInput::Device(None),
+            verbosity: 0,
+            capture: None,
+            transport: Arc::new(box HyperTransport::new() as Box<HttpTransport + Send + Sync>),
+            cassette: None,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            context: None,
+            vad: Default::default(),
+            preprocess: PreprocessOptions::none(),
+            codec: Codec::Raw,
+            pipeline: PipelineOptions::new()
+        }
+    }
 }
 
-fn read_response(response: &mut Response) -> Result<Json,RequestError> {
+fn read_response(response: HttpResponse) -> Result<Json,RequestError> {
     let status = response.status;
     if status.class() != StatusClass::Informational && status.class() != StatusClass::Success {
         wit_log!(Error, "server responded with error: {}", status);
         return Err(RequestError::StatusError(status));
     }
-    match response.read_to_string() {
-        Ok(str) => {
-            let obj = json::from_str(str.as_slice());
-            obj.map_err(|e| {
-                wit_log!(Error, "could not parse response from server: {}", str);
-                RequestError::ParserError(e)
-            })
-        }
-        Err(e) => {
-            wit_log!(Error, "failed to read response body: {}", e);
-            Err(RequestError::InvalidResponseError)
-        }
-    }
+    let obj = json::from_str(response.body.as_slice());
+    obj.map_err(|e| {
+        wit_log!(Error, "could not parse response from server: {}", log::redact_text(response.body.as_slice()));
+        RequestError::ParserError(e)
+    })
 }
 
-fn set_common_headers(h: &mut Headers, token: String) {
-    h.set(Authorization(format!("Bearer {}", token)));
-    h.set(Accept(vec![Mime(TopLevel::Application, SubLevel::Ext("vnd.wit.20141124+json".to_string()), vec![])]));
+fn set_common_headers(h: &mut HttpHeaders, token: String) {
+    log::register_secret(token.as_slice());
+    let accept = Mime(TopLevel::Application, SubLevel::Ext("vnd.wit.20141124+json".to_string()), vec![]);
+    h.push(("Authorization".to_string(), format!("Bearer {}", token)));
+    h.push(("Accept".to_string(), format!("{}", accept)));
 }
 
-fn do_message_request(msg: String, token: String) -> Result<Json,RequestError> {
-    let encoded = url::utf8_percent_encode(msg.as_slice(), url::QUERY_ENCODE_SET);
-    let mut req = Request::get(Url::parse(format!("https://api.wit.ai/message?q={}", encoded).as_slice()).unwrap()).unwrap();
-    set_common_headers(req.headers_mut(), token);
-    let mut res = req.start().unwrap().send().unwrap();
-    read_response(&mut res)
+fn context_param(params: &mut Vec<(String, String)>, context: &Option<Json>) {
+    match *context {
+        Some(ref context) => params.push(("context".to_string(), json::encode(context))),
+        None => ()
+    }
 }
 
-fn do_speech_request(stream: &mut io::ChanReader, encoding:String, rate:u32, token: String) -> Result<Json,RequestError> {
-    let mut req = Request::post(Url::parse("https://api.wit.ai/speech").unwrap()).unwrap();
-    let mime = Mime(
-        TopLevel::Audio,
-        SubLevel::Ext("raw".to_string()),
-        vec![(Attr::Ext("encoding".to_string()), Value::Ext(encoding)),
-             (Attr::Ext("bits".to_string()), Value::Ext("16".to_string())),
-             (Attr::Ext("rate".to_string()), Value::Ext(format!("{}", rate))),
-             (Attr::Ext("endian".to_string()), Value::Ext("big".to_string()))]
-    );
-    {
-        let h = req.headers_mut();
-        h.set(ContentType(mime));
-        //h.set(TransferEncoding(vec![Encoding::Chunked]));
-        set_common_headers(h, token);
-    }
-    let mut streaming_req = req.start().unwrap();
-    match copy(stream, &mut streaming_req) {
-        Ok(..) => (),
-        Err(e) => wit_log!(Error, "failed to stream audio to server: {}", e)
+fn do_message_request(transport: &HttpTransport, base_url: &str, msg: String, context: &Option<Json>, token: String, metrics: &Recorder) -> Result<Json,RequestError> {
+    let mut params = vec![("q".to_string(), msg)];
+    context_param(&mut params, context);
+    let query = url::form_urlencoded::serialize_owned(params.as_slice());
+    let mut headers = Vec::new();
+    set_common_headers(&mut headers, token);
+    let res = try!(transport.get(format!("{}/message?{}", base_url, query).as_slice(), &headers));
+    metrics.response(res.received_at);
+    read_response(res)
+}
+
+fn do_speech_request(transport: &HttpTransport, base_url: &str, stream: &mut Reader, content_type: Mime, context: &Option<Json>, token: String, metrics: &Recorder) -> Result<Json,RequestError> {
+    let mut headers = Vec::new();
+    headers.push(("Content-Type".to_string(), format!("{}", content_type)));
+    set_common_headers(&mut headers, token);
+    let mut params = Vec::new();
+    context_param(&mut params, context);
+    let url = if params.is_empty() {
+        format!("{}/speech", base_url)
+    } else {
+        format!("{}/speech?{}", base_url, url::form_urlencoded::serialize_owned(params.as_slice()))
     };
-    match streaming_req.send() {
-        Ok(mut res) => read_response(&mut res),
-        Err(_) => Err(RequestError::ClientError)
-    }
+    let res = try!(transport.post(url.as_slice(), &headers, &mut UploadReader::new(stream, metrics)));
+    metrics.response(res.received_at);
+    read_response(res)
 }
 
-fn next_state(state: State, cmd: WitCommand, opts: Options) -> State {
+fn next_state(state: State, cmd: WitCommand, opts: &mut Options, events: &EventBus<WitEvent>, levels: &LevelBus) -> State {
     match cmd {
-        WitCommand::Text(token, text, result_tx) => {
-            let r = do_message_request(text, token);
+        WitCommand::Text(token, text, result_tx, metrics_tx) => {
+            let metrics = Recorder::new();
+            let r = do_message_request(&**opts.transport, opts.base_url.as_slice(), text, &opts.context, token, &metrics);
+            events.emit(WitEvent::QueryDone);
+            match metrics_tx {
+                Some(tx) => { let _ = tx.send_opt(metrics.finish()); }
+                None => ()
+            }
             result_tx.send(r);
             state
         }
-        WitCommand::Start(token, autoend_result_tx) => {
+        WitCommand::Start(token, autoend_result_tx, detect_end, metrics_tx) => {
             match state {
                 State::Ongoing(context) => State::Ongoing(context),
-                _ => {
-                    let mic_context_opt = mic::start(opts.input_device.clone(), autoend_result_tx.is_some());
+                s => {
+                    let capture_opts = opts.capture.clone();
+                    let wav_path = capture_opts.as_ref().and_then(|c| capture::new_session(c));
+                    let metrics = Recorder::new();
+                    let mic_context = match source::open(&opts.input).and_then(|audio| {
+                        let vad_opts = if detect_end { Some(opts.vad.clone()) } else { None };
+                        mic::start(audio, vad_opts, wav_path.clone(), events.clone(), levels.clone(), &opts.preprocess, &opts.codec, &opts.pipeline, metrics.clone())
+                    }) {
+                        Ok(mic_context) => mic_context,
+                        Err(e) => {
+                            // the metrics of the failed query, before its result
+                            match metrics_tx {
+                                Some(tx) => { let _ = tx.send_opt(metrics.finish()); }
+                                None => ()
+                            }
+                            match autoend_result_tx {
+                                Some(result_tx) => result_tx.send(Err(e)),
+                                None => ()
+                            };
+                            return s;
+                        }
+                    };
 
                     let (http_tx, http_rx) = channel();
                     let mic::MicContext {
                         mut reader,
                         sender: mic_tx,
-                        rate,
-                        encoding
-                    } = mic_context_opt.unwrap();
+                        content_type
+                    } = mic_context;
 
+                    let transport = opts.transport.clone();
+                    let base_url = opts.base_url.clone();
+                    let query_context = opts.context.clone();
+                    let query_events = events.clone();
                     spawn(proc() {
                         let reader_ref = &mut *reader;
-                        let foo = do_speech_request(reader_ref, encoding, rate, token);
-                        http_tx.send(foo);
+                        let foo = do_speech_request(&**transport, base_url.as_slice(), reader_ref, content_type, &query_context, token, &metrics);
+                        match (capture_opts, wav_path) {
+                            (Some(ref c), Some(ref path)) => capture::save_response(c, path, &foo),
+                            _ => ()
+                        }
+                        query_events.emit(WitEvent::QueryDone);
+                        let metrics = metrics.finish();
+                        wit_log!(Debug, "query metrics: {}", metrics);
+                        http_tx.send((foo, metrics));
                     });
 
                     State::Ongoing(Context {
                         http: http_rx,
                         mic: mic_tx,
-                        client: autoend_result_tx
+                        client: autoend_result_tx,
+                        metrics: metrics_tx
                     })
                 }
             }
         }
-        WitCommand::Stop(result_tx) => {
+        WitCommand::Stop(result_tx, metrics_tx) => {
             match state {
                 State::Ongoing(context) => {
-                    let Context { http: http_rx, mic: mic_tx, client: _ } = context;
+                    let Context { http: http_rx, mic: mic_tx, client: _, metrics: start_metrics_tx } = context;
 
                     mic::stop(&mic_tx);
-                    let foo = http_rx.recv();
+                    let (foo, metrics) = http_rx.recv();
+                    for tx in start_metrics_tx.iter().chain(metrics_tx.iter()) {
+                        let _ = tx.send_opt(metrics.clone());
+                    }
                     result_tx.send(foo);
 
                     State::Idle
                 },
                 s => {
-                    wit_log!(Warn, "trying to stop but no request started");
+                    if !source::is_available(&opts.input) {
+                        result_tx.send(Err(RequestError::AudioUnavailable));
+                    } else {
+                        wit_log!(Warn, "trying to stop but no request started");
+                    }
                     s
                 }
             }
         }
+        WitCommand::SetContext(context) => {
+            opts.context = context;
+            state
+        }
+        WitCommand::SetInput(input) => {
+            wit_log!(Info, "switching to input: {}", input.describe());
+            opts.input = input;
+            state
+        }
+        WitCommand::Subscribe(tx) => {
+            events.add(tx);
+            state
+        }
+        WitCommand::Levels(interval, tx) => {
+            levels.add(interval, tx);
+            state
+        }
+        WitCommand::Calibrate(duration, result_tx) => {
+            match state {
+                State::Ongoing(context) => {
+                    wit_log!(Warn, "can't calibrate during a recording session");
+                    result_tx.send(Err(RequestError::Busy));
+                    State::Ongoing(context)
+                }
+                s => {
+                    let r = source::open(&opts.input).and_then(|audio| mic::calibrate(audio, &opts.vad, duration));
+                    match r {
+                        Ok(ref calibration) => opts.vad.calibration = Some(calibration.clone()),
+                        Err(_) => ()
+                    }
+                    result_tx.send(r);
+                    s
+                }
+            }
+        }
+        WitCommand::SetCalibration(calibration) => {
+            opts.vad.calibration = calibration;
+            state
+        }
         WitCommand::Cleanup => {
             match state {
                 State::Ongoing(context) => {
-                    let Context { http: _, mic: mic_tx, client: _ } = context;
+                    let Context { http: _, mic: mic_tx, client: _, metrics: _ } = context;
                     mic::stop(&mic_tx)
                 },
                 _ => ()
             };
+            // the device is closed asynchronously by the mic, and keeps sox
+            // initialized until then
+            source::quit();
             State::Stopped
         }
     }
@@ -183,48 +326,114 @@ pub fn interpret_string(ctl: &WitHandle,
                         token: String,
                         text: String) -> Receiver<Result<Json,RequestError>> {
     let (result_tx, result_rx) = channel();
-    ctl.send(WitCommand::Text(token, text, result_tx));
+    ctl.send(WitCommand::Text(token, text, result_tx, None));
     return result_rx
 }
 
+pub fn interpret_string_with_metrics(ctl: &WitHandle, token: String, text: String)
+                                     -> (Receiver<Result<Json,RequestError>>, Receiver<QueryMetrics>) {
+    let (result_tx, result_rx) = channel();
+    let (metrics_tx, metrics_rx) = channel();
+    ctl.send(WitCommand::Text(token, text, result_tx, Some(metrics_tx)));
+    (result_rx, metrics_rx)
+}
+
 pub fn start_recording(ctl: &WitHandle, token: String) {
-    ctl.send(WitCommand::Start(token, None));
+    ctl.send(WitCommand::Start(token, None, false, None));
 }
 
 pub fn start_autoend_recording(ctl: &WitHandle, token: String) -> Receiver<Result<Json,RequestError>> {
     let (result_tx, result_rx) = channel();
-    ctl.send(WitCommand::Start(token, Some(result_tx)));
+    ctl.send(WitCommand::Start(token, Some(result_tx), true, None));
     result_rx
 }
 
+pub fn start_recording_to_end(ctl: &WitHandle, token: String) -> Receiver<Result<Json,RequestError>> {
+    let (result_tx, result_rx) = channel();
+    ctl.send(WitCommand::Start(token, Some(result_tx), false, None));
+    result_rx
+}
+
+pub fn start_autoend_recording_with_metrics(ctl: &WitHandle, token: String)
+                                            -> (Receiver<Result<Json,RequestError>>, Receiver<QueryMetrics>) {
+    let (result_tx, result_rx) = channel();
+    let (metrics_tx, metrics_rx) = channel();
+    ctl.send(WitCommand::Start(token, Some(result_tx), true, Some(metrics_tx)));
+    (result_rx, metrics_rx)
+}
+
 pub fn stop_recording(ctl: &WitHandle) -> Receiver<Result<Json,RequestError>> {
     let (result_tx, result_rx) = channel();
-    ctl.send(WitCommand::Stop(result_tx));
+    ctl.send(WitCommand::Stop(result_tx, None));
     result_rx
 }
 
+pub fn stop_recording_with_metrics(ctl: &WitHandle) -> (Receiver<Result<Json,RequestError>>, Receiver<QueryMetrics>) {
+    let (result_tx, result_rx) = channel();
+    let (metrics_tx, metrics_rx) = channel();
+    ctl.send(WitCommand::Stop(result_tx, Some(metrics_tx)));
+    (result_rx, metrics_rx)
+}
+
+pub fn set_context(ctl: &WitHandle, context: Option<Json>) {
+    ctl.send(WitCommand::SetContext(context));
+}
+
+pub fn set_input(ctl: &WitHandle, input: AudioInput) {
+    ctl.send(WitCommand::SetInput(input));
+}
+
+pub fn subscribe(ctl: &WitHandle) -> Receiver<WitEvent> {
+    let (event_tx, event_rx) = channel();
+    ctl.send(WitCommand::Subscribe(event_tx));
+    event_rx
+}
+
+pub fn levels(ctl: &WitHandle, interval: Duration) -> Receiver<Level> {
+    let (level_tx, level_rx) = channel();
+    ctl.send(WitCommand::Levels(interval, level_tx));
+    level_rx
+}
+
+pub fn calibrate(ctl: &WitHandle, duration: Duration) -> Receiver<Result<Calibration, RequestError>> {
+    let (result_tx, result_rx) = channel();
+    ctl.send(WitCommand::Calibrate(duration, result_tx));
+    result_rx
+}
+
+pub fn set_calibration(ctl: &WitHandle, calibration: Option<Calibration>) {
+    ctl.send(WitCommand::SetCalibration(calibration));
+}
+
 pub fn cleanup(ctl: &WitHandle) {
     ctl.send(WitCommand::Cleanup);
-    // TODO: have the mic call sox_quit()
 }
 
-pub fn init(opts: Options) -> WitHandle{
+pub fn init(mut opts: Options) -> WitHandle{
     log::set_verbosity(opts.verbosity);
 
-    mic::init();
+    let transport = match opts.cassette {
+        Some(ref cassette) => Some(cassette.wrap(opts.transport.clone())),
+        None => None
+    };
+    match transport {
+        Some(transport) => opts.transport = transport,
+        None => ()
+    }
+
+    source::init();
 
     let (cmd_tx, cmd_rx): (WitHandle, Receiver<WitCommand>) = channel();
 
     wit_log!(Debug, "init state machine");
 
     spawn(proc() {
+        let mut opts = opts;
+        let events = EventBus::new();
+        let levels = LevelBus::new();
         let mut ongoing: State = State::Idle;
         loop {
-            wit_log!(Info, "ready. state={}", match ongoing {
-                State::Ongoing(_) => "recording",
-                State::Idle => "idle",
-                State::Stopped => "stopped"
-            });
+            wit_log!(Info, "ready. state={}", ongoing.name());
 
             match ongoing {
                 State::Stopped => break,
@@ -237,9 +446,15 @@ pub fn init(opts: Options) -> WitHandle{
                         Some(client) => {
                             let http = context.http;
                             let mic = context.mic;
+                            let metrics_tx = context.metrics;
                             let cmd_opt = select! (
-                                cmd = cmd_rx.recv() => Some(cmd),
+                                cmd = cmd_rx.recv_opt() => Some(cmd.unwrap_or(WitCommand::Cleanup)),
                                 foo = http.recv() => {
+                                    let (foo, metrics) = foo;
+                                    match metrics_tx {
+                                        Some(ref tx) => { let _ = tx.send_opt(metrics); }
+                                        None => ()
+                                    }
                                     client.send(foo);
                                     None
                                 }
@@ -249,25 +464,200 @@ pub fn init(opts: Options) -> WitHandle{
                                     let context = Context {
                                         http: http,
                                         mic: mic,
-                                        client: Some(client.clone())
+                                        client: Some(client.clone()),
+                                        metrics: metrics_tx
                                     };
-                                    next_state(State::Ongoing(context), cmd, opts.clone())
+                                    next_state(State::Ongoing(context), cmd, &mut opts, &events, &levels)
                                 }
                                 None => State::Idle
                             }
                         },
                         None => {
-                            let cmd = cmd_rx.recv();
-                            next_state(State::Ongoing(context), cmd, opts.clone())
+                            let cmd = cmd_rx.recv_opt().unwrap_or(WitCommand::Cleanup);
+                            next_state(State::Ongoing(context), cmd, &mut opts, &events, &levels)
                         }
                     }
                 },
                 s => {
-                    let cmd = cmd_rx.recv();
-                    next_state(s, cmd, opts.clone())
+                    // once every handle is dropped, clean up as cleanup() would
+                    let cmd = cmd_rx.recv_opt().unwrap_or(WitCommand::Cleanup);
+                    next_state(s, cmd, &mut opts, &events, &levels)
                 }
             };
         }
     });
     return cmd_tx
 }
+
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use std::sync::Arc;
+    use hyper::status::StatusCode;
+    use serialize::json::{mod, Json};
+    use source::{AudioInput, AudioSource, Segment, SyntheticSource};
+    use transport::{HttpTransport, MockTransport, Method};
+    use events::{EventBus, LevelBus};
+    use log;
+    use super::{next_state, Options, State, WitCommand};
+
+    const RESPONSE: &'static str = r#"{"_text": "hello", "outcomes": []}"#;
+
+    fn options(mock: &MockTransport) -> Options {
+        Options {
+            // long enough to still be recording when stopped
+            input: AudioInput::Synthetic(vec![Segment::silence(600000)], 16000),
+            transport: Arc::new(box mock.clone() as Box<HttpTransport + Send + Sync>),
+            ..Default::default()
+        }
+    }
+
+    fn run(state: State, cmd: WitCommand, opts: &mut Options) -> State {
+        next_state(state, cmd, opts, &EventBus::new(), &LevelBus::new())
+    }
+
+    #[test]
+    fn voice_query_goes_from_idle_to_ongoing_and_back() {
+        // the token is registered as a secret
+        let _guard = log::TEST_LOCK.lock();
+        let mock = MockTransport::new();
+        mock.push_response(StatusCode::Ok, RESPONSE);
+        let mut opts = options(&mock);
+
+        let state = run(State::Idle, WitCommand::Start("token".to_string(), None, false, None), &mut opts);
+        assert_eq!(state.name(), "recording");
+
+        let (result_tx, result_rx) = channel();
+        let state = run(state, WitCommand::Stop(result_tx, None), &mut opts);
+        assert_eq!(state.name(), "idle");
+        assert_eq!(result_rx.recv().unwrap(), json::from_str(RESPONSE).unwrap());
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Method::Post);
+        assert!(requests[0].url.as_slice().ends_with("/speech"));
+        assert!(requests[0].header("Content-Type").unwrap().starts_with("audio/raw"));
+    }
+
+    #[test]
+    fn voice_query_uploads_the_encoded_input() {
+        let _guard = log::TEST_LOCK.lock();
+        let mock = MockTransport::new();
+        mock.push_response(StatusCode::Ok, RESPONSE);
+        let segments = vec![Segment::tone(440.0, 0.5, 200), Segment::noise(0.3, 100)];
+        let mut opts = Options {
+            input: AudioInput::Synthetic(segments.clone(), 16000),
+            ..options(&mock)
+        };
+
+        // the upload ends with the input
+        let (result_tx, _) = channel();
+        let state = run(State::Idle, WitCommand::Start("token".to_string(), Some(result_tx), false, None), &mut opts);
+        match state {
+            State::Ongoing(context) => {
+                let (result, _) = context.http.recv();
+                assert_eq!(result.unwrap(), json::from_str(RESPONSE).unwrap());
+            }
+            other => panic!("unexpected state {}", other.name())
+        }
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        let content_type = requests[0].header("Content-Type").unwrap();
+        let params: Vec<&str> = content_type.split(';').map(|p| p.trim()).collect();
+        let endian = if cfg!(target_endian = "little") { "endian=little" } else { "endian=big" };
+        assert_eq!(params[0], "audio/raw");
+        for param in ["encoding=signed-integer", "bits=16", "rate=16000", endian].iter() {
+            assert!(params.contains(param), "{} missing from {}", param, content_type);
+        }
+
+        // the samples of the input, in the byte order declared
+        let mut source = SyntheticSource::new(segments, 16000);
+        let mut samples = Vec::from_elem(4800, 0i16);
+        assert_eq!(source.read(samples.as_mut_slice()).unwrap(), 4800);
+        let mut expected = Vec::new();
+        for &sample in samples.iter() {
+            let (lo, hi) = (sample as u16 as u8, (sample as u16 >> 8) as u8);
+            if cfg!(target_endian = "little") { expected.push_all(&[lo, hi]) } else { expected.push_all(&[hi, lo]) }
+        }
+        assert_eq!(requests[0].body, expected);
+    }
+
+    #[test]
+    fn failed_queries_have_metrics() {
+        let _guard = log::TEST_LOCK.lock();
+        let mock = MockTransport::new();
+        let mut opts = Options {
+            input: AudioInput::File(Path::new("/nonexistent/utterance.wav")),
+            ..options(&mock)
+        };
+        let (result_tx, result_rx) = channel();
+        let (metrics_tx, metrics_rx) = channel();
+        let state = run(State::Idle, WitCommand::Start("token".to_string(), Some(result_tx), true, Some(metrics_tx)), &mut opts);
+        assert_eq!(state.name(), "idle");
+        assert!(result_rx.recv().is_err());
+        let metrics = metrics_rx.recv();
+        assert_eq!(metrics.capture_start, None);
+        assert_eq!(metrics.bytes_uploaded, 0);
+        assert!(mock.requests().is_empty());
+    }
+
+    #[test]
+    fn stop_while_idle_does_nothing() {
+        let mock = MockTransport::new();
+        let mut opts = options(&mock);
+        let (result_tx, result_rx) = channel();
+        let state = run(State::Idle, WitCommand::Stop(result_tx, None), &mut opts);
+        assert_eq!(state.name(), "idle");
+        // no response is coming
+        assert!(result_rx.recv_opt().is_err());
+        assert!(mock.requests().is_empty());
+    }
+
+    #[test]
+    fn cleanup_while_ongoing_stops() {
+        let _guard = log::TEST_LOCK.lock();
+        let mock = MockTransport::new();
+        mock.push_response(StatusCode::Ok, RESPONSE);
+        let mut opts = options(&mock);
+        let state = run(State::Idle, WitCommand::Start("token".to_string(), None, false, None), &mut opts);
+        assert_eq!(state.name(), "recording");
+        let state = run(state, WitCommand::Cleanup, &mut opts);
+        assert_eq!(state.name(), "stopped");
+    }
+
+    #[test]
+    fn context_reaches_the_query_string() {
+        let _guard = log::TEST_LOCK.lock();
+        let mock = MockTransport::new();
+        mock.push_response(StatusCode::Ok, RESPONSE);
+        let mut opts = options(&mock);
+        let context: Json = json::from_str(r#"{"state": "asking name"}"#).unwrap();
+        let state = run(State::Idle, WitCommand::SetContext(Some(context.clone())), &mut opts);
+        let (result_tx, result_rx) = channel();
+        let state = run(state, WitCommand::Text("token".to_string(), "hello world".to_string(), result_tx, None), &mut opts);
+        assert_eq!(state.name(), "idle");
+        assert!(result_rx.recv().is_ok());
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Method::Get);
+        assert_eq!(requests[0].query_param("q"), Some("hello world".to_string()));
+        let sent = requests[0].query_param("context").and_then(|c| json::from_str(c.as_slice()).ok());
+        assert_eq!(sent, Some(context));
+        assert_eq!(requests[0].header("Authorization"), Some("Bearer token"));
+    }
+
+    #[test]
+    fn input_changes_between_sessions() {
+        let _guard = log::TEST_LOCK.lock();
+        let mock = MockTransport::new();
+        let mut opts = options(&mock);
+        let state = run(State::Idle, WitCommand::Start("token".to_string(), None, false, None), &mut opts);
+        let input = AudioInput::File(Path::new("utterance.wav"));
+        let expected = input.describe();
+        let state = run(state, WitCommand::SetInput(input), &mut opts);
+        assert_eq!(state.name(), "recording");
+        assert_eq!(opts.input.describe(), expected);
+    }
+}
@@ -1,24 +1,157 @@
-use serialize::json::Json;
+use std::os;
+use std::default::Default;
+use std::io::File;
+use std::time::Duration;
+use std::sync::Arc;
+use serialize::json::{mod, Json};
 use client;
+use transport::{mod, HttpTransport, HyperTransport};
 use log;
-use log::LogLevel::Info;
+use log::LogLevel::{Warn, Info};
 
 pub use client::RequestError;
 pub use client::RequestError::ChannelClosedError;
 pub use client::WitHandle;
+pub use client::{Options, DEFAULT_BASE_URL};
+pub use capture::CaptureOptions;
+pub use cassette::{CassetteOptions, CassetteMode};
+pub use events::{WitEvent, Level};
+pub use mic::{VadOptions, VadAlgorithm, Calibration};
+pub use preprocess::PreprocessOptions;
+pub use codec::Codec;
+pub use pipe::{PipelineOptions, Backpressure};
+pub use metrics::QueryMetrics;
+pub use source::{AudioInput, AudioSource, Segment, Signal, list_devices};
+pub use log::{LogSink, LogRecord, LogLevel, StderrSink, FacadeSink};
+
+/**
+ * Replace the destination of the library's log records.
+ * By default, records are written to stderr. Use FacadeSink to forward
+ * them to the `log` crate. Verbosity filtering still applies.
+ */
+pub fn set_log_sink(sink: Box<LogSink + Send + Sync>) {
+    log::set_sink(sink)
+}
+
+/**
+ * Enable or disable the privacy mode. In privacy mode, transcripts and
+ * entity values are replaced with a hash in the logs, at any verbosity.
+ * Access tokens are always masked.
+ */
+pub fn set_privacy_mode(enabled: bool) {
+    log::set_privacy(enabled)
+}
 
 /**
  * Initialize the resources for audio recording and Wit API requests.
  * This function returns a handle used by all the other functions
  * in the library.
  * The resources can be released using the cleanup method.
+ *
+ * The other options are read from the environment (see options_from_env).
  */
 pub fn init(device_opt: Option<String>, verbosity: uint) -> WitHandle {
-    let handle = client::init(client::Options{
-        input_device: device_opt.clone(),
-        verbosity: verbosity
+    init_with_options(Options {
+        input: AudioInput::Device(device_opt),
+        verbosity: verbosity,
+        ..options_from_env()
+    })
+}
+
+/**
+ * The value named by an environment variable. An unknown name is logged,
+ * and the default used instead.
+ */
+fn env_choice<T>(var: &str, default: T, parse: |&str| -> Option<T>) -> T {
+    match os::getenv(var) {
+        Some(name) => match parse(name.as_slice()) {
+            Some(value) => value,
+            None => {
+                wit_log!(Warn, "unknown {}: {}, using the default", var, name);
+                default
+            }
+        },
+        None => default
+    }
+}
+
+/**
+ * Default options, adjusted by environment variables:
+ *
+ * If WIT_CAPTURE_DIR is set, the audio of each voice query is saved there
+ * with the response (see CaptureOptions). WIT_CAPTURE_MAX_BYTES caps the size
+ * of that directory.
+ *
+ * If WIT_CASSETTE is set, API interactions are recorded to or replayed from that
+ * file, depending on WIT_CASSETTE_MODE (see CassetteOptions).
+ * WIT_API_URL replaces the default base url of the API, e.g. to use a local stub server.
+ *
+ * WIT_VAD selects the end-of-speech detection algorithm: energy (default), spectral or gmm.
+ * WIT_VAD_CALIBRATION is the path of a calibration saved as JSON (see calibrate).
+ *
+ * WIT_PREPROCESS enables preprocessing stages, e.g. "highpass,ns,agc" (see PreprocessOptions).
+ * WIT_CODEC selects the encoding of the uploaded audio: raw (default), ulaw, flac or
+ * opus (with the opus feature).
+ *
+ * WIT_READ_SIZE is the number of samples read from the audio input at once, and
+ * WIT_BUFFER_BYTES the size of the buffer between the input and the upload.
+ * WIT_BACKPRESSURE is what happens when that buffer is full: block (default),
+ * drop-oldest or fail (see Backpressure).
+ *
+ * WIT_CHUNK_SIZE is the size in bytes of the chunks the audio is uploaded in
+ * (see HyperTransport).
+ */
+pub fn options_from_env() -> Options {
+    let capture = os::getenv("WIT_CAPTURE_DIR").map(|dir| {
+        CaptureOptions {
+            dir: Path::new(dir),
+            max_bytes: os::getenv("WIT_CAPTURE_MAX_BYTES").and_then(|max| from_str(max.as_slice()))
+        }
     });
-    wit_log!(Info, "initialized with device: {}", device_opt.unwrap_or("default".to_string()));
+    let vad = VadOptions {
+        algorithm: env_choice("WIT_VAD", VadAlgorithm::Energy, |name| VadAlgorithm::from_name(name)),
+        calibration: os::getenv("WIT_VAD_CALIBRATION").and_then(|path| load_calibration(&Path::new(path))),
+        ..Default::default()
+    };
+    let defaults = PipelineOptions::new();
+    let pipeline = PipelineOptions {
+        read_size: os::getenv("WIT_READ_SIZE").and_then(|n| from_str(n.as_slice())).unwrap_or(defaults.read_size),
+        buffer_bytes: os::getenv("WIT_BUFFER_BYTES").and_then(|n| from_str(n.as_slice())).unwrap_or(defaults.buffer_bytes),
+        backpressure: env_choice("WIT_BACKPRESSURE", defaults.backpressure, |name| Backpressure::from_name(name))
+    };
+    let transport = HyperTransport::with_chunk_size(
+        os::getenv("WIT_CHUNK_SIZE").and_then(|n| from_str(n.as_slice())).unwrap_or(transport::DEFAULT_CHUNK_SIZE));
+    Options {
+        capture: capture,
+        transport: Arc::new(box transport as Box<HttpTransport + Send + Sync>),
+        vad: vad,
+        cassette: CassetteOptions::from_env(),
+        preprocess: os::getenv("WIT_PREPROCESS").map(|names| PreprocessOptions::from_names(names.as_slice()))
+            .unwrap_or(PreprocessOptions::none()),
+        codec: env_choice("WIT_CODEC", Codec::Raw, |name| Codec::from_name(name)),
+        pipeline: pipeline,
+        base_url: os::getenv("WIT_API_URL").unwrap_or(DEFAULT_BASE_URL.to_string()),
+        ..Default::default()
+    }
+}
+
+fn load_calibration(path: &Path) -> Option<Calibration> {
+    let calibration = File::open(path).read_to_string().ok()
+        .and_then(|s| json::from_str(s.as_slice()).ok())
+        .and_then(|json| Calibration::from_json(&json));
+    if calibration.is_none() {
+        wit_log!(Warn, "failed to read a calibration from {}", path.display());
+    }
+    calibration
+}
+
+/**
+ * Same as init, with all the options available.
+ */
+pub fn init_with_options(opts: Options) -> WitHandle {
+    let input = opts.input.describe();
+    let handle = client::init(opts);
+    wit_log!(Info, "initialized with input: {}", input);
     handle
 }
 
@@ -31,6 +164,61 @@ pub fn cleanup(handle: &WitHandle) {
     client::cleanup(handle)
 }
 
+/**
+ * Set the context sent with the following queries, text and voice alike
+ * (None to stop sending one). A query already sent keeps its context.
+ */
+pub fn set_context(handle: &WitHandle, context: Option<Json>) {
+    client::set_context(handle, context)
+}
+
+/**
+ * Set the audio input of the following voice queries, e.g. to send several
+ * files through the same handle. A recording session already started keeps its input.
+ */
+pub fn set_input(handle: &WitHandle, input: AudioInput) {
+    client::set_input(handle, input)
+}
+
+/**
+ * Receive the events of the following queries: recording start and stop,
+ * speech detection and responses. Events are dropped once the Receiver is gone.
+ */
+pub fn subscribe(handle: &WitHandle) -> Receiver<WitEvent> {
+    client::subscribe(handle)
+}
+
+/**
+ * Receive the level of the input during the following recording sessions,
+ * every interval, e.g. for a VU meter. Each receiver gets its own interval,
+ * from the current session on. Levels are dropped once the Receiver is gone.
+ */
+pub fn levels(handle: &WitHandle, interval: Duration) -> Receiver<Level> {
+    client::levels(handle, interval)
+}
+
+/**
+ * Measure the background noise for the given duration, during which the user
+ * should stay silent. This function is blocking.
+ *
+ * The following voice queries start from this measurement instead of learning
+ * the noise during their first frames, so the user can speak right away. The
+ * calibration can be saved with to_json, and restored with set_calibration or
+ * WIT_VAD_CALIBRATION. It fails with Busy during a recording session.
+ */
+pub fn calibrate(handle: &WitHandle, duration: Duration) -> Result<Calibration, RequestError> {
+    client::calibrate(handle, duration).recv_opt().unwrap_or(Err(ChannelClosedError))
+}
+
+/**
+ * Replace the calibration used by the following voice queries (None to learn
+ * the noise in each session again). It only applies to the end-of-speech
+ * detection algorithm that measured it.
+ */
+pub fn set_calibration(handle: &WitHandle, calibration: Option<Calibration>) {
+    client::set_calibration(handle, calibration)
+}
+
 /**
  * Send a text query to the Wit instance identified by the access_token.
  * This function is blocking, and returns the response from the Wit instance.
@@ -73,6 +261,23 @@ pub fn voice_query_auto_async(handle: &WitHandle, access_token: String) -> Recei
     client::start_autoend_recording(handle, access_token)
 }
 
+/**
+ * Send the whole audio input as a voice query, without end-of-speech detection:
+ * the query ends with the input (a file, a pipe...), or when voice_query_stop
+ * is called. This function is blocking, and returns the response from the Wit instance.
+ */
+pub fn voice_query_to_end(handle: &WitHandle, access_token: String) -> Result<Json, RequestError> {
+    voice_query_to_end_async(handle, access_token).recv_opt().unwrap_or(Err(ChannelClosedError))
+}
+
+/**
+ * Same as voice_query_to_end. This function is non-blocking. It returns a
+ * Receiver that can be used to get the response from Wit.
+ */
+pub fn voice_query_to_end_async(handle: &WitHandle, access_token: String) -> Receiver<Result<Json, RequestError>> {
+    client::start_recording_to_end(handle, access_token)
+}
+
 /**
  * Send a voice query to the Wit instance identified by the access_token.
  * This function returns immediately. The recording session stops only when either
@@ -101,3 +306,35 @@ pub fn voice_query_stop(handle: &WitHandle) -> Result<Json, RequestError> {
 pub fn voice_query_stop_async(handle: &WitHandle) -> Receiver<Result<Json, RequestError>> {
     client::stop_recording(handle)
 }
+
+fn with_metrics(receivers: (Receiver<Result<Json, RequestError>>, Receiver<QueryMetrics>)) -> (Result<Json, RequestError>, Option<QueryMetrics>) {
+    let (result_rx, metrics_rx) = receivers;
+    let result = result_rx.recv_opt().unwrap_or(Err(ChannelClosedError));
+    // sent before the response, whether the query failed or not
+    (result, metrics_rx.recv_opt().ok())
+}
+
+/**
+ * Same as text_query, with the timing of the query. The metrics come with
+ * errors too, to tell how far the query went; they are None only when the
+ * query never started (e.g. after cleanup, or when stopping while idle).
+ */
+pub fn text_query_with_metrics(handle: &WitHandle, text: String, access_token: String) -> (Result<Json, RequestError>, Option<QueryMetrics>) {
+    with_metrics(client::interpret_string_with_metrics(handle, access_token, text))
+}
+
+/**
+ * Same as voice_query_auto, with the timing of the query and the size of
+ * the upload (see text_query_with_metrics).
+ */
+pub fn voice_query_auto_with_metrics(handle: &WitHandle, access_token: String) -> (Result<Json, RequestError>, Option<QueryMetrics>) {
+    with_metrics(client::start_autoend_recording_with_metrics(handle, access_token))
+}
+
+/**
+ * Same as voice_query_stop, with the timing of the query and the size of
+ * the upload (see text_query_with_metrics).
+ */
+pub fn voice_query_stop_with_metrics(handle: &WitHandle) -> (Result<Json, RequestError>, Option<QueryMetrics>) {
+    with_metrics(client::stop_recording_with_metrics(handle))
+}
@@ -0,0 +1,651 @@
+use std::num::Int;
+use std::mem;
+use std::raw;
+use mime::{Mime, TopLevel, SubLevel, Attr, Value};
+#[cfg(feature = "opus")]
+use opus;
+
+/// Samples per FLAC frame: about 70ms at 16kHz, to keep the upload streaming
+const FLAC_BLOCK_SIZE: uint = 1152;
+const FLAC_MAX_ORDER: uint = 4;
+const FLAC_MAX_RICE_PARAM: uint = 14;
+
+/**
+ * Encoding of the audio uploaded with voice queries.
+ */
+#[deriving(Clone, Show, PartialEq)]
+pub enum Codec {
+    /// 16-bit PCM (256 kbit/s at 16kHz)
+    Raw,
+    /// 8-bit G.711 mu-law (128 kbit/s at 16kHz)
+    Ulaw,
+    /// Lossless FLAC, usually about half the size of Raw. audio/flac is not
+    /// among the content types documented by the Wit API.
+    Flac,
+    /// Opus in Ogg at 24 kbit/s, lossy (libopus, with the "opus" feature)
+    Opus
+}
+
+impl Codec {
+    /**
+     * "raw", "ulaw", "flac" or "opus".
+     */
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "raw" => Some(Codec::Raw),
+            "ulaw" => Some(Codec::Ulaw),
+            "flac" => Some(Codec::Flac),
+            "opus" => Some(Codec::Opus),
+            _ => None
+        }
+    }
+
+    /**
+     * Whether audio at that rate can be encoded: Opus needs the "opus"
+     * feature, and one of its rates.
+     */
+    pub fn is_available(&self, rate: u32) -> bool {
+        match *self {
+            Codec::Opus => cfg!(feature = "opus") && opus_supports_rate(rate),
+            _ => true
+        }
+    }
+
+    /**
+     * Whether the stream survives dropped bytes (see Backpressure::DropOldest).
+     */
+    pub fn can_drop(&self) -> bool {
+        match *self {
+            Codec::Raw | Codec::Ulaw => true,
+            Codec::Flac | Codec::Opus => false
+        }
+    }
+
+    pub fn encoder(&self, rate: u32) -> Box<Encoder + Send> {
+        match *self {
+            Codec::Raw => box RawEncoder::new(rate) as Box<Encoder + Send>,
+            Codec::Ulaw => box UlawEncoder::new(rate) as Box<Encoder + Send>,
+            Codec::Flac => box FlacEncoder::new(rate) as Box<Encoder + Send>,
+            Codec::Opus => opus_encoder(rate)
+        }
+    }
+}
+
+pub fn opus_supports_rate(rate: u32) -> bool {
+    match rate {
+        8000 | 12000 | 16000 | 24000 | 48000 => true,
+        _ => false
+    }
+}
+
+#[cfg(feature = "opus")]
+fn opus_encoder(rate: u32) -> Box<Encoder + Send> {
+    box opus::OpusEncoder::new(rate) as Box<Encoder + Send>
+}
+
+#[cfg(not(feature = "opus"))]
+fn opus_encoder(_: u32) -> Box<Encoder + Send> {
+    panic!("libwit was built without Opus support")
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum Endianness {
+    Little,
+    Big
+}
+
+impl Endianness {
+    pub fn native() -> Endianness {
+        if cfg!(target_endian = "little") { Endianness::Little } else { Endianness::Big }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Endianness::Little => "little",
+            Endianness::Big => "big"
+        }
+    }
+}
+
+/**
+ * Layout of raw audio, as described to the Wit API. The encoding uses the
+ * names of the API ("signed-integer", "ulaw"...).
+ */
+#[deriving(Clone, Show, PartialEq)]
+pub struct AudioFormat {
+    pub encoding: &'static str,
+    pub bits: uint,
+    pub rate: u32,
+    pub endian: Endianness
+}
+
+impl AudioFormat {
+    pub fn content_type(&self) -> Mime {
+        Mime(
+            TopLevel::Audio,
+            SubLevel::Ext("raw".to_string()),
+            vec![(Attr::Ext("encoding".to_string()), Value::Ext(self.encoding.to_string())),
+                 (Attr::Ext("bits".to_string()), Value::Ext(format!("{}", self.bits))),
+                 (Attr::Ext("rate".to_string()), Value::Ext(format!("{}", self.rate))),
+                 (Attr::Ext("endian".to_string()), Value::Ext(self.endian.name().to_string()))]
+        )
+    }
+}
+
+/**
+ * Turns the recorded samples into the bytes of the upload, as they come.
+ * Each encoder describes the bytes it writes. The bytes are borrowed, from the
+ * samples or from a buffer of the encoder, until the next call.
+ */
+pub trait Encoder {
+    fn content_type(&self) -> Mime;
+
+    fn encode<'a>(&'a mut self, samples: &'a [i16]) -> &'a [u8];
+
+    /**
+     * The bytes of the samples still buffered, at the end of the session.
+     */
+    fn finish(&mut self) -> &[u8];
+}
+
+/**
+ * 16-bit PCM, in the byte order of the host: the samples are sent as they are.
+ */
+pub struct RawEncoder {
+    format: AudioFormat
+}
+
+impl RawEncoder {
+    pub fn new(rate: u32) -> RawEncoder {
+        RawEncoder {
+            format: AudioFormat {
+                encoding: "signed-integer",
+                bits: 16,
+                rate: rate,
+                endian: Endianness::native()
+            }
+        }
+    }
+}
+
+impl Encoder for RawEncoder {
+    fn content_type(&self) -> Mime {
+        self.format.content_type()
+    }
+
+    fn encode<'a>(&'a mut self, samples: &'a [i16]) -> &'a [u8] {
+        // a view of the samples, no copy
+        unsafe {
+            mem::transmute(raw::Slice { data: samples.as_ptr() as *const u8, len: samples.len() * 2 })
+        }
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        &[]
+    }
+}
+
+pub struct UlawEncoder {
+    format: AudioFormat,
+    out: Vec<u8>
+}
+
+impl UlawEncoder {
+    pub fn new(rate: u32) -> UlawEncoder {
+        UlawEncoder {
+            format: AudioFormat {
+                encoding: "ulaw",
+                bits: 8,
+                rate: rate,
+                // meaningless for 8-bit samples, but expected by the API
+                endian: Endianness::native()
+            },
+            out: Vec::new()
+        }
+    }
+}
+
+fn ulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let mut s = sample as i32;
+    let sign = if s < 0 {
+        s = -s;
+        0x80
+    } else {
+        0
+    };
+    if s > CLIP {
+        s = CLIP;
+    }
+    s += BIAS;
+    let mut exponent = 7i32;
+    let mut mask = 0x4000i32;
+    while exponent > 0 && s & mask == 0 {
+        exponent -= 1;
+        mask >>= 1;
+    }
+    let mantissa = (s >> (exponent as uint + 3)) & 0x0F;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+impl Encoder for UlawEncoder {
+    fn content_type(&self) -> Mime {
+        self.format.content_type()
+    }
+
+    fn encode<'a>(&'a mut self, samples: &'a [i16]) -> &'a [u8] {
+        self.out.clear();
+        self.out.extend(samples.iter().map(|&s| ulaw(s)));
+        self.out.as_slice()
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        &[]
+    }
+}
+
+/**
+ * MSB-first bit writer.
+ */
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: uint
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            acc: 0,
+            bits: 0
+        }
+    }
+
+    fn write(&mut self, value: u64, bits: uint) {
+        for i in range(0, bits).rev() {
+            self.acc = (self.acc << 1) | ((value >> i) & 1);
+            self.bits += 1;
+            if self.bits == 8 {
+                self.bytes.push(self.acc as u8);
+                self.acc = 0;
+                self.bits = 0;
+            }
+        }
+    }
+
+    fn write_unary(&mut self, zeros: u64) {
+        for _ in range(0, zeros) {
+            self.write(0, 1);
+        }
+        self.write(1, 1);
+    }
+
+    /**
+     * Pad with zeros to a byte boundary.
+     */
+    fn align(&mut self) {
+        while self.bits != 0 {
+            self.write(0, 1);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &b in bytes.iter() {
+        crc ^= b;
+        for _ in range(0u, 8) {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in bytes.iter() {
+        crc ^= (b as u16) << 8;
+        for _ in range(0u, 8) {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/**
+ * Frame numbers are coded like UTF-8 characters, up to 36 bits.
+ */
+fn write_utf8(w: &mut BitWriter, n: u64) {
+    if n < 0x80 {
+        w.write(n, 8);
+        return;
+    }
+    let mut len = 2u;
+    while len < 7 && n >= 1 << (5 * len + 1) {
+        len += 1;
+    }
+    // length prefix, then the top bits
+    let prefix = (0xFFu64 << (8 - len)) & 0xFF;
+    w.write(prefix | (n >> (6 * (len - 1))), 8);
+    for i in range(0, len - 1).rev() {
+        w.write(0x80 | ((n >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn residuals(samples: &[i32], order: uint) -> Vec<i32> {
+    range(order, samples.len()).map(|i| {
+        let s = |k: uint| samples[i - k];
+        match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - 2 * s(1) + s(2),
+            3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+            _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4)
+        }
+    }).collect()
+}
+
+fn fold(r: i32) -> u64 {
+    ((r << 1) ^ (r >> 31)) as u32 as u64
+}
+
+/**
+ * Best Rice parameter for the residuals, and the resulting size in bits.
+ */
+fn rice_param(residuals: &[i32]) -> (uint, u64) {
+    let (mut best_k, mut best_bits) = (0u, Int::max_value());
+    for k in range(0, FLAC_MAX_RICE_PARAM + 1) {
+        let bits = residuals.iter().fold(0u64, |acc, &r| acc + (fold(r) >> k) + 1 + k as u64);
+        if bits < best_bits {
+            best_k = k;
+            best_bits = bits;
+        }
+    }
+    (best_k, best_bits)
+}
+
+/**
+ * Streaming FLAC encoder for 16-bit mono audio, with fixed predictors and a
+ * single Rice partition per frame. The stream header doesn't give the total
+ * number of samples, nor a checksum, which FLAC allows for streams.
+ */
+pub struct FlacEncoder {
+    rate: u32,
+    pending: Vec<i32>,
+    out: Vec<u8>,
+    frame_number: u64,
+    header_sent: bool
+}
+
+impl FlacEncoder {
+    pub fn new(rate: u32) -> FlacEncoder {
+        FlacEncoder {
+            rate: rate,
+            pending: Vec::with_capacity(FLAC_BLOCK_SIZE),
+            out: Vec::new(),
+            frame_number: 0,
+            header_sent: false
+        }
+    }
+
+    fn stream_header(&self) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        for &b in b"fLaC".iter() {
+            w.write(b as u64, 8);
+        }
+        // last metadata block, STREAMINFO, 34 bytes
+        w.write(1, 1);
+        w.write(0, 7);
+        w.write(34, 24);
+        w.write(FLAC_BLOCK_SIZE as u64, 16);
+        w.write(FLAC_BLOCK_SIZE as u64, 16);
+        // unknown frame sizes
+        w.write(0, 24);
+        w.write(0, 24);
+        w.write(self.rate as u64, 20);
+        // mono, 16 bits
+        w.write(0, 3);
+        w.write(15, 5);
+        // unknown total samples and MD5
+        w.write(0, 36);
+        for _ in range(0u, 16) {
+            w.write(0, 8);
+        }
+        w.bytes
+    }
+
+    fn start(&mut self) {
+        if !self.header_sent {
+            self.header_sent = true;
+            let header = self.stream_header();
+            self.out.push_all(header.as_slice());
+        }
+    }
+
+    // encodes the pending samples into out
+    fn flush(&mut self) {
+        let mut block = mem::replace(&mut self.pending, Vec::new());
+        let frame = self.frame(block.as_slice());
+        self.out.push_all(frame.as_slice());
+        block.clear();
+        self.pending = block;
+    }
+
+    fn frame(&mut self, samples: &[i32]) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        // sync code, fixed block size
+        w.write(0xFFF8, 16);
+        // block size in 16 bits at the end of the header, sample rate from STREAMINFO
+        w.write(0b0111, 4);
+        w.write(0b0000, 4);
+        // mono, 16 bits
+        w.write(0b0000, 4);
+        w.write(0b100, 3);
+        w.write(0, 1);
+        write_utf8(&mut w, self.frame_number);
+        w.write((samples.len() - 1) as u64, 16);
+        let crc = crc8(w.bytes.as_slice());
+        w.write(crc as u64, 8);
+
+        // the fixed predictor with the smallest residuals
+        let max_order = if samples.len() > FLAC_MAX_ORDER { FLAC_MAX_ORDER } else { samples.len() - 1 };
+        let mut best: Option<(uint, Vec<i32>, uint, u64)> = None;
+        for order in range(0, max_order + 1) {
+            let res = residuals(samples, order);
+            let (k, bits) = rice_param(res.as_slice());
+            let better = match best {
+                Some((_, _, _, best_bits)) => bits < best_bits,
+                None => true
+            };
+            if better {
+                best = Some((order, res, k, bits));
+            }
+        }
+        let (order, res, k, bits) = best.unwrap();
+        if bits >= 16 * samples.len() as u64 {
+            // verbatim subframe
+            w.write(0b00000010, 8);
+            for &s in samples.iter() {
+                w.write(s as u16 as u64, 16);
+            }
+        } else {
+            // fixed subframe
+            w.write((0b01000 | order as u64) << 1, 8);
+            for &s in samples.slice_to(order).iter() {
+                w.write(s as u16 as u64, 16);
+            }
+            // Rice coding with 4-bit parameters, one partition
+            w.write(0, 2);
+            w.write(0, 4);
+            w.write(k as u64, 4);
+            for &r in res.iter() {
+                let u = fold(r);
+                w.write_unary(u >> k);
+                w.write(u & ((1 << k) - 1), k);
+            }
+        }
+        w.align();
+        let crc = crc16(w.bytes.as_slice());
+        w.write(crc as u64, 16);
+        self.frame_number += 1;
+        w.bytes
+    }
+}
+
+impl Encoder for FlacEncoder {
+    // FLAC describes itself
+    fn content_type(&self) -> Mime {
+        Mime(TopLevel::Audio, SubLevel::Ext("flac".to_string()), vec![])
+    }
+
+    fn encode<'a>(&'a mut self, samples: &'a [i16]) -> &'a [u8] {
+        self.out.clear();
+        self.start();
+        for &sample in samples.iter() {
+            self.pending.push(sample as i32);
+            if self.pending.len() == FLAC_BLOCK_SIZE {
+                self.flush();
+            }
+        }
+        self.out.as_slice()
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        self.out.clear();
+        self.start();
+        if !self.pending.is_empty() {
+            self.flush();
+        }
+        self.out.as_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use mime::Mime;
+    use test::Bencher;
+    use source::{mod, AudioInput, AudioSource, Segment};
+    use super::{Encoder, RawEncoder, UlawEncoder};
+
+    const RATE: u32 = 16000;
+
+    // a tone, then noise, then silence, read through the synthetic input
+    fn signal() -> Vec<i16> {
+        let input = AudioInput::Synthetic(vec![Segment::tone(440.0, 0.5, 100),
+                                               Segment::noise(0.9, 100),
+                                               Segment::silence(50)], RATE);
+        let mut source = source::open(&input).unwrap();
+        let mut samples = Vec::new();
+        let mut buf = Vec::from_elem(320, 0i16);
+        loop {
+            match source.read(buf.as_mut_slice()) {
+                Ok(n) => samples.push_all(buf.slice_to(n)),
+                Err(_) => break
+            }
+        }
+        assert_eq!(samples.len(), 250 * RATE as uint / 1000);
+        samples
+    }
+
+    // encodes in chunks of various sizes, as the recording loop does
+    fn encode_all(encoder: &mut Encoder, samples: &[i16]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut start = 0;
+        for &len in [1u, 160, 333, 1024].iter().cycle() {
+            if start >= samples.len() {
+                break;
+            }
+            let end = cmp::min(start + len, samples.len());
+            bytes.push_all(encoder.encode(samples.slice(start, end)));
+            start = end;
+        }
+        bytes.push_all(encoder.finish());
+        bytes
+    }
+
+    fn param(mime: &Mime, name: &str) -> Option<String> {
+        let Mime(_, _, ref params) = *mime;
+        params.iter()
+            .find(|&&(ref attr, _)| format!("{}", attr).as_slice() == name)
+            .map(|&(_, ref value)| format!("{}", value))
+    }
+
+    fn native_endian() -> &'static str {
+        if cfg!(target_endian = "little") { "little" } else { "big" }
+    }
+
+    fn ulaw_decode(u: u8) -> i16 {
+        let u = !u;
+        let exponent = ((u >> 4) & 0x07) as uint;
+        let mantissa = (u & 0x0F) as i32;
+        let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+        (if u & 0x80 != 0 { -magnitude } else { magnitude }) as i16
+    }
+
+    #[test]
+    fn raw_is_declared_as_native_16_bit() {
+        let encoder = RawEncoder::new(RATE);
+        let mime = encoder.content_type();
+        assert!(format!("{}", mime).as_slice().starts_with("audio/raw"));
+        assert_eq!(param(&mime, "encoding"), Some("signed-integer".to_string()));
+        assert_eq!(param(&mime, "bits"), Some("16".to_string()));
+        assert_eq!(param(&mime, "rate"), Some("16000".to_string()));
+        assert_eq!(param(&mime, "endian"), Some(native_endian().to_string()));
+    }
+
+    #[test]
+    fn raw_round_trips() {
+        let samples = signal();
+        let mut encoder = RawEncoder::new(RATE);
+        let bytes = encode_all(&mut encoder, samples.as_slice());
+        assert_eq!(bytes.len(), samples.len() * 2);
+        // decoded in the byte order the content type declares
+        let little = param(&encoder.content_type(), "endian") == Some("little".to_string());
+        let decoded: Vec<i16> = bytes.chunks(2).map(|b| {
+            let (lo, hi) = if little { (b[0], b[1]) } else { (b[1], b[0]) };
+            (lo as u16 | (hi as u16 << 8)) as i16
+        }).collect();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn ulaw_is_declared_as_8_bit() {
+        let encoder = UlawEncoder::new(RATE);
+        let mime = encoder.content_type();
+        assert!(format!("{}", mime).as_slice().starts_with("audio/raw"));
+        assert_eq!(param(&mime, "encoding"), Some("ulaw".to_string()));
+        assert_eq!(param(&mime, "bits"), Some("8".to_string()));
+        assert_eq!(param(&mime, "rate"), Some("16000".to_string()));
+        assert_eq!(param(&mime, "endian"), Some(native_endian().to_string()));
+    }
+
+    #[test]
+    fn ulaw_round_trips_within_its_step() {
+        let samples = signal();
+        let mut encoder = UlawEncoder::new(RATE);
+        let bytes = encode_all(&mut encoder, samples.as_slice());
+        assert_eq!(bytes.len(), samples.len());
+        for (&sample, &byte) in samples.iter().zip(bytes.iter()) {
+            let (sample, decoded) = (sample as i32, ulaw_decode(byte) as i32);
+            let error = if decoded > sample { decoded - sample } else { sample - decoded };
+            let magnitude = if sample < 0 { -sample } else { sample };
+            // the quantization step doubles with each segment: about 1/16 of the magnitude
+            assert!(error <= magnitude / 16 + 8,
+                    "{} decoded as {}", sample, decoded);
+        }
+        // silence stays silent
+        assert_eq!(ulaw_decode(*bytes.last().unwrap()), 0);
+    }
+
+    // one read of the recording loop
+    #[bench]
+    fn bench_raw_encoder(b: &mut Bencher) {
+        let samples = signal();
+        let mut encoder = RawEncoder::new(RATE);
+        b.bytes = 1024 * 2;
+        b.iter(|| encoder.encode(samples.slice_to(1024)).len());
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/**
+ * What happens during a query, as seen by subscribers (see cmd::subscribe).
+ */
+#[deriving(Clone, Show, PartialEq)]
+pub enum WitEvent {
+    /// A recording session started
+    RecordingStarted,
+    /// The VAD detected the beginning of speech
+    SpeechStarted,
+    /// The VAD detected the end of speech
+    SpeechEnded,
+    /// The recording session stopped, the rest of the audio is being sent
+    RecordingStopped,
+    /// The response to a query was received
+    QueryDone
+}
+
+/**
+ * Level of the audio input over a metering interval (see cmd::levels).
+ * rms and peak are in dBFS (-100 for silence); clips is the number of samples
+ * clipped by the device during the interval, when it reports them.
+ */
+#[deriving(Clone, Show, PartialEq)]
+pub struct Level {
+    pub rms: f64,
+    pub peak: f64,
+    pub clips: u64
+}
+
+/**
+ * Delivers events to every subscriber. Clones share the same subscribers.
+ */
+#[deriving(Clone)]
+pub struct EventBus<T> {
+    subscribers: Arc<Mutex<Vec<Sender<T>>>>
+}
+
+impl<T: Clone + Send> EventBus<T> {
+    pub fn new() -> EventBus<T> {
+        EventBus {
+            subscribers: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    pub fn add(&self, tx: Sender<T>) {
+        self.subscribers.lock().push(tx);
+    }
+
+    /**
+     * Send the event to the subscribers, and forget those that went away.
+     */
+    pub fn emit(&self, event: T) {
+        self.subscribers.lock().retain(|tx| tx.send_opt(event.clone()).is_ok());
+    }
+}
+
+/**
+ * Delivers input levels to every level subscriber, each over its own
+ * interval. Clones share the same subscribers.
+ */
+#[deriving(Clone)]
+pub struct LevelBus {
+    subscribers: Arc<Mutex<Vec<(Duration, Sender<Level>)>>>
+}
+
+impl LevelBus {
+    pub fn new() -> LevelBus {
+        LevelBus {
+            subscribers: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    pub fn add(&self, interval: Duration, tx: Sender<Level>) {
+        self.subscribers.lock().push((interval, tx));
+    }
+
+    /**
+     * The intervals the subscribers asked for, without duplicates.
+     */
+    pub fn intervals(&self) -> Vec<Duration> {
+        let mut intervals: Vec<Duration> = Vec::new();
+        for &(interval, _) in self.subscribers.lock().iter() {
+            if !intervals.contains(&interval) {
+                intervals.push(interval);
+            }
+        }
+        intervals
+    }
+
+    /**
+     * Send the level to the subscribers of that interval, and forget those that went away.
+     */
+    pub fn emit(&self, interval: Duration, level: Level) {
+        self.subscribers.lock().retain(|&(i, ref tx)| i != interval || tx.send_opt(level.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{LevelBus, Level};
+
+    fn level(rms: f64) -> Level {
+        Level { rms: rms, peak: rms, clips: 0 }
+    }
+
+    #[test]
+    fn levels_go_to_the_subscribers_of_their_interval() {
+        let bus = LevelBus::new();
+        let (fast_tx, fast_rx) = channel();
+        let (slow_tx, slow_rx) = channel();
+        bus.add(Duration::milliseconds(50), fast_tx);
+        bus.add(Duration::milliseconds(500), slow_tx);
+        assert_eq!(bus.intervals(), vec![Duration::milliseconds(50), Duration::milliseconds(500)]);
+        bus.emit(Duration::milliseconds(50), level(-20.0));
+        assert_eq!(fast_rx.try_recv(), Ok(level(-20.0)));
+        assert!(slow_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn subscribers_that_went_away_are_forgotten() {
+        let bus = LevelBus::new();
+        let (tx, rx) = channel();
+        bus.add(Duration::milliseconds(50), tx);
+        drop(rx);
+        bus.emit(Duration::milliseconds(50), level(-20.0));
+        assert!(bus.intervals().is_empty());
+    }
+}
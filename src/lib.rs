@@ -1,18 +1,42 @@
-#![feature(macro_rules)]
+#![feature(macro_rules, unsafe_destructor)]
 
 extern crate hyper;
 extern crate mime;
 extern crate serialize;
 extern crate libc;
 extern crate url;
+extern crate time;
+extern crate dsp;
+extern crate "log" as rlog;
+#[cfg(feature = "audio")]
 extern crate "sox-sys" as ffi;
+#[cfg(target_arch = "arm")]
 extern crate "fake-sys" as fakeffi;
+#[cfg(feature = "vad")]
 extern crate "vad" as vad;
+#[cfg(feature = "c-api")]
 extern crate rustrt;
+#[cfg(test)]
+extern crate test;
 
 mod log;
 mod client;
+pub mod events;
+pub mod transport;
+pub mod cassette;
+#[cfg(feature = "audio")]
+mod sox;
 mod mic;
+mod preprocess;
+mod codec;
+mod ogg;
+#[cfg(feature = "opus")]
+mod opus;
+mod pipe;
+mod metrics;
+mod capture;
+pub mod source;
 
 pub mod cmd;
+#[cfg(feature = "c-api")]
 pub mod c;
@@ -1,13 +1,41 @@
 #![macro_escape]
 
-use std::sync::atomic::{AtomicUint, Relaxed, INIT_ATOMIC_UINT};
+use std::io;
+use std::mem;
+use std::hash;
+use std::collections::BTreeMap;
+use std::sync::{Arc, StaticMutex, MUTEX_INIT};
+use std::sync::atomic::{AtomicUint, AtomicBool, Relaxed, INIT_ATOMIC_UINT, INIT_ATOMIC_BOOL};
+use serialize::json::Json;
+use time::{mod, Timespec};
+use rlog;
+
+pub use rlog::LogLocation;
 
 // Not sure there is a much better way. We want to be able to use
 // logging from anywhere, without having to carry the verbosity level
 // everywhere in the code
 static mut VERBOSITY: AtomicUint = INIT_ATOMIC_UINT;
 
-#[deriving(PartialEq, PartialOrd)]
+// When set, transcripts and entity values never make it to the logs
+static mut PRIVACY: AtomicBool = INIT_ATOMIC_BOOL;
+
+// Same thing for the sink. A null pointer means "use the default sink".
+// The lock is only held to take a reference to the sink: records are written
+// after it is released, so that a sink can call libwit functions that log.
+static SINK_LOCK: StaticMutex = MUTEX_INIT;
+static mut SINK: *const SharedSink = 0 as *const SharedSink;
+
+type SharedSink = Arc<Box<LogSink + Send + Sync>>;
+
+// Access tokens seen so far, most recent last. They are masked from every
+// record, whatever the verbosity or the privacy mode. Protected by SINK_LOCK.
+static mut SECRETS: *mut Vec<String> = 0 as *mut Vec<String>;
+
+/// Secrets remembered at most: the oldest ones are forgotten first
+const MAX_SECRETS: uint = 32;
+
+#[deriving(PartialEq, PartialOrd, Show, Copy)]
 pub enum LogLevel {
     Error,
     Warn,
@@ -25,17 +53,404 @@ impl LogLevel {
             _ => level <= LogLevel::Debug
         }
     }
+
+    /**
+     * Numeric value of the level, using the same scale as the verbosity
+     * (1 = Error ... 4 = Debug) and as the `log` crate.
+     */
+    pub fn as_uint(self) -> uint {
+        match self {
+            LogLevel::Error => 1,
+            LogLevel::Warn => 2,
+            LogLevel::Info => 3,
+            LogLevel::Debug => 4
+        }
+    }
+}
+
+/**
+ * A single log message, as handed to the current sink.
+ */
+pub struct LogRecord<'a> {
+    pub level: LogLevel,
+    pub location: &'static LogLocation,
+    pub timestamp: Timespec,
+    pub message: &'a str
+}
+
+impl<'a> LogRecord<'a> {
+    pub fn module(&self) -> &'static str {
+        self.location.module_path
+    }
+}
+
+/**
+ * Destination for the library's log records. Records are filtered by
+ * verbosity before reaching the sink.
+ * A sink may call libwit functions, but the records they log come back to it.
+ */
+pub trait LogSink {
+    fn log(&self, record: &LogRecord);
+}
+
+/**
+ * Default sink: writes one line per record to stderr.
+ */
+pub struct StderrSink;
+
+impl LogSink for StderrSink {
+    fn log(&self, record: &LogRecord) {
+        let mut stderr = io::stderr();
+        let _ = writeln!(&mut stderr, "[wit] {}.{:03} {} {}: {}",
+            record.timestamp.sec,
+            record.timestamp.nsec / 1000000,
+            record.level,
+            record.module(),
+            record.message);
+    }
+}
+
+/**
+ * Forwards records to the `log` crate facade, so that they go through
+ * whatever logger the application has set up.
+ */
+pub struct FacadeSink;
+
+impl LogSink for FacadeSink {
+    fn log(&self, record: &LogRecord) {
+        let level = record.level.as_uint() as u32;
+        if rlog::mod_enabled(level, record.module()) {
+            rlog::log(level, record.location, &format_args!("{}", record.message));
+        }
+    }
 }
 
 pub fn set_verbosity(verbosity: uint) {
     unsafe { VERBOSITY.store(verbosity, Relaxed) }
 }
 
+pub fn set_privacy(enabled: bool) {
+    unsafe { PRIVACY.store(enabled, Relaxed) }
+}
+
+pub fn privacy_enabled() -> bool {
+    unsafe { PRIVACY.load(Relaxed) }
+}
+
+/**
+ * Mask an access token, keeping only its last 4 characters
+ * (or nothing at all for short tokens).
+ */
+pub fn mask_token(token: &str) -> String {
+    let len = token.char_len();
+    if len <= 8 {
+        "****".to_string()
+    } else {
+        format!("****{}", token.slice_chars(len - 4, len))
+    }
+}
+
+/**
+ * Remember a secret (typically an access token) so that it is masked
+ * from any log record that would contain it. Only the MAX_SECRETS most
+ * recently used secrets are remembered.
+ */
+pub fn register_secret(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    let _guard = SINK_LOCK.lock();
+    unsafe {
+        if SECRETS.is_null() {
+            SECRETS = mem::transmute(box Vec::<String>::new());
+        }
+        let secrets = &mut *SECRETS;
+        match secrets.iter().position(|s| s.as_slice() == secret) {
+            Some(i) => {
+                let known = secrets.remove(i).unwrap();
+                secrets.push(known);
+            }
+            None => {
+                if secrets.len() == MAX_SECRETS {
+                    secrets.remove(0);
+                }
+                secrets.push(secret.to_string());
+            }
+        }
+    }
+}
+
+// Must be called with SINK_LOCK held
+unsafe fn mask_secrets(message: String) -> String {
+    if SECRETS.is_null() {
+        return message;
+    }
+    let mut masked = message;
+    for secret in (*SECRETS).iter() {
+        if masked.contains(secret.as_slice()) {
+            masked = masked.replace(secret.as_slice(), mask_token(secret.as_slice()).as_slice());
+        }
+    }
+    masked
+}
+
+fn redaction_marker(text: &str) -> String {
+    format!("<redacted:{:016x}>", hash::hash(&text))
+}
+
+/**
+ * In privacy mode, replace user-provided text with a stable hash, so that
+ * identical utterances can still be correlated. Otherwise, return the text as is.
+ */
+pub fn redact_text(text: &str) -> String {
+    if privacy_enabled() {
+        redaction_marker(text)
+    } else {
+        text.to_string()
+    }
+}
+
+/**
+ * In privacy mode, return a copy of a Wit response with transcripts (`_text`)
+ * and all entity values redacted. Otherwise, return a plain copy.
+ */
+pub fn redact_json(json: &Json) -> Json {
+    if privacy_enabled() {
+        redact_json_value(json, false)
+    } else {
+        json.clone()
+    }
+}
+
+fn redact_json_value(json: &Json, in_entities: bool) -> Json {
+    match *json {
+        Json::Object(ref obj) => {
+            let mut redacted = BTreeMap::new();
+            for (key, value) in obj.iter() {
+                let v = match key.as_slice() {
+                    "_text" | "msg_body" => redact_json_leaf(value),
+                    "entities" => redact_json_value(value, true),
+                    _ => redact_json_value(value, in_entities)
+                };
+                redacted.insert(key.clone(), v);
+            }
+            Json::Object(redacted)
+        }
+        Json::Array(ref values) => {
+            Json::Array(values.iter().map(|v| redact_json_value(v, in_entities)).collect())
+        }
+        Json::Null | Json::Boolean(_) => json.clone(),
+        _ if in_entities => redact_json_leaf(json),
+        _ => json.clone()
+    }
+}
+
+fn redact_json_leaf(json: &Json) -> Json {
+    let marker = match json.as_string() {
+        Some(text) => redaction_marker(text),
+        None => redaction_marker(json.to_string().as_slice())
+    };
+    Json::String(marker)
+}
+
+pub fn set_sink(sink: Box<LogSink + Send + Sync>) {
+    let new_sink: *const SharedSink = unsafe { mem::transmute(box Arc::new(sink)) };
+    let old_sink = {
+        let _guard = SINK_LOCK.lock();
+        unsafe { mem::replace(&mut SINK, new_sink) }
+    };
+    if !old_sink.is_null() {
+        // records being written keep their own reference to the old sink
+        let _: Box<SharedSink> = unsafe { mem::transmute(old_sink) };
+    }
+}
+
+pub fn dispatch(level: LogLevel, location: &'static LogLocation, message: String) {
+    let (message, sink) = {
+        let _guard = SINK_LOCK.lock();
+        let message = unsafe { mask_secrets(message) };
+        let sink = unsafe {
+            if SINK.is_null() { None } else { Some((*SINK).clone()) }
+        };
+        (message, sink)
+    };
+    let record = LogRecord {
+        level: level,
+        location: location,
+        timestamp: time::get_time(),
+        message: message.as_slice()
+    };
+    match sink {
+        Some(sink) => sink.log(&record),
+        None => StderrSink.log(&record)
+    }
+}
+
 macro_rules! wit_log(
     ($level: expr, $($arg:expr),+) => ({
-        if log::LogLevel::should_show($level) {
-            print!("[wit] ");
-            println!($($arg),+);
+        static LOC: log::LogLocation = log::LogLocation {
+            module_path: module_path!(),
+            file: file!(),
+            line: line!()
+        };
+        let level = $level;
+        if log::LogLevel::should_show(level) {
+            log::dispatch(level, &LOC, format!($($arg),+));
         }
     });
 )
+
+// The log settings are global: the tests changing them, in any module
+// (registering a secret included), run one at a time.
+#[cfg(test)]
+pub static TEST_LOCK: StaticMutex = MUTEX_INIT;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use serialize::json;
+    use log;
+    use log::LogLevel::{Error, Warn, Info, Debug};
+    use super::{LogSink, LogRecord, StderrSink, MAX_SECRETS, SINK_LOCK};
+    use super::{register_secret, mask_secrets, mask_token, redact_text, redact_json};
+    use super::{set_sink, set_verbosity, set_privacy, TEST_LOCK};
+
+    struct CaptureSink {
+        messages: Arc<Mutex<Vec<String>>>
+    }
+
+    impl LogSink for CaptureSink {
+        fn log(&self, record: &LogRecord) {
+            self.messages.lock().push(record.message.to_string());
+        }
+    }
+
+    fn capture() -> Arc<Mutex<Vec<String>>> {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        set_sink(box CaptureSink { messages: messages.clone() });
+        messages
+    }
+
+    fn masked(message: &str) -> String {
+        let _guard = SINK_LOCK.lock();
+        unsafe { mask_secrets(message.to_string()) }
+    }
+
+    #[test]
+    fn short_tokens_are_fully_masked() {
+        assert_eq!(mask_token("abcd").as_slice(), "****");
+        assert_eq!(mask_token("ABCDEFGHIJKLMNOP").as_slice(), "****MNOP");
+    }
+
+    #[test]
+    fn registered_secrets_are_masked() {
+        let _guard = TEST_LOCK.lock();
+        register_secret("SECRETTOKEN0001");
+        let message = masked("Authorization: Bearer SECRETTOKEN0001");
+        assert!(!message.as_slice().contains("SECRETTOKEN0001"));
+        assert!(message.as_slice().contains("****0001"));
+    }
+
+    #[test]
+    fn secrets_are_capped() {
+        let _guard = TEST_LOCK.lock();
+        let first = "FIRSTSECRETTOKEN";
+        register_secret(first);
+        for i in range(0, MAX_SECRETS) {
+            register_secret(format!("OTHERSECRET{:05}", i).as_slice());
+        }
+        // the oldest one was forgotten, the most recent ones are still masked
+        assert!(masked(first).as_slice().contains(first));
+        let last = format!("OTHERSECRET{:05}", MAX_SECRETS - 1);
+        assert!(!masked(last.as_slice()).as_slice().contains(last.as_slice()));
+        let _lock = SINK_LOCK.lock();
+        assert_eq!(unsafe { (*super::SECRETS).len() }, MAX_SECRETS);
+    }
+
+    #[test]
+    fn tokens_never_reach_the_sink() {
+        let _guard = TEST_LOCK.lock();
+        let messages = capture();
+        let token = "LEAKYACCESSTOKEN42";
+        register_secret(token);
+        for verbosity in range(1u, 5) {
+            set_verbosity(verbosity);
+            wit_log!(Error, "token {}", token);
+            wit_log!(Warn, "token {}", token);
+            wit_log!(Info, "token {}", token);
+            wit_log!(Debug, "Authorization: Bearer {}", token);
+        }
+        set_verbosity(0);
+        set_sink(box StderrSink);
+        // other tests may log meanwhile
+        let messages: Vec<String> = messages.lock().iter()
+            .filter(|m| m.as_slice().starts_with("token") || m.as_slice().starts_with("Authorization"))
+            .map(|m| m.clone())
+            .collect();
+        // 1 + 2 + 3 + 4 records
+        assert_eq!(messages.len(), 10);
+        assert!(messages.iter().all(|m| !m.as_slice().contains(token)));
+    }
+
+    #[test]
+    fn a_sink_can_log_through_libwit() {
+        struct ReentrantSink {
+            messages: Arc<Mutex<Vec<String>>>
+        }
+
+        impl LogSink for ReentrantSink {
+            fn log(&self, record: &LogRecord) {
+                if record.message.starts_with("outer") {
+                    register_secret("REENTRANTSECRET1");
+                    wit_log!(Error, "inner");
+                }
+                self.messages.lock().push(record.message.to_string());
+            }
+        }
+
+        let _guard = TEST_LOCK.lock();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        set_sink(box ReentrantSink { messages: messages.clone() });
+        set_verbosity(1);
+        wit_log!(Error, "outer");
+        set_verbosity(0);
+        set_sink(box StderrSink);
+        let messages: Vec<String> = messages.lock().iter()
+            .filter(|m| m.as_slice() == "inner" || m.as_slice() == "outer")
+            .map(|m| m.clone())
+            .collect();
+        assert_eq!(messages, vec!["inner".to_string(), "outer".to_string()]);
+    }
+
+    #[test]
+    fn privacy_mode_redacts_text() {
+        let _guard = TEST_LOCK.lock();
+        set_privacy(true);
+        let redacted = redact_text("turn on the lights");
+        let again = redact_text("turn on the lights");
+        set_privacy(false);
+        assert!(!redacted.as_slice().contains("lights"));
+        assert!(redacted.as_slice().starts_with("<redacted:"));
+        // identical utterances can still be correlated
+        assert_eq!(redacted, again);
+        assert_eq!(redact_text("turn on the lights").as_slice(), "turn on the lights");
+    }
+
+    #[test]
+    fn privacy_mode_redacts_transcripts_and_entities() {
+        let _guard = TEST_LOCK.lock();
+        let response = json::from_str(r#"{"msg_id": "42", "_text": "call alice at noon",
+            "outcomes": [{"intent": "call", "confidence": 0.9,
+                          "entities": {"contact": [{"value": "alice"}], "datetime": [{"value": {"hour": 12}}]}}]}"#).unwrap();
+        set_privacy(true);
+        let redacted = json::encode(&redact_json(&response));
+        set_privacy(false);
+        assert!(!redacted.as_slice().contains("alice"));
+        assert!(!redacted.as_slice().contains("noon"));
+        assert!(!redacted.as_slice().contains("\"hour\":12"));
+        // the rest is kept
+        assert!(redacted.as_slice().contains("\"intent\":\"call\""));
+        assert!(redacted.as_slice().contains("\"msg_id\":\"42\""));
+        assert_eq!(redact_json(&response), response);
+    }
+}
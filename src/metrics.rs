@@ -0,0 +1,266 @@
+use std::io::{mod, IoResult};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time;
+
+/**
+ * Timing of a query, each step from the start of the query (the command being
+ * processed). The steps that didn't happen are None: no capture for text
+ * queries, no speech detected, or no response. For voice queries stopped by
+ * hand, the end of speech is when the query was stopped.
+ */
+#[deriving(Clone, Show)]
+pub struct QueryMetrics {
+    pub capture_start: Option<Duration>,
+    pub first_speech: Option<Duration>,
+    pub end_of_speech: Option<Duration>,
+    pub upload_done: Option<Duration>,
+    pub first_byte: Option<Duration>,
+    pub total: Duration,
+    // of the recorded audio
+    pub audio_duration: Duration,
+    // after encoding
+    pub bytes_uploaded: u64
+}
+
+struct Marks {
+    capture_start: Option<u64>,
+    first_speech: Option<u64>,
+    end_of_speech: Option<u64>,
+    upload_done: Option<u64>,
+    first_byte: Option<u64>,
+    rate: u32,
+    samples: u64,
+    bytes: u64
+}
+
+/**
+ * Collects the metrics of one query, from the threads taking part in it.
+ * Times are precise_time_ns values.
+ */
+#[deriving(Clone)]
+pub struct Recorder {
+    start: u64,
+    marks: Arc<Mutex<Marks>>
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder {
+            start: time::precise_time_ns(),
+            marks: Arc::new(Mutex::new(Marks {
+                capture_start: None,
+                first_speech: None,
+                end_of_speech: None,
+                upload_done: None,
+                first_byte: None,
+                rate: 0,
+                samples: 0,
+                bytes: 0
+            }))
+        }
+    }
+
+    pub fn capture_started(&self, rate: u32) {
+        let mut marks = self.marks.lock();
+        marks.capture_start = Some(time::precise_time_ns());
+        marks.rate = rate;
+    }
+
+    pub fn speech_started(&self) {
+        let mut marks = self.marks.lock();
+        if marks.first_speech.is_none() {
+            marks.first_speech = Some(time::precise_time_ns());
+        }
+    }
+
+    pub fn speech_ended(&self) {
+        let mut marks = self.marks.lock();
+        if marks.end_of_speech.is_none() {
+            marks.end_of_speech = Some(time::precise_time_ns());
+        }
+    }
+
+    pub fn recorded(&self, samples: uint) {
+        self.marks.lock().samples += samples as u64;
+    }
+
+    pub fn uploaded(&self, bytes: uint) {
+        self.marks.lock().bytes += bytes as u64;
+    }
+
+    pub fn upload_done(&self) {
+        self.marks.lock().upload_done = Some(time::precise_time_ns());
+    }
+
+    /**
+     * When the first byte of the response arrived: the time given by the
+     * transport, if it knows it, else now.
+     */
+    pub fn response(&self, received_at: Option<u64>) {
+        self.marks.lock().first_byte = Some(received_at.unwrap_or_else(time::precise_time_ns));
+    }
+
+    pub fn finish(&self) -> QueryMetrics {
+        let now = time::precise_time_ns();
+        let marks = self.marks.lock();
+        let since_start = |t: Option<u64>| t.map(|t| Duration::microseconds(((t - self.start) / 1000) as i64));
+        QueryMetrics {
+            capture_start: since_start(marks.capture_start),
+            first_speech: since_start(marks.first_speech),
+            end_of_speech: since_start(marks.end_of_speech),
+            upload_done: since_start(marks.upload_done),
+            first_byte: since_start(marks.first_byte),
+            total: Duration::microseconds(((now - self.start) / 1000) as i64),
+            audio_duration: if marks.rate > 0 {
+                Duration::microseconds((marks.samples * 1000000 / marks.rate as u64) as i64)
+            } else {
+                Duration::zero()
+            },
+            bytes_uploaded: marks.bytes
+        }
+    }
+}
+
+/**
+ * Reader counting the bytes of the upload as they go, so that a failed
+ * upload still tells how far it went, and marking its end.
+ */
+pub struct UploadReader<'a> {
+    inner: &'a mut (Reader + 'a),
+    metrics: &'a Recorder
+}
+
+impl<'a> UploadReader<'a> {
+    pub fn new(inner: &'a mut (Reader + 'a), metrics: &'a Recorder) -> UploadReader<'a> {
+        UploadReader {
+            inner: inner,
+            metrics: metrics
+        }
+    }
+}
+
+impl<'a> Reader for UploadReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.metrics.uploaded(n);
+                Ok(n)
+            }
+            Err(e) => {
+                if e.kind == io::EndOfFile {
+                    self.metrics.upload_done();
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{mod, IoResult, IoError, MemReader};
+    use std::io::timer;
+    use std::time::Duration;
+    use super::{Recorder, UploadReader};
+
+    /**
+     * The bytes of the MemReader, then a broken pipe instead of the end.
+     */
+    struct FailingReader {
+        inner: MemReader
+    }
+
+    impl Reader for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            match self.inner.read(buf) {
+                Err(ref e) if e.kind == io::EndOfFile => Err(IoError {
+                    kind: io::BrokenPipe,
+                    desc: "audio buffer overflow",
+                    detail: None
+                }),
+                r => r
+            }
+        }
+    }
+
+    #[test]
+    fn audio_duration_needs_the_rate() {
+        let metrics = Recorder::new();
+        metrics.recorded(8000);
+        let finished = metrics.finish();
+        assert_eq!(finished.audio_duration, Duration::zero());
+        assert_eq!(finished.capture_start, None);
+
+        let metrics = Recorder::new();
+        metrics.capture_started(16000);
+        metrics.recorded(8000);
+        metrics.recorded(4000);
+        let finished = metrics.finish();
+        assert_eq!(finished.audio_duration, Duration::milliseconds(750));
+        assert!(finished.capture_start.is_some());
+    }
+
+    #[test]
+    fn upload_is_counted_as_it_goes() {
+        let metrics = Recorder::new();
+        let mut body = MemReader::new(Vec::from_elem(300, 0u8));
+        assert_eq!(UploadReader::new(&mut body, &metrics).read_to_end().unwrap().len(), 300);
+        let finished = metrics.finish();
+        assert_eq!(finished.bytes_uploaded, 300);
+        assert!(finished.upload_done.is_some());
+    }
+
+    #[test]
+    fn failed_upload_keeps_its_byte_count() {
+        let metrics = Recorder::new();
+        let mut body = FailingReader { inner: MemReader::new(Vec::from_elem(300, 0u8)) };
+        {
+            let mut reader = UploadReader::new(&mut body, &metrics);
+            let mut buf = [0u8, ..128];
+            let mut read = 0;
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(n) => read += n,
+                    Err(e) => {
+                        assert_eq!(e.kind, io::BrokenPipe);
+                        break;
+                    }
+                }
+            }
+            assert_eq!(read, 300);
+        }
+        let finished = metrics.finish();
+        assert_eq!(finished.bytes_uploaded, 300);
+        assert_eq!(finished.upload_done, None);
+    }
+
+    #[test]
+    fn marks_follow_the_query() {
+        let metrics = Recorder::new();
+        let step = || timer::sleep(Duration::milliseconds(2));
+        step();
+        metrics.capture_started(16000);
+        step();
+        metrics.speech_started();
+        step();
+        // only the first detection counts
+        let first_speech = metrics.finish().first_speech;
+        metrics.speech_started();
+        metrics.speech_ended();
+        step();
+        metrics.speech_ended();
+        metrics.upload_done();
+        step();
+        metrics.response(None);
+        step();
+        let finished = metrics.finish();
+        assert_eq!(finished.first_speech, first_speech);
+        let marks = [finished.capture_start, finished.first_speech, finished.end_of_speech,
+                     finished.upload_done, finished.first_byte, Some(finished.total)];
+        for pair in marks.windows(2) {
+            assert!(pair[0].unwrap() < pair[1].unwrap(), "{} before {}", pair[0], pair[1]);
+        }
+        assert!(finished.capture_start.unwrap() > Duration::zero());
+    }
+}
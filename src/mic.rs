@@ -1,80 +1,418 @@
-use std::ptr::null;
 use std::io;
-use std::c_str::CString;
-use libc::{c_void, size_t};
+use std::cmp;
 use std::comm::{Empty, Disconnected};
-use std::vec::Vec;
-use std::num::Int;
+use std::default::Default;
+use std::num::Float;
+use std::time::Duration;
+use std::collections::BTreeMap;
+use serialize::json::{Json, ToJson};
 use log;
-use log::LogLevel::{Error, Debug, Info};
-use ffi::{mod, SoxEncodingT, SoxErrorT, SoxBool, SoxFormatT};
+use log::LogLevel::{Error, Warn, Debug, Info};
+#[cfg(feature = "vad")]
 use vad;
-
-const BUF_SIZE: uint = 100;
+use capture::WavWriter;
+use client::RequestError;
+use source::AudioSource;
+use preprocess::{Preprocessor, PreprocessOptions};
+use codec::Codec;
+use metrics::Recorder;
+use pipe;
+use pipe::{PipeReader, PipeError, PipelineOptions, Backpressure};
+use mime::Mime;
+use events::{EventBus, LevelBus, WitEvent, Level};
+use dsp::dbfs;
 
 pub struct MicContext {
-    pub reader: Box<io::ChanReader>,
+    pub reader: Box<PipeReader>,
     pub sender: Sender<bool>,
-    pub rate: u32,
-    pub encoding: String
+    // of the encoded audio
+    pub content_type: Mime
 }
 
-fn is_big_endian() -> bool {
-    1u16.to_be() == 1u16
+#[deriving(Clone, Show, PartialEq)]
+pub enum VadAlgorithm {
+    /// Energy versus noise floor (the original detector)
+    Energy,
+    /// Energy, zero-crossing rate and spectral flatness, for music or TV in the background
+    Spectral,
+    /// Gaussian mixtures of speech and noise over sub-band energies, like WebRTC's VAD
+    Gmm
 }
 
-fn cleanup_recording_session(input_ptr: *const SoxFormatT, vad_state: *const c_void) {
-    wit_log!(Info, "stopping mic");
-    unsafe {ffi::sox_close(input_ptr)};
-    if !vad_state.is_null() {
-        unsafe {vad::wvs_clean(vad_state)};
+impl VadAlgorithm {
+    pub fn from_name(name: &str) -> Option<VadAlgorithm> {
+        match name {
+            "energy" => Some(VadAlgorithm::Energy),
+            "spectral" => Some(VadAlgorithm::Spectral),
+            "gmm" => Some(VadAlgorithm::Gmm),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            VadAlgorithm::Energy => "energy",
+            VadAlgorithm::Spectral => "spectral",
+            VadAlgorithm::Gmm => "gmm"
+        }
     }
 }
 
-pub fn start(input_device: Option<String>, vad_enabled: bool) -> Option<MicContext> {
+/**
+ * The background noise measured by calibrate, used by the detector instead of
+ * learning it during the first init_frames of each session. It only applies
+ * to the algorithm that measured it.
+ */
+#[deriving(Clone, Show, PartialEq)]
+pub struct Calibration {
+    pub algorithm: VadAlgorithm,
+    pub noise_floor: f64,
+    // sub-band noise energies (gmm only)
+    pub bands: Vec<f64>
+}
+
+impl Calibration {
+    /**
+     * Read a calibration saved with to_json.
+     */
+    pub fn from_json(json: &Json) -> Option<Calibration> {
+        let algorithm = json.find("algorithm").and_then(|a| a.as_string()).and_then(VadAlgorithm::from_name);
+        let noise_floor = json.find("noise_floor").and_then(|f| f.as_f64());
+        let bands = match json.find("bands") {
+            Some(bands) => match bands.as_array() {
+                Some(bands) => {
+                    let values: Vec<f64> = bands.iter().filter_map(|b| b.as_f64()).collect();
+                    if values.len() != bands.len() {
+                        return None;
+                    }
+                    values
+                }
+                None => return None
+            },
+            None => Vec::new()
+        };
+        match (algorithm, noise_floor) {
+            (Some(algorithm), Some(noise_floor)) => Some(Calibration {
+                algorithm: algorithm,
+                noise_floor: noise_floor,
+                bands: bands
+            }),
+            _ => None
+        }
+    }
+}
 
-    let (tx, rx) = channel();
-    let reader = io::ChanReader::new(rx);
+impl ToJson for Calibration {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("algorithm".to_string(), self.algorithm.name().to_json());
+        obj.insert("noise_floor".to_string(), self.noise_floor.to_json());
+        obj.insert("bands".to_string(), self.bands.to_json());
+        Json::Object(obj)
+    }
+}
 
-    let (ctl_tx, ctl_rx) = channel();
+/**
+ * End-of-speech detection settings. The threshold defaults to the algorithm's
+ * own (see the vad crate); frames are 10ms long.
+ */
+#[deriving(Clone, Show)]
+pub struct VadOptions {
+    pub algorithm: VadAlgorithm,
+    pub threshold: Option<f64>,
+    pub init_frames: uint,
+    pub end_frames: uint,
+    // skips the learning frames when set (see calibrate)
+    pub calibration: Option<Calibration>
+}
+
+impl Default for VadOptions {
+    fn default() -> VadOptions {
+        VadOptions {
+            algorithm: VadAlgorithm::Energy,
+            threshold: None,
+            init_frames: 30,
+            end_frames: 30,
+            calibration: None
+        }
+    }
+}
+
+// End-of-speech detection, when libwit is built with the "vad" feature
+#[cfg(feature = "vad")]
+struct Detector {
+    inner: Box<vad::VoiceDetector + Send>,
+    algorithm: VadAlgorithm,
+    // 0 when calibrated
+    init_frames: uint
+}
+
+#[cfg(feature = "vad")]
+impl Detector {
+    fn new(opts: &VadOptions, rate: u32) -> Detector {
+        let mut config = vad::DetectorConfig::new(match opts.algorithm {
+            VadAlgorithm::Energy => vad::Algorithm::Energy,
+            VadAlgorithm::Spectral => vad::Algorithm::Spectral,
+            VadAlgorithm::Gmm => vad::Algorithm::Gmm
+        });
+        match opts.threshold {
+            Some(threshold) => config.threshold = threshold,
+            None => ()
+        }
+        config.init_frames = opts.init_frames;
+        config.end_frames = opts.end_frames;
+        wit_log!(Debug, "end-of-speech detection: {}", config);
+        let mut inner = vad::new_detector(&config, rate);
+        let mut init_frames = opts.init_frames;
+        match opts.calibration {
+            Some(ref c) if c.algorithm == opts.algorithm => {
+                let profile = vad::NoiseProfile { noise_floor: c.noise_floor, bands: c.bands.clone() };
+                if inner.set_noise_profile(&profile) {
+                    wit_log!(Debug, "using calibrated noise floor: {}", c.noise_floor);
+                    init_frames = 0;
+                } else {
+                    wit_log!(Warn, "ignoring invalid calibration, learning the noise instead");
+                }
+            }
+            Some(ref c) => wit_log!(Warn, "ignoring calibration made with the {} algorithm", c.algorithm.name()),
+            None => ()
+        }
+        Detector {
+            inner: inner,
+            algorithm: opts.algorithm.clone(),
+            init_frames: init_frames
+        }
+    }
+
+    /**
+     * Whether noise_floor will give a level in dBFS. The energy detector's
+     * is the average attenuation of the samples, which is not.
+     */
+    fn has_noise_floor(&self) -> bool {
+        self.algorithm != VadAlgorithm::Energy
+    }
+
+    /**
+     * The noise floor in dBFS, once learned.
+     */
+    fn noise_floor(&self) -> Option<f64> {
+        if self.has_noise_floor() && self.inner.frames() >= self.init_frames {
+            Some(self.inner.levels().1)
+        } else {
+            None
+        }
+    }
+
+    fn frames(&self) -> uint {
+        self.inner.frames()
+    }
+
+    fn calibration(&self, algorithm: VadAlgorithm) -> Calibration {
+        let profile = self.inner.noise_profile();
+        Calibration {
+            algorithm: algorithm,
+            noise_floor: profile.noise_floor,
+            bands: profile.bands
+        }
+    }
+
+    fn still_talking(&mut self, samples: &[i16]) -> bool {
+        self.inner.still_talking(samples)
+    }
+
+    fn talking(&self) -> bool {
+        self.inner.talking()
+    }
+}
+
+#[cfg(not(feature = "vad"))]
+struct Detector;
+
+#[cfg(not(feature = "vad"))]
+impl Detector {
+    fn new(_: &VadOptions, _: u32) -> Detector {
+        panic!("libwit was built without VAD support")
+    }
+
+    fn still_talking(&mut self, _: &[i16]) -> bool {
+        true
+    }
+
+    fn talking(&self) -> bool {
+        false
+    }
+
+    fn frames(&self) -> uint {
+        0
+    }
+
+    fn has_noise_floor(&self) -> bool {
+        false
+    }
+
+    fn noise_floor(&self) -> Option<f64> {
+        None
+    }
+
+    fn calibration(&self, _: VadAlgorithm) -> Calibration {
+        unreachable!()
+    }
+}
+
+/**
+ * Level of the input over one metering interval.
+ */
+struct Interval {
+    interval: Duration,
+    len: uint,
+    samples: Vec<f64>,
+    peak: f64,
+    clips: u64
+}
+
+/**
+ * Computes the level of the input for the level subscribers, over the interval
+ * each of them asked for. Subscribers added during the session are metered
+ * from the next samples.
+ */
+struct Meter {
+    levels: LevelBus,
+    rate: u32,
+    intervals: Vec<Interval>
+}
+
+impl Meter {
+    fn new(levels: LevelBus, rate: u32) -> Meter {
+        Meter {
+            levels: levels,
+            rate: rate,
+            intervals: Vec::new()
+        }
+    }
 
-    let path = input_device.unwrap_or("default".to_string()).to_c_str();
-    let alsa = "alsa".to_c_str();
-    let coreaudio = "coreaudio".to_c_str();
+    /**
+     * Add the samples just read. clips is the clip count of the source.
+     */
+    fn push(&mut self, samples: &[i16], clips: u64) {
+        let intervals = self.levels.intervals();
+        self.intervals.retain(|i| intervals.contains(&i.interval));
+        for &interval in intervals.iter() {
+            if !self.intervals.iter().any(|i| i.interval == interval) {
+                let len = interval.num_milliseconds() as uint * self.rate as uint / 1000;
+                let len = if len == 0 { 1 } else { len };
+                self.intervals.push(Interval {
+                    interval: interval,
+                    len: len,
+                    samples: Vec::with_capacity(len),
+                    peak: 0.0,
+                    clips: clips
+                });
+            }
+        }
+        for meter in self.intervals.iter_mut() {
+            for &sample in samples.iter() {
+                let x = sample as f64 / 32768.0;
+                if x.abs() > meter.peak {
+                    meter.peak = x.abs();
+                }
+                meter.samples.push(x);
+                if meter.samples.len() == meter.len {
+                    self.levels.emit(meter.interval, Level {
+                        rms: dbfs(meter.samples.as_slice()),
+                        // the level of a single sample is its peak level
+                        peak: dbfs(&[meter.peak]),
+                        clips: if clips > meter.clips { clips - meter.clips } else { 0 }
+                    });
+                    meter.clips = clips;
+                    meter.samples.clear();
+                    meter.peak = 0.0;
+                }
+            }
+        }
+    }
+}
 
-    let mut input_ptr = unsafe {ffi::sox_open_read(path.as_ptr(), null(), null(), alsa.as_ptr())};
-    if input_ptr.is_null() {
-        wit_log!(Info, "couldn't open input device using alsa. Trying with coreaudio...");
-        input_ptr = unsafe {ffi::sox_open_read(path.as_ptr(), null(), null(), coreaudio.as_ptr())};
+fn cleanup_recording_session(source: Box<AudioSource + Send>, detector: Option<Detector>, tee: Option<WavWriter>, events: &EventBus<WitEvent>) {
+    wit_log!(Info, "stopping mic");
+    drop(source);
+    events.emit(WitEvent::RecordingStopped);
+    match tee.map(|writer| writer.finish()) {
+        Some(Err(e)) => wit_log!(Error, "failed to finalize captured audio: {}", e),
+        _ => ()
     }
-    if input_ptr.is_null() {
-        wit_log!(Error, "Failed to open input device");
-        return None;
+    drop(detector);
+}
+
+pub fn start(mut source: Box<AudioSource + Send>, vad_opts: Option<VadOptions>, tee_path: Option<Path>,
+             events: EventBus<WitEvent>, levels: LevelBus,
+             preprocess: &PreprocessOptions, codec: &Codec, pipeline: &PipelineOptions,
+             metrics: Recorder) -> Result<MicContext, RequestError> {
+    if vad_opts.is_some() && !cfg!(feature = "vad") {
+        wit_log!(Error, "end-of-speech detection is unavailable: libwit was built without VAD support");
+        return Err(RequestError::VadUnavailable);
     }
 
-    let input = unsafe {&*input_ptr};
-    wit_log!(Info, "initialized recording device");
-    wit_log!(Debug, "rate: {}, channels: {}, encoding: {}, bits_per_sample: {}, opposite_endian: {}",
-        input.signal.rate,
-        input.signal.channels,
-        input.encoding.encoding,
-        input.encoding.bits_per_sample,
-        input.encoding.opposite_endian);
+    if !codec.is_available(source.rate()) {
+        wit_log!(Error, "{} encoding is unavailable at {}Hz", codec, source.rate());
+        return Err(RequestError::CodecUnavailable);
+    }
+    if *codec == Codec::Flac {
+        wit_log!(Warn, "audio/flac is not a content type documented by the Wit API");
+    }
 
-    let is_big_endian = match input.encoding.opposite_endian {
-        SoxBool::SoxFalse => is_big_endian(),
-        SoxBool::SoxTrue => !is_big_endian()
+    let drop_oldest = pipeline.backpressure == Backpressure::DropOldest;
+    if drop_oldest && !codec.can_drop() {
+        wit_log!(Warn, "dropping audio would corrupt the {} stream, blocking instead", codec);
+    }
+    let pipeline = PipelineOptions {
+        backpressure: if drop_oldest && !codec.can_drop() { Backpressure::Block } else { pipeline.backpressure.clone() },
+        ..pipeline.clone()
     };
+    let (mut writer, reader) = pipe::pipe(&pipeline);
+
+    let (ctl_tx, ctl_rx) = channel();
+
+    let rate = source.rate();
 
     // initialize VAD
-    let vad_state = if vad_enabled {
-        unsafe {vad::wvs_init(8f64, input.signal.rate as i32)}
+    let mut detector = vad_opts.map(|opts| Detector::new(&opts, rate));
+
+    // optionally save the session audio to a WAV file
+    let mut tee = tee_path.and_then(|path| {
+        match WavWriter::create(&path, rate) {
+            Ok(writer) => {
+                wit_log!(Info, "saving captured audio to {}", path.display());
+                Some(writer)
+            }
+            Err(e) => {
+                wit_log!(Error, "failed to create {}: {}", path.display(), e);
+                None
+            }
+        }
+    });
+
+    let mut meter = Meter::new(levels, rate);
+
+    let mut encoder = codec.encoder(rate);
+    let content_type = encoder.content_type();
+    let codec_name = format!("{}", codec);
+
+    let mut preprocessor = if preprocess.is_enabled() {
+        let vad_noise_floor = detector.as_ref().map_or(false, |d| d.has_noise_floor());
+        Some(Preprocessor::new(preprocess, rate, vad_noise_floor))
     } else {
-        null()
+        None
     };
 
-    let cloned_input = input.clone();
+    // reused for every read
+    let mut buf = Vec::from_elem(if pipeline.read_size > 0 { pipeline.read_size } else { 1 }, 0i16);
+
+    events.emit(WitEvent::RecordingStarted);
+    metrics.capture_started(rate);
+
     spawn(proc() {
+        let mut was_talking = false;
+        let (mut bytes_in, mut bytes_out) = (0u, 0u);
         loop {
             match ctl_rx.try_recv() {
                 Ok(x) => {
@@ -82,98 +420,190 @@ pub fn start(input_device: Option<String>, vad_enabled: bool) -> Option<MicConte
                     match x {
                         true => (),
                         false => {
-                            cleanup_recording_session(input_ptr, vad_state);
+                            // stopped by hand: the end of speech as far as the user is concerned
+                            metrics.speech_ended();
+                            cleanup_recording_session(source, detector.take(), tee.take(), &events);
                             break;
                         }
                     }
                 }
                 Err(Empty) => {
-                    let num_channels = cloned_input.signal.channels as uint;
-                    let total_bytes = 4 * (BUF_SIZE - BUF_SIZE % num_channels);
-                    let buf = Vec::from_elem(total_bytes, 0u8);
-                    unsafe {ffi::sox_read(input_ptr, (&buf).as_ptr() as *const i32, BUF_SIZE as size_t)};
-                    //println!("Read: {}", buf);
-                    let total_mono_bytes = total_bytes / (2 * num_channels); // 32bit -> 16bit
-                    let num_samples = total_mono_bytes / 2;
-                    let monobuf = Vec::from_fn(total_mono_bytes, |idx| {
-                        let byte_offset = if is_big_endian {
-                            idx % 2
-                        } else {
-                            3 - idx % 2
-                        };
-                        buf[(idx / 4) * 4 * num_channels * 2 + byte_offset]
-                    });
+                    let num_read = match source.read(buf.as_mut_slice()) {
+                        Ok(num_read) => num_read,
+                        Err(e) => {
+                            if e.kind == io::EndOfFile {
+                                wit_log!(Info, "end of audio input");
+                            } else {
+                                wit_log!(Error, "failed to read audio: {}", e);
+                            }
+                            cleanup_recording_session(source, detector.take(), tee.take(), &events);
+                            break;
+                        }
+                    };
+                    let samples = buf.slice_to_mut(num_read);
+                    metrics.recorded(num_read);
 
-                    if vad_enabled {
-                        // TODO we shouldn't need to change the format twice
-                        let monobuf_platform_endianness = Vec::from_fn(total_mono_bytes, |idx| {
-                            let byte_offset = match cloned_input.encoding.opposite_endian {
-                                SoxBool::SoxFalse => idx % 2,
-                                SoxBool::SoxTrue => 1 - idx % 2
-                            };
-                            buf[(idx / 2) * 2 + byte_offset]
-                        });
-
-                        let still_talking = unsafe {
-                            vad::wvs_still_talking(
-                                vad_state,
-                                monobuf_platform_endianness.as_ptr() as *const i16,
-                                num_samples as i32)
+                    // the levels of the input itself
+                    meter.push(samples.as_slice(), source.clips());
+
+                    match preprocessor {
+                        Some(ref mut preprocessor) => {
+                            let (talking, noise_floor) = match detector {
+                                Some(ref detector) => (detector.talking(), detector.noise_floor()),
+                                None => (false, None)
                             };
-                        if still_talking == 0 {
-                            wit_log!(Info, "detected end of speech");
-                            cleanup_recording_session(input_ptr, vad_state);
-                            break;
+                            preprocessor.process(samples, talking, noise_floor);
+                        }
+                        None => ()
+                    }
+
+                    let still_talking = match detector {
+                        Some(ref mut detector) => {
+                            let still_talking = detector.still_talking(samples.as_slice());
+                            if detector.talking() && !was_talking {
+                                was_talking = true;
+                                metrics.speech_started();
+                                events.emit(WitEvent::SpeechStarted);
+                            }
+                            still_talking
                         }
+                        None => true
+                    };
+                    if !still_talking {
+                        wit_log!(Info, "detected end of speech");
+                        metrics.speech_ended();
+                        events.emit(WitEvent::SpeechEnded);
+                        cleanup_recording_session(source, detector.take(), tee.take(), &events);
+                        break;
+                    }
+
+                    let tee_failed = match tee {
+                        Some(ref mut writer) => writer.write_samples(samples.as_slice()).is_err(),
+                        None => false
+                    };
+                    if tee_failed {
+                        wit_log!(Error, "failed to write captured audio, disabling capture");
+                        tee = None;
                     }
 
-                    let result = tx.send_opt(monobuf);
-                    if result.is_err() {
-                        wit_log!(Error, "error while sending: {}", result.err());
+                    bytes_in += samples.len() * 2;
+                    let encoded = encoder.encode(samples.as_slice());
+                    bytes_out += encoded.len();
+                    match writer.write(encoded) {
+                        Ok(()) => (),
+                        Err(e) => {
+                            match e {
+                                PipeError::Overflow => wit_log!(Error, "the upload is too slow, audio buffer full: failing the query"),
+                                PipeError::Closed => wit_log!(Info, "upload closed, stopping the recording")
+                            }
+                            cleanup_recording_session(source, detector.take(), tee.take(), &events);
+                            break;
+                        }
                     }
                 }
                 Err(Disconnected) => {
                     wit_log!(Info, "done");
-                    cleanup_recording_session(input_ptr, vad_state);
+                    cleanup_recording_session(source, detector.take(), tee.take(), &events);
                     break;
                 }
             }
         }
+        // the end of the stream, with the samples the encoder still had
+        let encoded = encoder.finish();
+        bytes_out += encoded.len();
+        let _ = writer.write(encoded);
+        wit_log!(Debug, "{} encoding: {} bytes of audio sent as {} bytes", codec_name, bytes_in, bytes_out);
+        let dropped = writer.dropped();
+        if dropped > 0 {
+            wit_log!(Warn, "the upload fell behind: dropped {} bytes of audio", dropped);
+        }
+        // dropping the writer ends the upload
     });
 
     ctl_tx.send(true);
 
-    let ref sox_encoding = input.encoding.encoding;
-    let encoding_opt = match sox_encoding {
-        &SoxEncodingT::SOX_ENCODING_SIGN2 => Some("signed-integer"),
-        &SoxEncodingT::SOX_ENCODING_UNSIGNED => Some("unsigned-integer"),
-        &SoxEncodingT::SOX_ENCODING_FLOAT => Some("floating-point"),
-        &SoxEncodingT::SOX_ENCODING_ULAW => Some("ulaw"),
-        &SoxEncodingT::SOX_ENCODING_ALAW => Some("alaw"),
-        _ => None
-    };
-    if encoding_opt.is_none() {
-        wit_log!(Error, "unsupported encoding: {}", sox_encoding);
-        return None
-    }
-    Some(MicContext {
+    Ok(MicContext {
         reader: box reader,
         sender: ctl_tx,
-        rate: input.signal.rate as u32,
-        encoding: encoding_opt.unwrap().to_string()
+        content_type: content_type
     })
 }
 
+/**
+ * Measure the background noise for the given duration, with the detector
+ * selected in opts. The user should stay silent meanwhile.
+ */
+pub fn calibrate(mut source: Box<AudioSource + Send>, opts: &VadOptions, duration: Duration) -> Result<Calibration, RequestError> {
+    if !cfg!(feature = "vad") {
+        wit_log!(Error, "calibration is unavailable: libwit was built without VAD support");
+        return Err(RequestError::VadUnavailable);
+    }
+    // 10ms frames, all of them learning
+    let frames = (duration.num_milliseconds() / 10) as uint;
+    let frames = if frames == 0 { 1 } else { frames };
+    let vad_opts = VadOptions {
+        init_frames: frames,
+        calibration: None,
+        ..opts.clone()
+    };
+    let mut detector = Detector::new(&vad_opts, source.rate());
+    wit_log!(Info, "calibrating for {}ms", frames * 10);
+    // bounded by the samples read, not the frames: vad.c skips exact zeros
+    let total = frames as u64 * source.rate() as u64 / 100;
+    let mut read = 0u64;
+    let mut samples = Vec::from_elem(PipelineOptions::new().read_size, 0i16);
+    while read < total && detector.frames() < frames {
+        let len = cmp::min(samples.len() as u64, total - read) as uint;
+        match source.read(samples.slice_to_mut(len)) {
+            Ok(num_read) => {
+                detector.still_talking(samples.slice_to(num_read));
+                read += num_read as u64;
+            }
+            Err(e) => {
+                wit_log!(Error, "failed to read audio: {}", e);
+                return Err(RequestError::DeviceError);
+            }
+        }
+    }
+    if detector.frames() == 0 {
+        wit_log!(Error, "nothing to calibrate on: the input was digital silence (muted?)");
+        return Err(RequestError::DeviceError);
+    }
+    let calibration = detector.calibration(opts.algorithm.clone());
+    wit_log!(Info, "measured noise floor: {}", calibration.noise_floor);
+    Ok(calibration)
+}
+
 pub fn stop(tx: &Sender<bool>) {
-    tx.send(false);
+    // the session may be over already, at the end of the input
+    let _ = tx.send_opt(false);
 }
 
-pub fn init (/*args: &[String]*/) {
-    match unsafe {ffi::sox_format_init()} {
-        SoxErrorT::SOX_SUCCESS => wit_log!(Info, "initialized sox: {}", unsafe {CString::new(ffi::sox_version(), false)}),
-        err => {
-            wit_log!(Error, "failed to initialize sox: {}", err);
-            return;
+#[cfg(all(test, feature = "vad"))]
+mod tests {
+    use std::default::Default;
+    use std::time::Duration;
+    use client::RequestError;
+    use source::{AudioSource, SyntheticSource, Segment};
+    use super::calibrate;
+
+    fn source(segments: Vec<Segment>) -> Box<AudioSource + Send> {
+        box SyntheticSource::new(segments, 16000) as Box<AudioSource + Send>
+    }
+
+    #[test]
+    fn calibration_measures_the_noise() {
+        let calibration = calibrate(source(vec![Segment::noise(0.01, 1000)]), &Default::default(), Duration::milliseconds(500));
+        assert!(calibration.unwrap().noise_floor < -30.0);
+    }
+
+    #[test]
+    fn calibration_fails_on_digital_silence() {
+        // more silence than the calibration lasts: it must stop on its own
+        let result = calibrate(source(vec![Segment::silence(60000)]), &Default::default(), Duration::milliseconds(500));
+        match result {
+            Err(RequestError::DeviceError) => (),
+            other => panic!("unexpected result: {}", other)
         }
-    };
+    }
 }
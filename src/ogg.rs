@@ -0,0 +1,184 @@
+/*!
+ * Ogg pages, to upload Opus as audio/ogg.
+ */
+
+/// Lacing values per page, the most an Ogg page can hold
+const MAX_SEGMENTS: uint = 255;
+
+const BEGINNING_OF_STREAM: u8 = 0x02;
+const END_OF_STREAM: u8 = 0x04;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    // polynomial 0x04C11DB7, not reflected, no final XOR
+    let mut crc = 0u32;
+    for &b in bytes.iter() {
+        crc ^= (b as u32) << 24;
+        for _ in range(0u, 8) {
+            crc = if crc & 0x80000000 != 0 { (crc << 1) ^ 0x04C11DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/**
+ * Writes the packets of a single logical stream as Ogg pages. Packets are
+ * buffered until flush, so that a page can hold several of them.
+ */
+pub struct OggWriter {
+    serial: u32,
+    sequence: u32,
+    granule: i64,
+    lacing: Vec<u8>,
+    data: Vec<u8>
+}
+
+impl OggWriter {
+    pub fn new(serial: u32) -> OggWriter {
+        OggWriter {
+            serial: serial,
+            sequence: 0,
+            granule: 0,
+            lacing: Vec::new(),
+            data: Vec::new()
+        }
+    }
+
+    /**
+     * Add a packet to the current page. granule is the position of the stream
+     * at the end of the packet. A full page is written to out first.
+     */
+    pub fn packet(&mut self, packet: &[u8], granule: i64, out: &mut Vec<u8>) {
+        let segments = packet.len() / 255 + 1;
+        assert!(segments <= MAX_SEGMENTS, "Ogg packets spanning pages are not supported");
+        if self.lacing.len() + segments > MAX_SEGMENTS {
+            self.flush(false, out);
+        }
+        for _ in range(0, packet.len() / 255) {
+            self.lacing.push(255);
+        }
+        // a packet ends with a lacing value under 255, possibly 0
+        self.lacing.push((packet.len() % 255) as u8);
+        self.data.push_all(packet);
+        self.granule = granule;
+    }
+
+    /**
+     * Write the buffered packets as a page. The last page of the stream is
+     * written even without packets, to mark the end.
+     */
+    pub fn flush(&mut self, last: bool, out: &mut Vec<u8>) {
+        if self.lacing.is_empty() && !last {
+            return;
+        }
+        let start = out.len();
+        out.push_all(b"OggS");
+        // version
+        out.push(0);
+        out.push(if self.sequence == 0 { BEGINNING_OF_STREAM } else { 0 } | if last { END_OF_STREAM } else { 0 });
+        push_le(out, self.granule as u64, 8);
+        push_le(out, self.serial as u64, 4);
+        push_le(out, self.sequence as u64, 4);
+        // the checksum, once the page is complete
+        push_le(out, 0, 4);
+        out.push(self.lacing.len() as u8);
+        out.push_all(self.lacing.as_slice());
+        out.push_all(self.data.as_slice());
+        let crc = crc32(out.slice_from(start));
+        for i in range(0u, 4) {
+            out[start + 22 + i] = (crc >> (8 * i)) as u8;
+        }
+        self.sequence += 1;
+        self.lacing.clear();
+        self.data.clear();
+    }
+}
+
+fn push_le(out: &mut Vec<u8>, value: u64, bytes: uint) {
+    for i in range(0, bytes) {
+        out.push((value >> (8 * i)) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OggWriter, crc32};
+
+    fn le(bytes: &[u8]) -> u64 {
+        bytes.iter().enumerate().fold(0u64, |acc, (i, &b)| acc | (b as u64 << (8 * i)))
+    }
+
+    /**
+     * The pages of a stream: (flags, granule, sequence, packets).
+     */
+    fn pages(mut bytes: &[u8]) -> Vec<(u8, i64, u32, Vec<Vec<u8>>)> {
+        let mut pages = Vec::new();
+        while !bytes.is_empty() {
+            assert_eq!(bytes.slice_to(4), b"OggS");
+            let segments = bytes[26] as uint;
+            let lacing = bytes.slice(27, 27 + segments);
+            let len = lacing.iter().fold(0u, |acc, &l| acc + l as uint);
+            let page = bytes.slice_to(27 + segments + len);
+            let mut zeroed = page.to_vec();
+            for i in range(22u, 26) {
+                zeroed[i] = 0;
+            }
+            assert_eq!(crc32(zeroed.as_slice()) as u64, le(page.slice(22, 26)));
+            let mut packets = Vec::new();
+            let mut packet = Vec::new();
+            let mut pos = 27 + segments;
+            for &l in lacing.iter() {
+                packet.push_all(page.slice(pos, pos + l as uint));
+                pos += l as uint;
+                if l < 255 {
+                    packets.push(packet);
+                    packet = Vec::new();
+                }
+            }
+            pages.push((page[5], le(page.slice(6, 14)) as i64, le(page.slice(18, 22)) as u32, packets));
+            bytes = bytes.slice_from(page.len());
+        }
+        pages
+    }
+
+    #[test]
+    fn crc_is_the_ogg_one() {
+        assert_eq!(crc32(b""), 0);
+        // CRC-32/CKSUM without the final XOR
+        assert_eq!(crc32(b"123456789"), 0x89a1897f);
+    }
+
+    #[test]
+    fn packets_are_laced_into_pages() {
+        let mut ogg = OggWriter::new(42);
+        let mut out = Vec::new();
+        ogg.packet(b"head", 0, &mut out);
+        ogg.flush(false, &mut out);
+        let big = Vec::from_elem(510, 7u8);
+        ogg.packet(big.as_slice(), 960, &mut out);
+        ogg.packet(b"", 960, &mut out);
+        ogg.packet(b"end", 1920, &mut out);
+        ogg.flush(true, &mut out);
+        let pages = pages(out.as_slice());
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0], (0x02, 0, 0, vec![b"head".to_vec()]));
+        assert_eq!(pages[1], (0x04, 1920, 1, vec![big, Vec::new(), b"end".to_vec()]));
+    }
+
+    #[test]
+    fn full_pages_are_written_as_they_come() {
+        let mut ogg = OggWriter::new(42);
+        let mut out = Vec::new();
+        let packet = Vec::from_elem(300, 1u8);
+        // two lacing values each
+        for i in range(0, 200i64) {
+            ogg.packet(packet.as_slice(), i, &mut out);
+        }
+        ogg.flush(true, &mut out);
+        let pages = pages(out.as_slice());
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].3.len(), 127);
+        assert_eq!(pages[0].1, 126);
+        assert_eq!(pages[1].3.len(), 73);
+        assert_eq!(pages[1].0, 0x04);
+    }
+}
@@ -0,0 +1,208 @@
+/*!
+ * Ogg/Opus encoding through libopus, with the "opus" feature.
+ */
+
+use std::ptr;
+use libc::{c_int, c_uchar, c_void};
+use mime::{Mime, TopLevel, SubLevel};
+use codec::Encoder;
+use ogg::OggWriter;
+use log;
+use log::LogLevel::Error;
+
+/// Samples per packet: 20ms
+const FRAME_MS: uint = 20;
+/// Packets per Ogg page: 100ms, to keep the upload streaming
+const PACKETS_PER_PAGE: uint = 5;
+/// Bits per second, for speech
+const BITRATE: c_int = 24000;
+/// Largest Opus packet
+const MAX_PACKET: uint = 1275;
+const OGG_SERIAL: u32 = 0x77697421;
+
+const OPUS_APPLICATION_VOIP: c_int = 2048;
+const OPUS_SET_BITRATE_REQUEST: c_int = 4002;
+const OPUS_GET_LOOKAHEAD_REQUEST: c_int = 4027;
+
+#[link(name = "opus")]
+extern {
+    fn opus_encoder_create(fs: i32, channels: c_int, application: c_int, error: *mut c_int) -> *mut c_void;
+    fn opus_encode(st: *mut c_void, pcm: *const i16, frame_size: c_int, data: *mut c_uchar, max_data_bytes: i32) -> i32;
+    fn opus_encoder_ctl(st: *mut c_void, request: c_int, ...) -> c_int;
+    fn opus_encoder_destroy(st: *mut c_void);
+}
+
+fn push_le(out: &mut Vec<u8>, value: u64, bytes: uint) {
+    for i in range(0, bytes) {
+        out.push((value >> (8 * i)) as u8);
+    }
+}
+
+/**
+ * Mono Opus in Ogg (RFC 7845). The rate must be one of Opus' (see
+ * codec::opus_supports_rate). Granule positions are at 48kHz, as Opus requires.
+ */
+pub struct OpusEncoder {
+    state: *mut c_void,
+    rate: u32,
+    frame_len: uint,
+    // samples of the encoder's delay, at 48kHz
+    pre_skip: u64,
+    pending: Vec<i16>,
+    packet: Vec<u8>,
+    ogg: OggWriter,
+    // samples encoded so far, at the input rate
+    encoded: u64,
+    packets_in_page: uint,
+    out: Vec<u8>,
+    header_sent: bool
+}
+
+impl OpusEncoder {
+    pub fn new(rate: u32) -> OpusEncoder {
+        let mut error = 0;
+        let state = unsafe { opus_encoder_create(rate as i32, 1, OPUS_APPLICATION_VOIP, &mut error) };
+        if error != 0 || state.is_null() {
+            panic!("opus_encoder_create failed: {}", error);
+        }
+        let mut lookahead: i32 = 0;
+        unsafe {
+            opus_encoder_ctl(state, OPUS_SET_BITRATE_REQUEST, BITRATE);
+            opus_encoder_ctl(state, OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead as *mut i32);
+        }
+        let frame_len = rate as uint * FRAME_MS / 1000;
+        OpusEncoder {
+            state: state,
+            rate: rate,
+            frame_len: frame_len,
+            pre_skip: lookahead as u64 * 48000 / rate as u64,
+            pending: Vec::with_capacity(frame_len),
+            packet: Vec::from_elem(MAX_PACKET, 0u8),
+            ogg: OggWriter::new(OGG_SERIAL),
+            encoded: 0,
+            packets_in_page: 0,
+            out: Vec::new(),
+            header_sent: false
+        }
+    }
+
+    fn granule(&self, samples: u64) -> i64 {
+        (self.pre_skip + samples * 48000 / self.rate as u64) as i64
+    }
+
+    // the identification and comment headers, each on its own page
+    fn start(&mut self) {
+        if self.header_sent {
+            return;
+        }
+        self.header_sent = true;
+        let mut head = Vec::new();
+        head.push_all(b"OpusHead");
+        // version, channels
+        head.push(1);
+        head.push(1);
+        push_le(&mut head, self.pre_skip, 2);
+        push_le(&mut head, self.rate as u64, 4);
+        // output gain, channel mapping family
+        push_le(&mut head, 0, 2);
+        head.push(0);
+        self.ogg.packet(head.as_slice(), 0, &mut self.out);
+        self.ogg.flush(false, &mut self.out);
+
+        let vendor = b"libwit";
+        let mut tags = Vec::new();
+        tags.push_all(b"OpusTags");
+        push_le(&mut tags, vendor.len() as u64, 4);
+        tags.push_all(vendor);
+        // no user comments
+        push_le(&mut tags, 0, 4);
+        self.ogg.packet(tags.as_slice(), 0, &mut self.out);
+        self.ogg.flush(false, &mut self.out);
+    }
+
+    // encodes the pending frame, of which `samples` are real samples
+    fn encode_frame(&mut self, samples: uint) {
+        let len = unsafe {
+            opus_encode(self.state, self.pending.as_ptr(), self.frame_len as c_int,
+                        self.packet.as_mut_ptr(), MAX_PACKET as i32)
+        };
+        self.pending.clear();
+        if len < 0 {
+            wit_log!(Error, "opus_encode failed: {}", len);
+            return;
+        }
+        self.encoded += samples as u64;
+        let granule = self.granule(self.encoded);
+        self.ogg.packet(self.packet.slice_to(len as uint), granule, &mut self.out);
+        self.packets_in_page += 1;
+        if self.packets_in_page == PACKETS_PER_PAGE {
+            self.ogg.flush(false, &mut self.out);
+            self.packets_in_page = 0;
+        }
+    }
+}
+
+impl Encoder for OpusEncoder {
+    fn content_type(&self) -> Mime {
+        Mime(TopLevel::Audio, SubLevel::Ext("ogg".to_string()), vec![])
+    }
+
+    fn encode<'a>(&'a mut self, samples: &'a [i16]) -> &'a [u8] {
+        self.out.clear();
+        self.start();
+        for &sample in samples.iter() {
+            self.pending.push(sample);
+            if self.pending.len() == self.frame_len {
+                let len = self.frame_len;
+                self.encode_frame(len);
+            }
+        }
+        self.out.as_slice()
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        self.out.clear();
+        self.start();
+        if !self.pending.is_empty() {
+            // pad the last frame with silence, which the granule position trims
+            let samples = self.pending.len();
+            let padding = self.frame_len - samples;
+            self.pending.grow(padding, 0);
+            self.encode_frame(samples);
+        }
+        self.ogg.flush(true, &mut self.out);
+        self.out.as_slice()
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { opus_encoder_destroy(self.state) };
+        self.state = ptr::null_mut();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::Encoder;
+    use source::{AudioSource, SyntheticSource, Segment};
+    use super::OpusEncoder;
+
+    #[test]
+    fn opus_stream_has_headers_and_ends() {
+        let mut source = SyntheticSource::new(vec![Segment::tone(440.0, 0.5, 1010)], 16000);
+        let mut samples = Vec::from_elem(16160, 0i16);
+        assert_eq!(source.read(samples.as_mut_slice()).unwrap(), 16160);
+        let mut encoder = OpusEncoder::new(16000);
+        assert_eq!(format!("{}", encoder.content_type()).as_slice(), "audio/ogg");
+        let mut bytes = encoder.encode(samples.as_slice()).to_vec();
+        bytes.push_all(encoder.finish());
+        // OpusHead, then OpusTags, on pages of their own
+        assert_eq!(bytes.slice(0, 4), b"OggS");
+        assert_eq!(bytes[5], 0x02);
+        assert_eq!(bytes.slice(28, 36), b"OpusHead");
+        assert_eq!(bytes.slice(28 + 19, 28 + 19 + 4), b"OggS");
+        // about 24 kbit/s instead of 256
+        assert!(bytes.len() < 16160 * 2 / 4);
+    }
+}
@@ -0,0 +1,322 @@
+use std::io::{mod, IoResult, IoError};
+use std::{cmp, mem};
+use std::slice::bytes::copy_memory;
+use std::sync::{Arc, Mutex, Condvar};
+
+/**
+ * What the mic does when the upload falls behind and the buffer is full.
+ */
+#[deriving(Clone, Show, PartialEq)]
+pub enum Backpressure {
+    /// Wait for the upload. The device may then drop samples on its side.
+    Block,
+    /// Drop the oldest audio not uploaded yet. Only for the raw codecs:
+    /// it would corrupt a FLAC or Opus stream.
+    DropOldest,
+    /// Fail the query
+    Fail
+}
+
+impl Backpressure {
+    pub fn from_name(name: &str) -> Option<Backpressure> {
+        match name {
+            "block" => Some(Backpressure::Block),
+            "drop-oldest" => Some(Backpressure::DropOldest),
+            "fail" => Some(Backpressure::Fail),
+            _ => None
+        }
+    }
+}
+
+/**
+ * How the audio goes from the source to the upload: the number of samples
+ * read at once, and the size of the buffer in between.
+ */
+#[deriving(Clone, Show)]
+pub struct PipelineOptions {
+    pub read_size: uint,
+    pub buffer_bytes: uint,
+    pub backpressure: Backpressure
+}
+
+impl PipelineOptions {
+    pub fn new() -> PipelineOptions {
+        PipelineOptions {
+            // 64ms at 16kHz
+            read_size: 1024,
+            // about 30s of 16-bit audio at 16kHz
+            buffer_bytes: 1 << 20,
+            backpressure: Backpressure::Block
+        }
+    }
+}
+
+#[deriving(Show, PartialEq)]
+pub enum PipeError {
+    /// The buffer is full, and the policy is to fail
+    Overflow,
+    /// The upload is over
+    Closed
+}
+
+struct Ring {
+    buf: Vec<u8>,
+    start: uint,
+    len: uint,
+    // no more writes
+    closed: bool,
+    // the writer gave up, after an overflow
+    failed: bool,
+    // the reader is gone
+    abandoned: bool,
+    dropped: u64
+}
+
+type Shared = Arc<(Mutex<Ring>, Condvar)>;
+
+/**
+ * The mic side of a bounded, preallocated byte ring buffer.
+ */
+pub struct PipeWriter {
+    shared: Shared,
+    backpressure: Backpressure
+}
+
+/**
+ * The upload side, read until the writer is dropped.
+ */
+pub struct PipeReader {
+    shared: Shared
+}
+
+/**
+ * A pipe of buffer_bytes, rounded up to whole 16-bit samples.
+ */
+pub fn pipe(opts: &PipelineOptions) -> (PipeWriter, PipeReader) {
+    let shared = Arc::new((Mutex::new(Ring {
+        buf: Vec::from_elem(cmp::max((opts.buffer_bytes + 1) & !1, 2), 0u8),
+        start: 0,
+        len: 0,
+        closed: false,
+        failed: false,
+        abandoned: false,
+        dropped: 0
+    }), Condvar::new()));
+    (PipeWriter { shared: shared.clone(), backpressure: opts.backpressure.clone() },
+     PipeReader { shared: shared })
+}
+
+impl PipeWriter {
+    pub fn write(&mut self, data: &[u8]) -> Result<(), PipeError> {
+        let &(ref lock, ref cond) = &*self.shared;
+        let mut ring = lock.lock();
+        let mut data = data;
+        while !data.is_empty() {
+            if ring.abandoned {
+                return Err(PipeError::Closed);
+            }
+            let cap = ring.buf.len();
+            if ring.len == cap {
+                match self.backpressure {
+                    Backpressure::Block => cond.wait(&ring),
+                    Backpressure::DropOldest => {
+                        // whole 16-bit samples
+                        let n = cmp::min((data.len() + 1) & !1, ring.len);
+                        ring.start = (ring.start + n) % cap;
+                        ring.len -= n;
+                        ring.dropped += n as u64;
+                    }
+                    Backpressure::Fail => {
+                        ring.failed = true;
+                        cond.notify_all();
+                        return Err(PipeError::Overflow);
+                    }
+                }
+                continue;
+            }
+            // up to the free space, in at most two copies around the end
+            let n = cmp::min(cap - ring.len, data.len());
+            let end = (ring.start + ring.len) % cap;
+            let first = cmp::min(n, cap - end);
+            copy_memory(ring.buf.slice_mut(end, end + first), data.slice_to(first));
+            copy_memory(ring.buf.slice_to_mut(n - first), data.slice(first, n));
+            ring.len += n;
+            data = data.slice_from(n);
+            cond.notify_all();
+        }
+        Ok(())
+    }
+
+    /**
+     * Number of bytes dropped so far (DropOldest).
+     */
+    pub fn dropped(&self) -> u64 {
+        let &(ref lock, _) = &*self.shared;
+        lock.lock().dropped
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let &(ref lock, ref cond) = &*self.shared;
+        lock.lock().closed = true;
+        cond.notify_all();
+    }
+}
+
+impl Reader for PipeReader {
+    fn read(&mut self, out: &mut [u8]) -> IoResult<uint> {
+        let &(ref lock, ref cond) = &*self.shared;
+        let mut ring = lock.lock();
+        while ring.len == 0 && !ring.closed && !ring.failed {
+            cond.wait(&ring);
+        }
+        if ring.failed {
+            return Err(IoError {
+                kind: io::BrokenPipe,
+                desc: "audio buffer overflow",
+                detail: None
+            });
+        }
+        if ring.len == 0 {
+            return Err(io::standard_error(io::EndOfFile));
+        }
+        let cap = ring.buf.len();
+        let n = cmp::min(ring.len, out.len());
+        let first = cmp::min(n, cap - ring.start);
+        {
+            let (head, tail) = out.split_at_mut(first);
+            copy_memory(head, ring.buf.slice(ring.start, ring.start + first));
+            copy_memory(tail.slice_to_mut(n - first), ring.buf.slice_to(n - first));
+        }
+        ring.start = (ring.start + n) % cap;
+        ring.len -= n;
+        cond.notify_all();
+        Ok(n)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let &(ref lock, ref cond) = &*self.shared;
+        let mut ring = lock.lock();
+        ring.abandoned = true;
+        // the memory can go now
+        mem::replace(&mut ring.buf, Vec::new());
+        cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{mod, ChanReader, ChanWriter};
+    use test::Bencher;
+    use super::{pipe, Backpressure, PipelineOptions, PipeError};
+
+    fn options(buffer_bytes: uint, backpressure: Backpressure) -> PipelineOptions {
+        PipelineOptions {
+            buffer_bytes: buffer_bytes,
+            backpressure: backpressure,
+            ..PipelineOptions::new()
+        }
+    }
+
+    #[test]
+    fn bytes_come_out_in_order_around_the_end() {
+        let (mut writer, mut reader) = pipe(&options(8, Backpressure::Fail));
+        let mut buf = [0u8, ..8];
+        writer.write(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(reader.read(buf.slice_to_mut(4)).unwrap(), 4);
+        assert_eq!(buf.slice_to(4), [1, 2, 3, 4].as_slice());
+        // 2 bytes at the end of the buffer, 4 at its start
+        writer.write(&[7, 8, 9, 10, 11, 12]).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 8);
+        assert_eq!(buf.as_slice(), [5, 6, 7, 8, 9, 10, 11, 12].as_slice());
+        drop(writer);
+        assert_eq!(reader.read(&mut buf).unwrap_err().kind, io::EndOfFile);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_latest_samples() {
+        let (mut writer, mut reader) = pipe(&options(8, Backpressure::DropOldest));
+        writer.write(&[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        writer.write(&[8, 9, 10, 11]).unwrap();
+        assert_eq!(writer.dropped(), 4);
+        // an odd write still drops whole samples
+        writer.write(&[12, 13, 14]).unwrap();
+        assert_eq!(writer.dropped(), 8);
+        drop(writer);
+        assert_eq!(reader.read_to_end().unwrap(), vec![8, 9, 10, 11, 12, 13, 14]);
+    }
+
+    #[test]
+    fn fail_fails_both_ends() {
+        let (mut writer, mut reader) = pipe(&options(4, Backpressure::Fail));
+        writer.write(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(writer.write(&[5]), Err(PipeError::Overflow));
+        assert_eq!(reader.read(&mut [0u8, ..4]).unwrap_err().kind, io::BrokenPipe);
+    }
+
+    #[test]
+    fn writes_fail_once_the_reader_is_gone() {
+        let (mut writer, reader) = pipe(&options(4, Backpressure::Block));
+        drop(reader);
+        assert_eq!(writer.write(&[1, 2]), Err(PipeError::Closed));
+    }
+
+    #[test]
+    fn the_buffer_holds_whole_samples() {
+        let (mut writer, _reader) = pipe(&options(5, Backpressure::Fail));
+        writer.write(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(writer.write(&[7]), Err(PipeError::Overflow));
+    }
+
+    // 1s of 16kHz audio, written 1024 samples at a time by another task
+    const CHUNK: uint = 2048;
+    const CHUNKS: uint = 16;
+
+    fn drain(reader: &mut Reader) {
+        let mut buf = [0u8, ..CHUNK];
+        let mut total = 0;
+        loop {
+            match reader.read(&mut buf) {
+                Ok(n) => total += n,
+                Err(_) => break
+            }
+        }
+        assert_eq!(total, CHUNK * CHUNKS);
+    }
+
+    #[bench]
+    fn bench_pipe(b: &mut Bencher) {
+        b.bytes = (CHUNK * CHUNKS) as u64;
+        b.iter(|| {
+            let (mut writer, mut reader) = pipe(&PipelineOptions::new());
+            spawn(proc() {
+                let chunk = [0u8, ..CHUNK];
+                for _ in range(0, CHUNKS) {
+                    writer.write(&chunk).unwrap();
+                }
+            });
+            drain(&mut reader);
+        });
+    }
+
+    // what the mic used before the pipe: a Vec per read, through a channel
+    #[bench]
+    fn bench_channel(b: &mut Bencher) {
+        b.bytes = (CHUNK * CHUNKS) as u64;
+        b.iter(|| {
+            let (tx, rx) = channel();
+            let mut reader = ChanReader::new(rx);
+            spawn(proc() {
+                let mut writer = ChanWriter::new(tx);
+                let chunk = [0u8, ..CHUNK];
+                for _ in range(0, CHUNKS) {
+                    writer.write(&chunk).unwrap();
+                }
+            });
+            drain(&mut reader);
+        });
+    }
+}
@@ -0,0 +1,467 @@
+use std::num::Float;
+use std::f64::consts::PI;
+use std::collections::RingBuf;
+use dsp::fft::fft;
+use log;
+use log::LogLevel::{Warn, Debug};
+
+/// Frames of the noise suppression learned as noise when there is no estimate
+const NOISE_LEARNING_FRAMES: uint = 10;
+/// Over-subtraction factor and spectral floor of the noise suppression
+const OVER_SUBTRACTION: f64 = 2.0;
+const SPECTRAL_FLOOR: f64 = 0.1;
+/// Below this level (dBFS), the gain control leaves the gain alone
+const AGC_GATE: f64 = -50.0;
+const AGC_MAX_GAIN: f64 = 30.0;
+/// Peak level of the limiter, in full scale
+const LIMIT: f64 = 0.89;
+
+/**
+ * Processing applied to the audio between the device and the VAD and upload,
+ * in this order. Each stage is disabled by default.
+ */
+#[deriving(Clone, Show)]
+pub struct PreprocessOptions {
+    /// Cutoff of the high-pass filter, in Hz. It also removes the DC offset.
+    pub high_pass: Option<f64>,
+    /// Spectral subtraction of the background noise
+    pub noise_suppression: bool,
+    /// Target RMS level of the automatic gain control, in dBFS. A limiter keeps
+    /// the peaks under full scale.
+    pub agc: Option<f64>
+}
+
+impl PreprocessOptions {
+    pub fn none() -> PreprocessOptions {
+        PreprocessOptions {
+            high_pass: None,
+            noise_suppression: false,
+            agc: None
+        }
+    }
+
+    /**
+     * Parse a comma-separated list of stages: "highpass" (100Hz), "ns" and "agc" (-20dBFS).
+     */
+    pub fn from_names(names: &str) -> PreprocessOptions {
+        let mut opts = PreprocessOptions::none();
+        for name in names.split(',').map(|n| n.trim()) {
+            match name {
+                "highpass" => opts.high_pass = Some(100.0),
+                "ns" => opts.noise_suppression = true,
+                "agc" => opts.agc = Some(-20.0),
+                "" => (),
+                _ => wit_log!(Warn, "unknown preprocessing stage: {}", name)
+            }
+        }
+        opts
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.high_pass.is_some() || self.noise_suppression || self.agc.is_some()
+    }
+}
+
+/**
+ * Second-order Butterworth high-pass filter.
+ */
+struct HighPass {
+    b: [f64, ..3],
+    a: [f64, ..2],
+    x: [f64, ..2],
+    y: [f64, ..2]
+}
+
+impl HighPass {
+    fn new(cutoff: f64, rate: u32) -> HighPass {
+        let w = 2.0 * PI * cutoff / rate as f64;
+        let alpha = w.sin() / 2.0f64.sqrt();
+        let a0 = 1.0 + alpha;
+        let c = w.cos();
+        HighPass {
+            b: [(1.0 + c) / 2.0 / a0, -(1.0 + c) / a0, (1.0 + c) / 2.0 / a0],
+            a: [-2.0 * c / a0, (1.0 - alpha) / a0],
+            x: [0.0, 0.0],
+            y: [0.0, 0.0]
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f64]) {
+        for s in samples.iter_mut() {
+            let x = *s;
+            let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+                - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+            self.x = [x, self.x[0]];
+            self.y = [y, self.y[0]];
+            *s = y;
+        }
+    }
+}
+
+/**
+ * Spectral subtraction over 50% overlapping frames, with square root Hann
+ * windows for the analysis and the synthesis. The output is delayed by a frame.
+ */
+struct NoiseSuppressor {
+    size: uint,
+    window: Vec<f64>,
+    // the last frame of input, and the samples of the next one
+    frame: Vec<f64>,
+    pending: Vec<f64>,
+    overlap: Vec<f64>,
+    output: RingBuf<f64>,
+    // noise power per bin
+    noise: Vec<f64>,
+    noise_frames: uint,
+    // leave the noise to the VAD, and pass the audio through until seeded
+    wait_for_seed: bool
+}
+
+impl NoiseSuppressor {
+    fn new(rate: u32, wait_for_seed: bool) -> NoiseSuppressor {
+        // about 20ms frames
+        let mut size = 1u;
+        while size < rate as uint / 50 {
+            size <<= 1;
+        }
+        let hop = size / 2;
+        NoiseSuppressor {
+            size: size,
+            window: range(0, size).map(|i| (0.5 - 0.5 * (2.0 * PI * i as f64 / size as f64).cos()).sqrt()).collect(),
+            frame: Vec::from_elem(size, 0f64),
+            pending: Vec::with_capacity(hop),
+            overlap: Vec::from_elem(hop, 0f64),
+            output: range(0, hop).map(|_| 0f64).collect(),
+            noise: Vec::from_elem(size / 2 + 1, 0f64),
+            noise_frames: 0,
+            wait_for_seed: wait_for_seed
+        }
+    }
+
+    /**
+     * Start from a noise floor in dBFS, assuming white noise.
+     */
+    fn seed(&mut self, level: f64) {
+        // the squared window sums to size / 2
+        let power = 10f64.powf(level / 10.0) * (self.size / 2) as f64;
+        for n in self.noise.iter_mut() {
+            *n = power;
+        }
+        self.noise_frames = NOISE_LEARNING_FRAMES;
+        wit_log!(Debug, "noise suppression seeded with a noise floor of {}dBFS", level);
+    }
+
+    fn is_seeded(&self) -> bool {
+        self.noise_frames >= NOISE_LEARNING_FRAMES
+    }
+
+    fn process(&mut self, samples: &mut [f64], talking: bool) {
+        let hop = self.size / 2;
+        for &x in samples.iter() {
+            self.pending.push(x);
+            if self.pending.len() == hop {
+                self.process_frame(talking);
+            }
+        }
+        for s in samples.iter_mut() {
+            *s = self.output.pop_front().unwrap_or(0.0);
+        }
+    }
+
+    fn process_frame(&mut self, talking: bool) {
+        let hop = self.size / 2;
+        // slide the frame by a hop
+        for i in range(0, hop) {
+            self.frame[i] = self.frame[i + hop];
+            self.frame[i + hop] = self.pending[i];
+        }
+        self.pending.clear();
+
+        let mut re: Vec<f64> = self.frame.iter().zip(self.window.iter()).map(|(&x, &w)| x * w).collect();
+        let mut im = Vec::from_elem(self.size, 0f64);
+        fft(re.as_mut_slice(), im.as_mut_slice());
+
+        let learning = !self.is_seeded();
+        let powers: Vec<f64> = range(0, self.size / 2 + 1).map(|k| re[k] * re[k] + im[k] * im[k]).collect();
+        // without a VAD decision, speech is also told apart by its level (6dB above the noise)
+        let total = powers.iter().fold(0f64, |acc, &p| acc + p);
+        let noise_total = self.noise.iter().fold(0f64, |acc, &p| acc + p);
+        let is_noise = !talking && total < 4.0 * noise_total;
+        for k in range(0, self.size / 2 + 1) {
+            let power = powers[k];
+            if learning {
+                if !self.wait_for_seed {
+                    self.noise[k] = (self.noise[k] * self.noise_frames as f64 + power) / (self.noise_frames + 1) as f64;
+                }
+            } else if is_noise {
+                self.noise[k] = 0.9 * self.noise[k] + 0.1 * power;
+            } else if !talking {
+                // follow a louder background, slowly
+                self.noise[k] *= 1.005;
+            }
+            // the audio goes through untouched until the noise is known
+            let gain = if learning {
+                1.0
+            } else {
+                (1.0 - OVER_SUBTRACTION * self.noise[k] / (power + 1e-20)).max(SPECTRAL_FLOOR * SPECTRAL_FLOOR).sqrt()
+            };
+            re[k] *= gain;
+            im[k] *= gain;
+            // keep the spectrum conjugate-symmetric
+            if k > 0 && k < self.size / 2 {
+                re[self.size - k] = re[k];
+                im[self.size - k] = -im[k];
+            }
+        }
+        if learning && !self.wait_for_seed {
+            self.noise_frames += 1;
+        }
+
+        // inverse transform, through the forward one
+        for v in im.iter_mut() {
+            *v = -*v;
+        }
+        fft(re.as_mut_slice(), im.as_mut_slice());
+        for i in range(0, self.size) {
+            re[i] = re[i] / self.size as f64 * self.window[i];
+        }
+        for i in range(0, hop) {
+            self.output.push_back(self.overlap[i] + re[i]);
+            self.overlap[i] = re[i + hop];
+        }
+    }
+}
+
+/**
+ * Automatic gain control towards a target RMS level, followed by a limiter.
+ * The gain only changes while the input is above AGC_GATE, so that silence
+ * is not amplified.
+ */
+struct Agc {
+    target: f64,
+    envelope: f64,
+    gain: f64,
+    limiter: f64,
+    attack: f64,
+    release: f64,
+    smoothing: f64
+}
+
+impl Agc {
+    fn new(target: f64, rate: u32) -> Agc {
+        let rate = rate as f64;
+        Agc {
+            target: target,
+            envelope: 0.0,
+            gain: 1.0,
+            limiter: 1.0,
+            // 10ms attack and 500ms release of the level envelope
+            attack: 1.0 - (-1.0 / (0.01 * rate)).exp(),
+            release: 1.0 - (-1.0 / (0.5 * rate)).exp(),
+            // the gain moves within about 200ms
+            smoothing: 1.0 - (-1.0 / (0.2 * rate)).exp()
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f64]) {
+        for s in samples.iter_mut() {
+            let x = *s;
+            let coeff = if x * x > self.envelope { self.attack } else { self.release };
+            self.envelope += coeff * (x * x - self.envelope);
+            let level = 10.0 * (self.envelope + 1e-20).log10();
+            if level > AGC_GATE {
+                let wanted = (self.target - level).min(AGC_MAX_GAIN);
+                let wanted = 10f64.powf(wanted / 20.0);
+                self.gain += self.smoothing * (wanted - self.gain);
+            }
+            let y = x * self.gain;
+            // instant attack, 50ms release
+            let needed = if y.abs() * self.limiter > LIMIT { LIMIT / y.abs() } else { 1.0 };
+            self.limiter = if needed < self.limiter {
+                needed
+            } else {
+                self.limiter + (1.0 - self.limiter) * self.release * 10.0
+            };
+            *s = y * self.limiter;
+        }
+    }
+}
+
+/**
+ * The enabled stages, with their state across reads.
+ */
+pub struct Preprocessor {
+    high_pass: Option<HighPass>,
+    noise_suppressor: Option<NoiseSuppressor>,
+    agc: Option<Agc>,
+    buf: Vec<f64>
+}
+
+impl Preprocessor {
+    /**
+     * With vad_noise_floor, the noise suppression waits for the noise floor of
+     * the VAD (see process) instead of learning the noise itself.
+     */
+    pub fn new(opts: &PreprocessOptions, rate: u32, vad_noise_floor: bool) -> Preprocessor {
+        wit_log!(Debug, "preprocessing: {}", opts);
+        Preprocessor {
+            high_pass: opts.high_pass.map(|cutoff| HighPass::new(cutoff, rate)),
+            noise_suppressor: if opts.noise_suppression { Some(NoiseSuppressor::new(rate, vad_noise_floor)) } else { None },
+            agc: opts.agc.map(|target| Agc::new(target, rate)),
+            buf: Vec::new()
+        }
+    }
+
+    /**
+     * Process the samples in place. talking and noise_floor come from the VAD,
+     * when there is one: the noise is only tracked outside of speech, and the
+     * noise suppression starts from the VAD's noise floor (dBFS) once it is
+     * known, right away with a calibration. Otherwise it learns the noise
+     * during its first frames.
+     */
+    pub fn process(&mut self, samples: &mut [i16], talking: bool, noise_floor: Option<f64>) {
+        self.buf.clear();
+        self.buf.extend(samples.iter().map(|&s| s as f64 / 32768.0));
+        match self.high_pass {
+            Some(ref mut filter) => filter.process(self.buf.as_mut_slice()),
+            None => ()
+        }
+        match self.noise_suppressor {
+            Some(ref mut ns) => {
+                match noise_floor {
+                    Some(level) if !ns.is_seeded() => ns.seed(level),
+                    _ => ()
+                }
+                ns.process(self.buf.as_mut_slice(), talking)
+            }
+            None => ()
+        }
+        match self.agc {
+            Some(ref mut agc) => agc.process(self.buf.as_mut_slice()),
+            None => ()
+        }
+        for (s, &x) in samples.iter_mut().zip(self.buf.iter()) {
+            *s = (x * 32768.0).round().max(-32768.0).min(32767.0) as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+    use std::f64::consts::PI;
+    use dsp::dbfs;
+    use super::{HighPass, NoiseSuppressor, Agc, LIMIT};
+
+    const RATE: u32 = 16000;
+
+    fn tone(frequency: f64, amplitude: f64, len: uint) -> Vec<f64> {
+        range(0, len).map(|i| amplitude * (2.0 * PI * frequency * i as f64 / RATE as f64).sin()).collect()
+    }
+
+    fn noise(amplitude: f64, len: uint) -> Vec<f64> {
+        let mut seed = 0x12345678u32;
+        range(0, len).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            amplitude * ((seed as f64 / ::std::u32::MAX as f64) * 2.0 - 1.0)
+        }).collect()
+    }
+
+    #[test]
+    fn high_pass_removes_the_dc_offset() {
+        let mut filter = HighPass::new(100.0, RATE);
+        let mut samples = Vec::from_elem(RATE as uint, 0.5f64);
+        filter.process(samples.as_mut_slice());
+        assert!(samples.slice_from(8000).iter().all(|x| x.abs() < 1e-3));
+    }
+
+    #[test]
+    fn high_pass_keeps_speech_frequencies() {
+        let mut filter = HighPass::new(100.0, RATE);
+        let input = tone(1000.0, 0.5, RATE as uint);
+        let mut output = input.clone();
+        filter.process(output.as_mut_slice());
+        let change = dbfs(output.slice_from(8000)) - dbfs(input.slice_from(8000));
+        assert!(change.abs() < 0.2, "1kHz changed by {}dB", change);
+    }
+
+    #[test]
+    fn noise_suppression_attenuates_steady_noise() {
+        let mut ns = NoiseSuppressor::new(RATE, false);
+        let input = noise(0.1, 2 * RATE as uint);
+        let mut output = input.clone();
+        ns.process(output.as_mut_slice(), false);
+        let change = dbfs(output.slice_from(RATE as uint)) - dbfs(input.slice_from(RATE as uint));
+        assert!(change < -6.0, "the noise changed by {}dB", change);
+    }
+
+    #[test]
+    fn noise_suppression_keeps_a_tone_over_the_noise() {
+        let mut ns = NoiseSuppressor::new(RATE, false);
+        let mut output = noise(0.01, RATE as uint / 2);
+        let tone = tone(1000.0, 0.5, RATE as uint / 2);
+        for (x, &t) in output.iter_mut().zip(tone.iter()) {
+            *x += t;
+        }
+        let mut learning = noise(0.01, RATE as uint / 2);
+        ns.process(learning.as_mut_slice(), false);
+        ns.process(output.as_mut_slice(), true);
+        // the first frame of output is still the noise
+        let change = dbfs(output.slice_from(ns.size)) - dbfs(tone.slice_to(tone.len() - ns.size));
+        assert!(change.abs() < 1.0, "the tone changed by {}dB", change);
+    }
+
+    #[test]
+    fn waiting_for_the_vad_lets_the_audio_through() {
+        let mut ns = NoiseSuppressor::new(RATE, true);
+        let input = noise(0.1, RATE as uint);
+        let mut output = input.clone();
+        ns.process(output.as_mut_slice(), false);
+        assert!(!ns.is_seeded());
+        for i in range(0, input.len() - ns.size) {
+            assert!((output[i + ns.size] - input[i]).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn a_seed_replaces_learning() {
+        let mut ns = NoiseSuppressor::new(RATE, true);
+        let input = noise(0.1, RATE as uint);
+        ns.seed(dbfs(input.as_slice()));
+        assert!(ns.is_seeded());
+        let mut output = input.clone();
+        ns.process(output.as_mut_slice(), false);
+        let change = dbfs(output.slice_from(ns.size)) - dbfs(input.as_slice());
+        assert!(change < -6.0, "the noise changed by {}dB", change);
+    }
+
+    #[test]
+    fn agc_brings_a_quiet_input_to_the_target() {
+        let mut agc = Agc::new(-20.0, RATE);
+        // -40dBFS
+        let mut samples = tone(440.0, 0.01 * 2f64.sqrt(), 3 * RATE as uint);
+        agc.process(samples.as_mut_slice());
+        let level = dbfs(samples.slice_from(2 * RATE as uint));
+        assert!(level > -26.0 && level < -17.0, "level after the AGC: {}dBFS", level);
+    }
+
+    #[test]
+    fn agc_leaves_silence_alone() {
+        let mut agc = Agc::new(-20.0, RATE);
+        // -65dBFS, under the gate
+        let input = noise(0.001, RATE as uint);
+        let mut output = input.clone();
+        agc.process(output.as_mut_slice());
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn limiter_keeps_the_peaks_under_full_scale() {
+        let mut agc = Agc::new(-3.0, RATE);
+        let mut samples = tone(440.0, 1.0, RATE as uint);
+        agc.process(samples.as_mut_slice());
+        assert!(samples.iter().all(|x| x.abs() <= LIMIT + 1e-9));
+    }
+}
@@ -0,0 +1,740 @@
+use std::io::{mod, File, BufferedReader, IoResult, IoError};
+use std::cmp;
+use std::num::Float;
+use std::f64::consts::PI;
+use log;
+use log::LogLevel::{Error, Warn, Info, Debug};
+use client::RequestError;
+#[cfg(feature = "audio")]
+use sox::{mod, SoxFormat};
+
+/**
+ * A source of 16-bit mono audio for voice queries.
+ */
+pub trait AudioSource {
+    /**
+     * Sample rate, in Hz.
+     */
+    fn rate(&self) -> u32;
+
+    /**
+     * Read up to buf.len() samples. Returns the number of samples read,
+     * or an EndOfFile error when the source is exhausted.
+     */
+    fn read(&mut self, buf: &mut [i16]) -> IoResult<uint>;
+
+    /**
+     * Number of samples clipped so far, for the sources that count them.
+     */
+    fn clips(&self) -> u64 {
+        0
+    }
+}
+
+/**
+ * Which audio source voice queries record from.
+ */
+#[deriving(Clone)]
+pub enum AudioInput {
+    /// Capture device, through sox (None for the default device)
+    Device(Option<String>),
+    /// WAV file (or any format sox can read, when available)
+    File(Path),
+    /// Raw 16-bit mono PCM in native endianness, at the given rate, from
+    /// a file or pipe (None for stdin)
+    Raw(Option<Path>, u32),
+    /// Generated signal, at the given rate
+    Synthetic(Vec<Segment>, u32)
+}
+
+impl AudioInput {
+    pub fn describe(&self) -> String {
+        match *self {
+            AudioInput::Device(ref device) => format!("device {}", device.clone().unwrap_or("default".to_string())),
+            AudioInput::File(ref path) => format!("file {}", path.display()),
+            AudioInput::Raw(Some(ref path), rate) => format!("raw audio from {} at {}Hz", path.display(), rate),
+            AudioInput::Raw(None, rate) => format!("raw audio from stdin at {}Hz", rate),
+            AudioInput::Synthetic(ref segments, rate) => format!("synthetic signal ({} segments) at {}Hz", segments.len(), rate)
+        }
+    }
+}
+
+/**
+ * Initialize what the sources need: load libsox if libwit was built with audio support.
+ */
+#[cfg(feature = "audio")]
+pub fn init() {
+    match sox::load() {
+        Ok(()) => (),
+        Err(e) => {
+            wit_log!(Warn, "libsox not found, recording from a device is disabled: {}", e);
+            return;
+        }
+    };
+    match sox::init() {
+        Ok(version) => wit_log!(Info, "initialized sox: {}", version),
+        Err(err) => wit_log!(Error, "failed to initialize sox: {}", err)
+    };
+}
+
+#[cfg(not(feature = "audio"))]
+pub fn init() {
+    wit_log!(Info, "built without audio support, recording from a device is disabled");
+}
+
+#[cfg(feature = "audio")]
+pub fn quit() {
+    if !sox::is_available() {
+        return;
+    }
+    match sox::quit() {
+        Ok(true) => wit_log!(Debug, "released sox"),
+        Ok(false) => wit_log!(Debug, "sox is still in use"),
+        Err(err) => wit_log!(Error, "failed to release sox: {}", err)
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+pub fn quit() {
+}
+
+/**
+ * Whether the given input can be opened at all in this build/environment.
+ */
+pub fn is_available(input: &AudioInput) -> bool {
+    match *input {
+        AudioInput::Device(_) => sox_available(),
+        _ => true
+    }
+}
+
+#[cfg(feature = "audio")]
+fn sox_available() -> bool {
+    sox::is_available()
+}
+
+#[cfg(not(feature = "audio"))]
+fn sox_available() -> bool {
+    false
+}
+
+/**
+ * Capture devices that can be given to AudioInput::Device, as (name, description) pairs.
+ * On Linux, the ALSA capture devices are listed from /proc/asound/pcm.
+ */
+pub fn list_devices() -> Vec<(String, String)> {
+    let mut devices = vec![("default".to_string(), "default capture device".to_string())];
+    let pcm = match File::open(&Path::new("/proc/asound/pcm")).and_then(|mut f| f.read_to_string()) {
+        Ok(pcm) => pcm,
+        Err(_) => return devices
+    };
+    // lines look like "00-01: ID : Name : playback 1 : capture 1"
+    for line in pcm.as_slice().lines() {
+        let fields: Vec<&str> = line.split(':').map(|f| f.trim()).collect();
+        if fields.len() < 3 || !fields.iter().any(|f| f.starts_with("capture")) {
+            continue;
+        }
+        let ids: Vec<uint> = fields[0].split('-').filter_map(|id| from_str(id)).collect();
+        if ids.len() == 2 {
+            devices.push((format!("hw:{},{}", ids[0], ids[1]), fields[2].to_string()));
+        }
+    }
+    devices
+}
+
+/**
+ * Open the source for the given input.
+ */
+pub fn open(input: &AudioInput) -> Result<Box<AudioSource + Send>, RequestError> {
+    match *input {
+        AudioInput::Device(ref device) => open_device(device.clone()),
+        AudioInput::File(ref path) => open_file(path),
+        AudioInput::Raw(ref path, rate) => {
+            let reader: Box<Reader + Send> = match *path {
+                Some(ref path) => match File::open(path) {
+                    Ok(f) => box BufferedReader::new(f) as Box<Reader + Send>,
+                    Err(e) => {
+                        wit_log!(Error, "failed to open {}: {}", path.display(), e);
+                        return Err(RequestError::DeviceError);
+                    }
+                },
+                None => box io::stdin() as Box<Reader + Send>
+            };
+            Ok(box RawSource::new(reader, rate) as Box<AudioSource + Send>)
+        }
+        AudioInput::Synthetic(ref segments, rate) => {
+            Ok(box SyntheticSource::new(segments.clone(), rate) as Box<AudioSource + Send>)
+        }
+    }
+}
+
+#[cfg(feature = "audio")]
+fn open_device(device: Option<String>) -> Result<Box<AudioSource + Send>, RequestError> {
+    if !sox::is_available() {
+        wit_log!(Error, "audio is unavailable: libsox could not be loaded");
+        return Err(RequestError::AudioUnavailable);
+    }
+    let path = device.unwrap_or("default".to_string());
+    let format = SoxFormat::open_read(path.as_slice(), Some("alsa")).or_else(|| {
+        wit_log!(Info, "couldn't open input device using alsa. Trying with coreaudio...");
+        SoxFormat::open_read(path.as_slice(), Some("coreaudio"))
+    });
+    match format {
+        Some(format) => {
+            wit_log!(Info, "initialized recording device");
+            Ok(box SoxSource::new(format) as Box<AudioSource + Send>)
+        }
+        None => {
+            wit_log!(Error, "Failed to open input device");
+            Err(RequestError::DeviceError)
+        }
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+fn open_device(_: Option<String>) -> Result<Box<AudioSource + Send>, RequestError> {
+    wit_log!(Error, "audio is unavailable: libwit was built without audio support");
+    Err(RequestError::AudioUnavailable)
+}
+
+/**
+ * Open an audio file: 16-bit PCM WAV files are read directly, other formats
+ * (and other WAV encodings) through sox.
+ */
+pub fn open_file(path: &Path) -> Result<Box<AudioSource + Send>, RequestError> {
+    if path.extension_str() == Some("wav") || !sox_available() {
+        match FileSource::open(path) {
+            Ok(source) => return Ok(box source as Box<AudioSource + Send>),
+            Err(ref e) if e.kind == io::InvalidInput && sox_available() => {
+                wit_log!(Info, "{}: {}, reading it through sox", path.display(), e.desc);
+            }
+            Err(e) => {
+                wit_log!(Error, "failed to read {}: {}", path.display(), e);
+                return Err(RequestError::DeviceError);
+            }
+        }
+    }
+    open_sox_file(path)
+}
+
+#[cfg(feature = "audio")]
+fn open_sox_file(path: &Path) -> Result<Box<AudioSource + Send>, RequestError> {
+    let path_str = format!("{}", path.display());
+    match SoxFormat::open_read(path_str.as_slice(), None) {
+        Some(format) => Ok(box SoxSource::new(format) as Box<AudioSource + Send>),
+        None => {
+            wit_log!(Error, "failed to open {}", path.display());
+            Err(RequestError::DeviceError)
+        }
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+fn open_sox_file(_: &Path) -> Result<Box<AudioSource + Send>, RequestError> {
+    Err(RequestError::AudioUnavailable)
+}
+
+fn invalid_input(desc: &'static str) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+/**
+ * Audio read through sox, from a device or a file. Whatever its encoding, sox
+ * decodes it to linear samples. Only the first channel is kept.
+ */
+#[cfg(feature = "audio")]
+pub struct SoxSource {
+    format: SoxFormat,
+    buf: Vec<i32>
+}
+
+#[cfg(feature = "audio")]
+impl SoxSource {
+    pub fn new(format: SoxFormat) -> SoxSource {
+        wit_log!(Debug, "rate: {}, channels: {}, encoding: {}, bits_per_sample: {}, opposite_endian: {}",
+            format.signal().rate,
+            format.signal().channels,
+            format.encoding().encoding,
+            format.encoding().bits_per_sample,
+            format.encoding().opposite_endian);
+        SoxSource {
+            format: format,
+            buf: Vec::new()
+        }
+    }
+}
+
+#[cfg(feature = "audio")]
+impl AudioSource for SoxSource {
+    fn rate(&self) -> u32 {
+        self.format.signal().rate as u32
+    }
+
+    fn read(&mut self, buf: &mut [i16]) -> IoResult<uint> {
+        let num_channels = self.format.signal().channels as uint;
+        if self.buf.len() != buf.len() * num_channels {
+            self.buf = Vec::from_elem(buf.len() * num_channels, 0i32);
+        }
+        let num_read = self.format.read(self.buf.as_mut_slice());
+        if num_read == 0 {
+            return Err(io::standard_error(io::EndOfFile));
+        }
+        Ok(first_channel_16bit(self.buf.slice_to(num_read), num_channels, buf))
+    }
+
+    fn clips(&self) -> u64 {
+        self.format.clips()
+    }
+}
+
+/**
+ * The first channel of interleaved 32-bit samples, as 16-bit samples.
+ * Returns the number of samples written to out.
+ */
+fn first_channel_16bit(samples: &[i32], num_channels: uint, out: &mut [i16]) -> uint {
+    if num_channels == 1 {
+        // in a loop simple enough to be vectorized
+        for (sample, &s) in out.iter_mut().zip(samples.iter()) {
+            *sample = (s >> 16) as i16;
+        }
+        return cmp::min(samples.len(), out.len());
+    }
+    let mut count = 0;
+    for (frame, sample) in samples.chunks(num_channels).zip(out.iter_mut()) {
+        *sample = (frame[0] >> 16) as i16;
+        count += 1;
+    }
+    count
+}
+
+/**
+ * 16-bit PCM WAV file. Only the first channel is kept.
+ */
+pub struct FileSource {
+    reader: BufferedReader<File>,
+    rate: u32,
+    num_channels: uint,
+    remaining_bytes: u64
+}
+
+impl FileSource {
+    pub fn open(path: &Path) -> IoResult<FileSource> {
+        let mut reader = BufferedReader::new(try!(File::open(path)));
+        if try!(reader.read_exact(4)).as_slice() != b"RIFF" {
+            return Err(invalid_input("not a RIFF file"));
+        }
+        try!(reader.read_le_u32());
+        if try!(reader.read_exact(4)).as_slice() != b"WAVE" {
+            return Err(invalid_input("not a WAVE file"));
+        }
+        let mut fmt: Option<(uint, u32)> = None;
+        loop {
+            let id = try!(reader.read_exact(4));
+            let size = try!(reader.read_le_u32()) as u64;
+            let padded_size = size + size % 2;
+            if id.as_slice() == b"fmt " {
+                if size < 16 {
+                    return Err(invalid_input("fmt chunk too short"));
+                }
+                let format = try!(reader.read_le_u16());
+                let num_channels = try!(reader.read_le_u16()) as uint;
+                let rate = try!(reader.read_le_u32());
+                try!(reader.read_le_u32()); // byte rate
+                try!(reader.read_le_u16()); // block align
+                let bits = try!(reader.read_le_u16());
+                // 0xFFFE is WAVE_FORMAT_EXTENSIBLE
+                if (format != 1 && format != 0xFFFE) || bits != 16 || num_channels == 0 {
+                    return Err(invalid_input("only 16-bit PCM WAV files are supported"));
+                }
+                try!(skip(&mut reader, padded_size - 16));
+                fmt = Some((num_channels, rate));
+            } else if id.as_slice() == b"data" {
+                return match fmt {
+                    Some((num_channels, rate)) => Ok(FileSource {
+                        reader: reader,
+                        rate: rate,
+                        num_channels: num_channels,
+                        remaining_bytes: size
+                    }),
+                    None => Err(invalid_input("missing fmt chunk"))
+                };
+            } else {
+                try!(skip(&mut reader, padded_size));
+            }
+        }
+    }
+}
+
+/**
+ * Skip n bytes, without buffering more than a few KB whatever the size
+ * a chunk claims.
+ */
+fn skip<R: Reader>(reader: &mut R, n: u64) -> IoResult<()> {
+    let mut buf = [0u8, ..4096];
+    let mut remaining = n;
+    while remaining > 0 {
+        let len = cmp::min(remaining, buf.len() as u64) as uint;
+        try!(reader.read_at_least(len, buf.slice_to_mut(len)));
+        remaining -= len as u64;
+    }
+    Ok(())
+}
+
+impl AudioSource for FileSource {
+    fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    fn read(&mut self, buf: &mut [i16]) -> IoResult<uint> {
+        let frame_bytes = 2 * self.num_channels as u64;
+        let mut count = 0;
+        for sample in buf.iter_mut() {
+            if self.remaining_bytes < frame_bytes {
+                break;
+            }
+            *sample = try!(self.reader.read_le_i16());
+            for _ in range(1, self.num_channels) {
+                try!(self.reader.read_le_i16());
+            }
+            self.remaining_bytes -= frame_bytes;
+            count += 1;
+        }
+        if count == 0 && buf.len() > 0 {
+            return Err(io::standard_error(io::EndOfFile));
+        }
+        Ok(count)
+    }
+}
+
+/**
+ * Raw 16-bit mono PCM, in native endianness, from any reader (stdin, a pipe...).
+ */
+pub struct RawSource {
+    reader: Box<Reader + Send>,
+    rate: u32,
+    bytes: Vec<u8>
+}
+
+impl RawSource {
+    pub fn new(reader: Box<Reader + Send>, rate: u32) -> RawSource {
+        RawSource {
+            reader: reader,
+            rate: rate,
+            bytes: Vec::new()
+        }
+    }
+}
+
+impl AudioSource for RawSource {
+    fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    fn read(&mut self, buf: &mut [i16]) -> IoResult<uint> {
+        // keep a possible odd byte from the previous read
+        let pending = self.bytes.len() % 2;
+        let start = self.bytes.len() - pending;
+        let leftover: Vec<u8> = self.bytes.slice_from(start).to_vec();
+        self.bytes = leftover;
+        let wanted = buf.len() * 2 - self.bytes.len();
+        try!(self.reader.push(wanted, &mut self.bytes));
+        let num_samples = self.bytes.len() / 2;
+        for (sample, bytes) in buf.iter_mut().zip(self.bytes.chunks(2)).take(num_samples) {
+            let value = if cfg!(target_endian = "big") {
+                (bytes[0] as u16 << 8) | bytes[1] as u16
+            } else {
+                (bytes[1] as u16 << 8) | bytes[0] as u16
+            };
+            *sample = value as i16;
+        }
+        Ok(num_samples)
+    }
+}
+
+/**
+ * Kind of signal generated by a SyntheticSource.
+ */
+#[deriving(Clone, Show, PartialEq)]
+pub enum Signal {
+    Silence,
+    /// Sine wave: frequency in Hz, amplitude between 0 and 1
+    Tone(f64, f64),
+    /// White noise: amplitude between 0 and 1
+    Noise(f64)
+}
+
+/**
+ * A part of a synthetic signal.
+ */
+#[deriving(Clone, Show, PartialEq)]
+pub struct Segment {
+    pub signal: Signal,
+    pub duration_ms: u32
+}
+
+impl Segment {
+    pub fn silence(duration_ms: u32) -> Segment {
+        Segment { signal: Signal::Silence, duration_ms: duration_ms }
+    }
+
+    pub fn tone(frequency: f64, amplitude: f64, duration_ms: u32) -> Segment {
+        Segment { signal: Signal::Tone(frequency, amplitude), duration_ms: duration_ms }
+    }
+
+    pub fn noise(amplitude: f64, duration_ms: u32) -> Segment {
+        Segment { signal: Signal::Noise(amplitude), duration_ms: duration_ms }
+    }
+}
+
+/**
+ * Deterministic generated audio (tones, noise and silences), ending after
+ * the last segment. The noise always uses the same seed, so two sources
+ * built from the same segments produce the same samples.
+ */
+pub struct SyntheticSource {
+    segments: Vec<Segment>,
+    rate: u32,
+    segment: uint,
+    position: u64,
+    seed: u32
+}
+
+impl SyntheticSource {
+    pub fn new(segments: Vec<Segment>, rate: u32) -> SyntheticSource {
+        SyntheticSource {
+            segments: segments,
+            rate: rate,
+            segment: 0,
+            position: 0,
+            seed: 0x12345678
+        }
+    }
+
+    fn next_random(&mut self) -> f64 {
+        // xorshift32, uniform in [-1, 1]
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        (self.seed as f64 / ::std::u32::MAX as f64) * 2.0 - 1.0
+    }
+}
+
+impl AudioSource for SyntheticSource {
+    fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    fn read(&mut self, buf: &mut [i16]) -> IoResult<uint> {
+        let mut count = 0;
+        for sample in buf.iter_mut() {
+            // skip finished segments
+            while self.segment < self.segments.len() &&
+                  self.position >= self.segments[self.segment].duration_ms as u64 * self.rate as u64 / 1000 {
+                self.segment += 1;
+                self.position = 0;
+            }
+            if self.segment >= self.segments.len() {
+                break;
+            }
+            let value = match self.segments[self.segment].signal {
+                Signal::Silence => 0.0,
+                Signal::Tone(frequency, amplitude) => {
+                    let t = self.position as f64 / self.rate as f64;
+                    amplitude * (2.0 * PI * frequency * t).sin()
+                }
+                Signal::Noise(amplitude) => amplitude * self.next_random()
+            };
+            *sample = (value.max(-1.0).min(1.0) * 32767.0) as i16;
+            self.position += 1;
+            count += 1;
+        }
+        if count == 0 && buf.len() > 0 {
+            return Err(io::standard_error(io::EndOfFile));
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{mod, File, TempDir, MemReader, MemWriter};
+    use test::Bencher;
+    use super::{AudioSource, FileSource, RawSource, SyntheticSource, Segment, first_channel_16bit};
+
+    fn read_all(source: &mut AudioSource) -> Vec<i16> {
+        let mut samples = Vec::new();
+        let mut buf = Vec::from_elem(100, 0i16);
+        loop {
+            match source.read(buf.as_mut_slice()) {
+                Ok(n) => samples.push_all(buf.slice_to(n)),
+                Err(ref e) if e.kind == io::EndOfFile => return samples,
+                Err(e) => panic!("unexpected error: {}", e)
+            }
+        }
+    }
+
+    fn chunk(w: &mut MemWriter, id: &[u8], size: u32, data: &[u8]) {
+        w.write(id).unwrap();
+        w.write_le_u32(size).unwrap();
+        w.write(data).unwrap();
+    }
+
+    fn fmt(num_channels: u16, rate: u32) -> Vec<u8> {
+        let mut w = MemWriter::new();
+        w.write_le_u16(1).unwrap();
+        w.write_le_u16(num_channels).unwrap();
+        w.write_le_u32(rate).unwrap();
+        w.write_le_u32(rate * 2 * num_channels as u32).unwrap();
+        w.write_le_u16(2 * num_channels).unwrap();
+        w.write_le_u16(16).unwrap();
+        w.into_inner()
+    }
+
+    fn samples_bytes(samples: &[i16]) -> Vec<u8> {
+        let mut w = MemWriter::new();
+        for &s in samples.iter() {
+            w.write_le_i16(s).unwrap();
+        }
+        w.into_inner()
+    }
+
+    // RIFF header around the given chunks
+    fn open_wav(chunks: &[u8]) -> io::IoResult<FileSource> {
+        let dir = TempDir::new("libwit-source").unwrap();
+        let path = dir.path().join("test.wav");
+        let mut w = MemWriter::new();
+        chunk(&mut w, b"RIFF", 4 + chunks.len() as u32, b"WAVE");
+        w.write(chunks).unwrap();
+        File::create(&path).write(w.get_ref()).unwrap();
+        FileSource::open(&path)
+    }
+
+    #[test]
+    fn wav_file_with_extra_chunks() {
+        let mut w = MemWriter::new();
+        // odd-sized chunks are padded
+        chunk(&mut w, b"LIST", 5, b"abcde\0");
+        chunk(&mut w, b"fmt ", 16, fmt(1, 8000).as_slice());
+        chunk(&mut w, b"data", 8, samples_bytes(&[1, -2, 300, -32768]).as_slice());
+        let mut source = open_wav(w.get_ref()).unwrap();
+        assert_eq!(source.rate(), 8000);
+        assert_eq!(read_all(&mut source), vec![1, -2, 300, -32768]);
+    }
+
+    #[test]
+    fn wav_file_keeps_the_first_channel() {
+        let mut w = MemWriter::new();
+        chunk(&mut w, b"fmt ", 16, fmt(2, 16000).as_slice());
+        chunk(&mut w, b"data", 12, samples_bytes(&[1, 100, 2, 200, 3, 300]).as_slice());
+        let mut source = open_wav(w.get_ref()).unwrap();
+        assert_eq!(read_all(&mut source), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn short_fmt_chunk_is_rejected() {
+        let mut w = MemWriter::new();
+        chunk(&mut w, b"fmt ", 14, fmt(1, 8000).slice_to(14));
+        chunk(&mut w, b"data", 2, samples_bytes(&[1]).as_slice());
+        match open_wav(w.get_ref()) {
+            Err(e) => assert_eq!(e.kind, io::InvalidInput),
+            Ok(_) => panic!("a 14-byte fmt chunk was accepted")
+        }
+    }
+
+    #[test]
+    fn huge_unknown_chunk_fails_at_the_end_of_the_file() {
+        let mut w = MemWriter::new();
+        chunk(&mut w, b"fmt ", 16, fmt(1, 8000).as_slice());
+        chunk(&mut w, b"junk", 0xFFFFFFF0, b"only a few bytes");
+        match open_wav(w.get_ref()) {
+            Err(e) => assert_eq!(e.kind, io::EndOfFile),
+            Ok(_) => panic!("a truncated chunk was accepted")
+        }
+    }
+
+    // hands out one byte per read, like a slow pipe
+    struct ByteReader(MemReader);
+
+    impl Reader for ByteReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::IoResult<uint> {
+            let ByteReader(ref mut reader) = *self;
+            reader.read(buf.slice_to_mut(1))
+        }
+    }
+
+    fn native_bytes(samples: &[i16]) -> Vec<u8> {
+        let mut w = MemWriter::new();
+        for &s in samples.iter() {
+            if cfg!(target_endian = "big") {
+                w.write_be_i16(s).unwrap();
+            } else {
+                w.write_le_i16(s).unwrap();
+            }
+        }
+        w.into_inner()
+    }
+
+    #[test]
+    fn raw_source_reads_native_samples() {
+        let reader = MemReader::new(native_bytes(&[1, -2, 300, -32768]));
+        let mut source = RawSource::new(box reader as Box<Reader + Send>, 8000);
+        assert_eq!(source.rate(), 8000);
+        assert_eq!(read_all(&mut source), vec![1, -2, 300, -32768]);
+    }
+
+    #[test]
+    fn raw_source_keeps_samples_split_across_reads() {
+        let mut bytes = native_bytes(&[0x1234, -0x5678, 7]);
+        // a trailing odd byte is not a sample
+        bytes.push(0xAB);
+        let mut source = RawSource::new(box ByteReader(MemReader::new(bytes)) as Box<Reader + Send>, 16000);
+        assert_eq!(read_all(&mut source), vec![0x1234, -0x5678, 7]);
+    }
+
+    #[test]
+    fn synthetic_source_is_deterministic() {
+        let segments = vec![Segment::noise(0.3, 50), Segment::tone(440.0, 0.5, 50)];
+        let first = read_all(&mut SyntheticSource::new(segments.clone(), 16000));
+        let second = read_all(&mut SyntheticSource::new(segments, 16000));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn synthetic_source_segments() {
+        let segments = vec![Segment::tone(440.0, 0.5, 100), Segment::silence(50), Segment::noise(0.25, 20)];
+        let mut source = SyntheticSource::new(segments, 16000);
+        assert_eq!(source.rate(), 16000);
+        let samples = read_all(&mut source);
+        assert_eq!(samples.len(), 1600 + 800 + 320);
+        let (tone, rest) = samples.split_at(1600);
+        let (silence, noise) = rest.split_at(800);
+        let peak = tone.iter().map(|&s| (s as i32).abs()).max().unwrap();
+        assert!(peak > 16000 && peak <= 16384, "tone peak: {}", peak);
+        assert!(silence.iter().all(|&s| s == 0));
+        assert!(noise.iter().all(|&s| (s as i32).abs() <= 8192));
+        assert!(noise.iter().any(|&s| s != 0));
+    }
+
+    #[test]
+    fn sox_samples_keep_the_first_channel() {
+        let mut out = [0i16, ..3];
+        assert_eq!(first_channel_16bit(&[0x10000, -0x10000, 0x7fffffff], 1, &mut out), 3);
+        assert_eq!(out.as_slice(), [1, -1, 32767].as_slice());
+        let stereo = [0x20000, 5, -0x20000, 6, 0x30000];
+        assert_eq!(first_channel_16bit(&stereo, 2, &mut out), 3);
+        assert_eq!(out.as_slice(), [2, -2, 3].as_slice());
+    }
+
+    // the conversion of SoxSource::read, for a read of 1024 mono samples
+    #[bench]
+    fn bench_sox_conversion(b: &mut Bencher) {
+        let samples = Vec::from_elem(1024, 0x12345678i32);
+        let mut out = Vec::from_elem(1024, 0i16);
+        b.bytes = 1024 * 4;
+        b.iter(|| first_channel_16bit(samples.as_slice(), 1, out.as_mut_slice()));
+    }
+}
@@ -0,0 +1,230 @@
+use std::mem;
+use std::os;
+use std::ptr::null;
+use std::sync::{StaticMutex, MUTEX_INIT};
+use std::c_str::{CString, ToCStr};
+use std::dynamic_lib::DynamicLibrary;
+use libc::{c_char, size_t};
+use ffi::{SoxErrorT, SoxFormatT, SoxSignalInfoT, SoxEncodingInfoT};
+
+#[cfg(target_os = "macos")]
+static LIBRARY_NAMES: &'static [&'static str] = &["libsox.3.dylib", "libsox.dylib"];
+#[cfg(not(target_os = "macos"))]
+static LIBRARY_NAMES: &'static [&'static str] = &["libsox.so.3", "libsox.so.2", "libsox.so"];
+
+// libsox is loaded at runtime, so that programs that don't record
+// audio work (and link) without it. Once loaded, it is never unloaded.
+static mut LIB: *const SoxLib = 0 as *const SoxLib;
+
+// Every handle initializes sox through source::init, and every open format
+// counts as a user too, as it must be closed before sox quits: the format
+// handlers are initialized by the first user, and released by the last one.
+// LOCK protects the loading of LIB and these counters.
+static LOCK: StaticMutex = MUTEX_INIT;
+static mut USERS: uint = 0;
+static mut INITIALIZED: bool = false;
+
+struct SoxLib {
+    _lib: DynamicLibrary,
+    sox_version: unsafe extern "C" fn() -> *const c_char,
+    sox_format_init: unsafe extern "C" fn() -> SoxErrorT,
+    sox_quit: unsafe extern "C" fn() -> SoxErrorT,
+    sox_open_read: unsafe extern "C" fn(*const c_char, *const SoxSignalInfoT, *const SoxEncodingInfoT,
+                                        *const c_char) -> *const SoxFormatT,
+    sox_read: unsafe extern "C" fn(*const SoxFormatT, *mut i32, size_t) -> size_t,
+    sox_close: unsafe extern "C" fn(*const SoxFormatT) -> SoxErrorT
+}
+
+macro_rules! load_symbol(
+    ($lib:expr, $name:ident) => (
+        unsafe {mem::transmute(try!($lib.symbol::<u8>(stringify!($name))))}
+    );
+)
+
+fn load_from(path: &Path) -> Result<SoxLib, String> {
+    let lib = try!(DynamicLibrary::open(Some(path)));
+    Ok(SoxLib {
+        sox_version: load_symbol!(lib, sox_version),
+        sox_format_init: load_symbol!(lib, sox_format_init),
+        sox_quit: load_symbol!(lib, sox_quit),
+        sox_open_read: load_symbol!(lib, sox_open_read),
+        sox_read: load_symbol!(lib, sox_read),
+        sox_close: load_symbol!(lib, sox_close),
+        _lib: lib
+    })
+}
+
+/**
+ * Load libsox, if it isn't loaded yet. The WIT_SOX_LIBRARY environment variable
+ * can be used to give the path of the library; otherwise the usual names are tried.
+ */
+pub fn load() -> Result<(), String> {
+    let _guard = LOCK.lock();
+    if is_available() {
+        return Ok(());
+    }
+    let candidates = match os::getenv("WIT_SOX_LIBRARY") {
+        Some(path) => vec![path],
+        None => LIBRARY_NAMES.iter().map(|name| name.to_string()).collect()
+    };
+    let mut errors = Vec::new();
+    for candidate in candidates.iter() {
+        match load_from(&Path::new(candidate.as_slice())) {
+            Ok(lib) => {
+                unsafe {LIB = mem::transmute(box lib)};
+                return Ok(());
+            }
+            Err(e) => errors.push(e)
+        }
+    }
+    Err(errors.as_slice().connect("; "))
+}
+
+/**
+ * Whether libsox has been loaded successfully.
+ */
+pub fn is_available() -> bool {
+    unsafe {!LIB.is_null()}
+}
+
+// Callers must check is_available() first
+fn lib() -> &'static SoxLib {
+    assert!(is_available(), "libsox is not loaded");
+    unsafe {&*LIB}
+}
+
+/**
+ * Initialize the sox format handlers, unless another user already did.
+ * Returns the sox version on success. Each call must be matched by a quit.
+ */
+pub fn init() -> Result<String, SoxErrorT> {
+    let _guard = LOCK.lock();
+    unsafe {
+        USERS += 1;
+        if INITIALIZED {
+            return Ok(version());
+        }
+        match (lib().sox_format_init)() {
+            SoxErrorT::SOX_SUCCESS => {
+                INITIALIZED = true;
+                Ok(version())
+            }
+            err => Err(err)
+        }
+    }
+}
+
+/**
+ * Release the sox format handlers when the last user quits.
+ * Returns whether they were released.
+ */
+pub fn quit() -> Result<bool, SoxErrorT> {
+    release()
+}
+
+fn retain() {
+    let _guard = LOCK.lock();
+    unsafe {
+        USERS += 1;
+    }
+}
+
+fn release() -> Result<bool, SoxErrorT> {
+    let _guard = LOCK.lock();
+    unsafe {
+        if USERS == 0 {
+            return Ok(false);
+        }
+        USERS -= 1;
+        if USERS > 0 || !INITIALIZED {
+            return Ok(false);
+        }
+        INITIALIZED = false;
+        to_result((lib().sox_quit)()).map(|_| true)
+    }
+}
+
+pub fn version() -> String {
+    let version = unsafe {CString::new((lib().sox_version)(), false)};
+    version.as_str().unwrap_or("unknown").to_string()
+}
+
+fn to_result(err: SoxErrorT) -> Result<(), SoxErrorT> {
+    match err {
+        SoxErrorT::SOX_SUCCESS => Ok(()),
+        err => Err(err)
+    }
+}
+
+/**
+ * An open sox file or device. It is closed when dropped, and keeps the
+ * format handlers initialized until then.
+ */
+pub struct SoxFormat {
+    ptr: *const SoxFormatT
+}
+
+impl SoxFormat {
+    /**
+     * Open a file or device for reading. When filetype is None, sox guesses
+     * it from the path.
+     */
+    pub fn open_read(path: &str, filetype: Option<&str>) -> Option<SoxFormat> {
+        if !is_available() {
+            return None;
+        }
+        let path = path.to_c_str();
+        let filetype = filetype.map(|t| t.to_c_str());
+        let ptr = unsafe {
+            (lib().sox_open_read)(
+                path.as_ptr(),
+                null(),
+                null(),
+                filetype.as_ref().map(|t| t.as_ptr()).unwrap_or(null()))
+        };
+        SoxFormat::from_ptr(ptr)
+    }
+
+    fn from_ptr(ptr: *const SoxFormatT) -> Option<SoxFormat> {
+        if ptr.is_null() {
+            None
+        } else {
+            retain();
+            Some(SoxFormat { ptr: ptr })
+        }
+    }
+
+    fn format(&self) -> &SoxFormatT {
+        unsafe {&*self.ptr}
+    }
+
+    pub fn signal(&self) -> &SoxSignalInfoT {
+        &self.format().signal
+    }
+
+    pub fn encoding(&self) -> &SoxEncodingInfoT {
+        &self.format().encoding
+    }
+
+    /**
+     * Number of samples clipped so far.
+     */
+    pub fn clips(&self) -> u64 {
+        self.format().clips
+    }
+
+    /**
+     * Read interleaved samples into buf. Returns the number of samples read,
+     * 0 meaning end of file or error.
+     */
+    pub fn read(&mut self, buf: &mut [i32]) -> uint {
+        unsafe {(lib().sox_read)(self.ptr, buf.as_mut_ptr(), buf.len() as size_t) as uint}
+    }
+}
+
+impl Drop for SoxFormat {
+    fn drop(&mut self) {
+        unsafe {(lib().sox_close)(self.ptr)};
+        let _ = release();
+    }
+}
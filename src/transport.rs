@@ -0,0 +1,462 @@
+use std::ascii::AsciiExt;
+use std::io::{mod, IoResult};
+use std::sync::{Arc, Mutex};
+use std::collections::RingBuf;
+use hyper::client::request::Request;
+use hyper::client::response::Response;
+use hyper::Url;
+use hyper::header::Headers;
+use hyper::header::common::TransferEncoding;
+use hyper::header::common::transfer_encoding::Encoding;
+use hyper::status::StatusCode;
+use time;
+
+use client::RequestError;
+use log;
+use log::LogLevel::{Error, Debug};
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum Method {
+    Get,
+    Post
+}
+
+/**
+ * HTTP headers, as (name, value) pairs.
+ */
+pub type HttpHeaders = Vec<(String, String)>;
+
+/**
+ * A complete response from the server.
+ */
+#[deriving(Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub body: String,
+    // when the response started to arrive (time::precise_time_ns), for the
+    // transports that talk to a server
+    pub received_at: Option<u64>
+}
+
+/**
+ * How requests reach the Wit API: GET for text queries, and a POST
+ * streaming the body for speech queries.
+ */
+pub trait HttpTransport {
+    fn get(&self, url: &str, headers: &HttpHeaders) -> Result<HttpResponse, RequestError>;
+    fn post(&self, url: &str, headers: &HttpHeaders, body: &mut Reader) -> Result<HttpResponse, RequestError>;
+}
+
+/// About 130ms of 16-bit audio at 16kHz
+pub const DEFAULT_CHUNK_SIZE: uint = 4096;
+
+/**
+ * Default transport, talking to the server with hyper. Speech queries are
+ * uploaded with chunked transfer encoding, a chunk being sent as soon as
+ * chunk_size bytes of audio are available, so that the server can start
+ * recognizing while the user is still speaking.
+ */
+pub struct HyperTransport {
+    pub chunk_size: uint
+}
+
+impl HyperTransport {
+    pub fn new() -> HyperTransport {
+        HyperTransport::with_chunk_size(DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn with_chunk_size(chunk_size: uint) -> HyperTransport {
+        HyperTransport {
+            chunk_size: if chunk_size > 0 { chunk_size } else { 1 }
+        }
+    }
+}
+
+/**
+ * The url without its query string, which holds the text of the user and
+ * the context: what can go to the logs, whatever the privacy mode.
+ */
+pub fn url_for_logs(url: &str) -> &str {
+    url.split(|c: char| c == '?' || c == '#').next().unwrap_or("")
+}
+
+fn parse_url(url: &str) -> Result<Url, RequestError> {
+    Url::parse(url).map_err(|e| {
+        wit_log!(Error, "invalid url {}: {}", url_for_logs(url), e);
+        RequestError::ClientError
+    })
+}
+
+fn set_raw_headers(h: &mut Headers, headers: &HttpHeaders) {
+    for &(ref name, ref value) in headers.iter() {
+        h.set_raw(name.clone(), vec![value.as_bytes().to_vec()]);
+    }
+}
+
+/**
+ * Copy the body to w, one chunk_size write at a time (less for the last one),
+ * flushed right away. Returns the number of chunks.
+ */
+fn copy_chunks<W: Writer>(body: &mut Reader, w: &mut W, chunk_size: uint) -> IoResult<uint> {
+    let mut buf = Vec::from_elem(chunk_size, 0u8);
+    let mut chunks = 0;
+    loop {
+        let mut len = 0;
+        let mut eof = false;
+        while len < chunk_size {
+            match body.read(buf.slice_from_mut(len)) {
+                Ok(n) => len += n,
+                Err(ref e) if e.kind == io::EndOfFile => {
+                    eof = true;
+                    break;
+                }
+                Err(e) => return Err(e)
+            }
+        }
+        if len > 0 {
+            try!(w.write(buf.slice_to(len)));
+            try!(w.flush());
+            chunks += 1;
+        }
+        if eof {
+            return Ok(chunks);
+        }
+    }
+}
+
+fn read_hyper_response(response: &mut Response) -> Result<HttpResponse, RequestError> {
+    // the status line and headers are read already
+    let received_at = time::precise_time_ns();
+    match response.read_to_string() {
+        Ok(body) => Ok(HttpResponse {
+            status: response.status,
+            body: body,
+            received_at: Some(received_at)
+        }),
+        Err(e) => {
+            wit_log!(Error, "failed to read response body: {}", e);
+            Err(RequestError::InvalidResponseError)
+        }
+    }
+}
+
+impl HttpTransport for HyperTransport {
+    fn get(&self, url: &str, headers: &HttpHeaders) -> Result<HttpResponse, RequestError> {
+        let mut req = try!(Request::get(try!(parse_url(url))).map_err(|e| {
+            wit_log!(Error, "failed to create request: {}", e);
+            RequestError::ClientError
+        }));
+        set_raw_headers(req.headers_mut(), headers);
+        let streaming_req = try!(req.start().map_err(|e| {
+            wit_log!(Error, "failed to connect to server: {}", e);
+            RequestError::ClientError
+        }));
+        match streaming_req.send() {
+            Ok(mut res) => read_hyper_response(&mut res),
+            Err(_) => Err(RequestError::ClientError)
+        }
+    }
+
+    fn post(&self, url: &str, headers: &HttpHeaders, body: &mut Reader) -> Result<HttpResponse, RequestError> {
+        let mut req = try!(Request::post(try!(parse_url(url))).map_err(|e| {
+            wit_log!(Error, "failed to create request: {}", e);
+            RequestError::ClientError
+        }));
+        {
+            let h = req.headers_mut();
+            h.set(TransferEncoding(vec![Encoding::Chunked]));
+            set_raw_headers(h, headers);
+        }
+        let mut streaming_req = try!(req.start().map_err(|e| {
+            wit_log!(Error, "failed to connect to server: {}", e);
+            RequestError::ClientError
+        }));
+        match copy_chunks(body, &mut streaming_req, self.chunk_size) {
+            Ok(chunks) => wit_log!(Debug, "audio streamed in {} chunks", chunks),
+            Err(e) => {
+                // e.g. the audio buffer overflowed: the query fails, as with the other transports
+                wit_log!(Error, "failed to stream audio to server: {}", e);
+                return Err(RequestError::ClientError);
+            }
+        };
+        match streaming_req.send() {
+            Ok(mut res) => read_hyper_response(&mut res),
+            Err(_) => Err(RequestError::ClientError)
+        }
+    }
+}
+
+/**
+ * A request received by a MockTransport.
+ */
+#[deriving(Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: HttpHeaders,
+    pub body: Vec<u8>
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&&(ref n, _)| n.as_slice().eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.as_slice())
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        Url::parse(self.url.as_slice()).ok()
+            .and_then(|url| url.query_pairs())
+            .and_then(|pairs| pairs.into_iter().find(|&(ref n, _)| n.as_slice() == name))
+            .map(|(_, value)| value)
+    }
+}
+
+/**
+ * In-process transport for tests: records every request, including the
+ * uploaded audio, and answers with scripted responses, in order.
+ * Clones share the same requests and responses.
+ */
+#[deriving(Clone)]
+pub struct MockTransport {
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    responses: Arc<Mutex<RingBuf<HttpResponse>>>
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport {
+            requests: Arc::new(Mutex::new(Vec::new())),
+            responses: Arc::new(Mutex::new(RingBuf::new()))
+        }
+    }
+
+    /**
+     * Queue the response to the next request.
+     */
+    pub fn push_response(&self, status: StatusCode, body: &str) {
+        self.responses.lock().push_back(HttpResponse {
+            status: status,
+            body: body.to_string(),
+            received_at: None
+        });
+    }
+
+    /**
+     * All the requests received so far.
+     */
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().clone()
+    }
+
+    fn respond(&self, request: RecordedRequest) -> Result<HttpResponse, RequestError> {
+        wit_log!(Debug, "mock transport: {} {}", request.method, url_for_logs(request.url.as_slice()));
+        self.requests.lock().push(request);
+        match self.responses.lock().pop_front() {
+            Some(response) => Ok(response),
+            None => {
+                wit_log!(Error, "mock transport: no response left");
+                Err(RequestError::ClientError)
+            }
+        }
+    }
+}
+
+impl HttpTransport for MockTransport {
+    fn get(&self, url: &str, headers: &HttpHeaders) -> Result<HttpResponse, RequestError> {
+        self.respond(RecordedRequest {
+            method: Method::Get,
+            url: url.to_string(),
+            headers: headers.clone(),
+            body: Vec::new()
+        })
+    }
+
+    fn post(&self, url: &str, headers: &HttpHeaders, body: &mut Reader) -> Result<HttpResponse, RequestError> {
+        let bytes = match body.read_to_end() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                wit_log!(Error, "mock transport: failed to read request body: {}", e);
+                return Err(RequestError::ClientError);
+            }
+        };
+        self.respond(RecordedRequest {
+            method: Method::Post,
+            url: url.to_string(),
+            headers: headers.clone(),
+            body: bytes
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{mod, IoResult, IoError, MemReader, BufferedReader, Acceptor, Listener};
+    use std::io::net::tcp::TcpListener;
+    use std::io::timer;
+    use std::num;
+    use std::time::Duration;
+    use hyper::status::StatusCode;
+    use time;
+    use source::{AudioSource, SyntheticSource, Segment};
+    use super::{copy_chunks, HttpTransport, HyperTransport};
+
+    /**
+     * Records the size of each write, and the flushes.
+     */
+    struct ChunkWriter {
+        writes: Vec<uint>,
+        flushes: uint
+    }
+
+    impl Writer for ChunkWriter {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            self.writes.push(buf.len());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> IoResult<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    /**
+     * Hands out a few bytes per read, then fails if asked to.
+     */
+    struct TrickleReader {
+        inner: MemReader,
+        step: uint,
+        fail: bool
+    }
+
+    impl Reader for TrickleReader {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            let len = ::std::cmp::min(self.step, buf.len());
+            match self.inner.read(buf.slice_to_mut(len)) {
+                Err(ref e) if e.kind == io::EndOfFile && self.fail => Err(IoError {
+                    kind: io::BrokenPipe,
+                    desc: "audio buffer overflow",
+                    detail: None
+                }),
+                r => r
+            }
+        }
+    }
+
+    fn copy(len: uint, step: uint, chunk_size: uint) -> (IoResult<uint>, ChunkWriter) {
+        let mut body = TrickleReader { inner: MemReader::new(Vec::from_elem(len, 1u8)), step: step, fail: false };
+        let mut w = ChunkWriter { writes: Vec::new(), flushes: 0 };
+        let result = copy_chunks(&mut body, &mut w, chunk_size);
+        (result, w)
+    }
+
+    #[test]
+    fn chunks_are_full_but_the_last() {
+        let (result, w) = copy(10, 3, 4);
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(w.writes, vec![4, 4, 2]);
+        assert_eq!(w.flushes, 3);
+    }
+
+    #[test]
+    fn no_empty_chunk_at_the_end() {
+        let (result, w) = copy(8, 8, 4);
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(w.writes, vec![4, 4]);
+        let (result, w) = copy(0, 8, 4);
+        assert_eq!(result.unwrap(), 0);
+        assert!(w.writes.is_empty());
+    }
+
+    #[test]
+    fn read_errors_stop_the_copy() {
+        let mut body = TrickleReader { inner: MemReader::new(vec![1, 2, 3, 4, 5, 6]), step: 6, fail: true };
+        let mut w = ChunkWriter { writes: Vec::new(), flushes: 0 };
+        assert_eq!(copy_chunks(&mut body, &mut w, 4).unwrap_err().kind, io::BrokenPipe);
+        // what was read before the error was sent
+        assert_eq!(w.writes, vec![4]);
+    }
+
+    /**
+     * 16-bit audio from a synthetic source, 20ms of it per read, as fast as
+     * a device would record it.
+     */
+    struct SlowSource {
+        source: SyntheticSource,
+        samples: Vec<i16>
+    }
+
+    impl Reader for SlowSource {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            timer::sleep(Duration::milliseconds(20));
+            let len = ::std::cmp::min(self.samples.len(), buf.len() / 2);
+            let n = try!(self.source.read(self.samples.slice_to_mut(len)));
+            for (i, &sample) in self.samples.slice_to(n).iter().enumerate() {
+                buf[2 * i] = sample as u8;
+                buf[2 * i + 1] = (sample as u16 >> 8) as u8;
+            }
+            Ok(n * 2)
+        }
+    }
+
+    /**
+     * Accepts one chunked POST, and sends back the time and size of each
+     * chunk as it arrived.
+     */
+    fn serve_one(listener: TcpListener, tx: Sender<Vec<(u64, uint)>>) {
+        let mut acceptor = listener.listen().unwrap();
+        let stream = acceptor.accept().unwrap();
+        let mut out = stream.clone();
+        let mut reader = BufferedReader::new(stream);
+        // the headers
+        loop {
+            let line = reader.read_line().unwrap();
+            if line.as_slice().trim().is_empty() {
+                break;
+            }
+        }
+        let mut chunks = Vec::new();
+        loop {
+            let line = reader.read_line().unwrap();
+            let size: uint = num::from_str_radix(line.as_slice().trim(), 16).unwrap();
+            if size == 0 {
+                let _ = reader.read_line();
+                break;
+            }
+            reader.read_exact(size).unwrap();
+            chunks.push((time::precise_time_ns(), size));
+            // the CRLF after the chunk
+            reader.read_line().unwrap();
+        }
+        let body = r#"{"outcomes": []}"#;
+        write!(&mut out, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+        tx.send(chunks);
+    }
+
+    #[test]
+    fn hyper_uploads_audio_as_it_is_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let (tx, rx) = channel();
+        spawn(proc() serve_one(listener, tx));
+
+        // 200ms of audio, in 40ms chunks
+        let mut body = SlowSource {
+            source: SyntheticSource::new(vec![Segment::tone(440.0, 0.5, 200)], 16000),
+            samples: Vec::from_elem(320, 0i16)
+        };
+        let transport = HyperTransport::with_chunk_size(1280);
+        let url = format!("http://127.0.0.1:{}/speech", port);
+        let headers = vec![("Content-Type".to_string(), "audio/raw".to_string())];
+        let response = transport.post(url.as_slice(), &headers, &mut body).unwrap();
+        assert_eq!(response.status, StatusCode::Ok);
+
+        let chunks = rx.recv();
+        assert_eq!(chunks.iter().map(|&(_, size)| size).collect::<Vec<uint>>(), vec![1280, 1280, 1280, 1280, 1280]);
+        // spread over the recording, not all at once at the end
+        let (first, _) = chunks[0];
+        let (last, _) = chunks[chunks.len() - 1];
+        assert!(last - first >= 100 * 1000000, "chunks within {}ms", (last - first) / 1000000);
+    }
+}
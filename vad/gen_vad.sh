@@ -3,7 +3,9 @@ set -x
 set -e
 cd vad
 autoreconf -vfi
-./configure --host=$TARGET
+# cross toolchains are usually named without the vendor, e.g. arm-linux-gnueabihf-gcc
+HOST=${VAD_HOST:-$(echo $TARGET | sed 's/-unknown-/-/')}
+./configure --host=$HOST
 make clean
 make
 mv libvad.a $OUT_DIR
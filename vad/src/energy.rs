@@ -0,0 +1,103 @@
+use libc::c_void;
+
+use {VoiceDetector, DetectorConfig, NoiseProfile};
+use {wvs_init_with, wvs_still_talking, wvs_talking, wvs_frames, wvs_energy, wvs_min_energy, wvs_set_min_energy, wvs_clean};
+
+/**
+ * The energy versus noise floor detector of vad.c.
+ */
+pub struct EnergyDetector {
+    state: *const c_void
+}
+
+impl EnergyDetector {
+    pub fn new(config: &DetectorConfig, rate: u32) -> EnergyDetector {
+        EnergyDetector {
+            state: unsafe {
+                wvs_init_with(config.threshold, rate as i32, config.init_frames as i32, config.end_frames as i32)
+            }
+        }
+    }
+}
+
+impl VoiceDetector for EnergyDetector {
+    fn still_talking(&mut self, samples: &[i16]) -> bool {
+        unsafe {wvs_still_talking(self.state, samples.as_ptr(), samples.len() as i32) != 0}
+    }
+
+    fn talking(&self) -> bool {
+        unsafe {wvs_talking(self.state) != 0}
+    }
+
+    fn frames(&self) -> uint {
+        unsafe {wvs_frames(self.state) as uint}
+    }
+
+    fn levels(&self) -> (f64, f64) {
+        // vad.c keeps positive attenuations
+        unsafe {(-wvs_energy(self.state), -wvs_min_energy(self.state))}
+    }
+
+    fn noise_profile(&self) -> NoiseProfile {
+        NoiseProfile {
+            noise_floor: unsafe {-wvs_min_energy(self.state)},
+            bands: Vec::new()
+        }
+    }
+
+    fn set_noise_profile(&mut self, profile: &NoiseProfile) -> bool {
+        unsafe {wvs_set_min_energy(self.state, -profile.noise_floor)};
+        true
+    }
+}
+
+impl Drop for EnergyDetector {
+    fn drop(&mut self) {
+        unsafe {wvs_clean(self.state)};
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testing;
+    use {VoiceDetector, DetectorConfig, Algorithm, NoiseProfile};
+    use super::EnergyDetector;
+
+    fn detector() -> EnergyDetector {
+        EnergyDetector::new(&DetectorConfig::new(Algorithm::Energy), testing::RATE)
+    }
+
+    #[test]
+    fn speech_ends_after_the_tone() {
+        let signal = testing::concat(vec![testing::noise(0.01, 300), testing::tone(440.0, 0.5, 1000), testing::noise(0.01, 1000)]);
+        let (end, talked) = testing::end_of_speech(&mut detector(), signal.as_slice());
+        assert!(talked);
+        // the tone ends at 1300ms, then 30 frames of silence
+        let end = end.expect("no end of speech");
+        assert!(end >= 1600 && end <= 1700, "end of speech at {}ms", end);
+    }
+
+    #[test]
+    fn noise_alone_is_not_speech() {
+        let signal = testing::noise(0.05, 2000);
+        assert_eq!(testing::end_of_speech(&mut detector(), signal.as_slice()), (None, false));
+    }
+
+    #[test]
+    fn digital_silence_is_skipped() {
+        // vad.c ignores exact zeros, whose attenuation is infinite
+        let mut detector = detector();
+        assert!(detector.still_talking(testing::silence(1000).as_slice()));
+        assert_eq!(detector.frames(), 0);
+    }
+
+    #[test]
+    fn a_noise_profile_replaces_learning() {
+        let mut detector = detector();
+        assert!(detector.set_noise_profile(&NoiseProfile { noise_floor: -48.0, bands: Vec::new() }));
+        // 10 speech frames, and the one that completes them
+        assert!(detector.still_talking(testing::tone(440.0, 0.5, 120).as_slice()));
+        assert!(detector.talking());
+        assert_eq!(detector.noise_profile().noise_floor, -48.0);
+    }
+}
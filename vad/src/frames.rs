@@ -0,0 +1,226 @@
+use {VoiceDetector, NoiseProfile};
+
+/// Consecutive speech frames needed to start talking, as in vad.c
+const START_FRAMES: uint = 10;
+
+/**
+ * Update a running average, giving the new value a weight of 1/(n+1),
+ * with n capped at 10 (a tenth of a second of 10ms frames).
+ */
+pub fn running_average(average: f64, value: f64, n: uint) -> f64 {
+    let n = if n > 10 { 10 } else { n } as f64;
+    (average * n + value) / (n + 1.0)
+}
+
+/**
+ * Cuts the incoming samples into fixed-size frames.
+ */
+pub struct Framer {
+    frame: Vec<f64>,
+    len: uint
+}
+
+impl Framer {
+    pub fn new(len: uint) -> Framer {
+        Framer {
+            frame: Vec::with_capacity(len),
+            len: len
+        }
+    }
+
+    /**
+     * Take samples from samples[*pos..] until the frame is full.
+     * Returns true when a complete frame is available through frame().
+     */
+    pub fn fill(&mut self, samples: &[i16], pos: &mut uint) -> bool {
+        if self.frame.len() == self.len {
+            self.frame.clear();
+        }
+        while *pos < samples.len() && self.frame.len() < self.len {
+            self.frame.push(samples[*pos] as f64 / 32768.0);
+            *pos += 1;
+        }
+        self.frame.len() == self.len
+    }
+
+    pub fn frame(&self) -> &[f64] {
+        self.frame.as_slice()
+    }
+}
+
+/**
+ * Turns per-frame decisions into the start and end of speech, like vad.c:
+ * speech starts after START_FRAMES speech frames in a row, and ends after
+ * end_frames non-speech frames in a row.
+ */
+pub struct Endpointer {
+    // most recent first
+    history: Vec<bool>,
+    talking: bool,
+    frames: uint,
+    init_frames: uint
+}
+
+impl Endpointer {
+    pub fn new(init_frames: uint, end_frames: uint) -> Endpointer {
+        let len = if end_frames < START_FRAMES { START_FRAMES } else { end_frames };
+        Endpointer {
+            history: Vec::from_elem(len, false),
+            talking: false,
+            frames: 0,
+            init_frames: init_frames
+        }
+    }
+
+    /**
+     * Whether the noise is still being learned.
+     */
+    pub fn learning(&self) -> bool {
+        self.frames < self.init_frames
+    }
+
+    /**
+     * Stop learning the noise, e.g. because it is already known.
+     */
+    pub fn skip_learning(&mut self) {
+        self.init_frames = self.frames;
+    }
+
+    /**
+     * Add the decision for a frame. Returns false at the end of speech.
+     */
+    pub fn push(&mut self, speech: bool) -> bool {
+        self.history.pop();
+        self.history.insert(0, speech);
+        self.frames += 1;
+        if self.frames <= self.init_frames {
+            return true;
+        }
+        if !self.talking && self.history.iter().take(START_FRAMES).all(|&s| s) {
+            self.talking = true;
+        } else if self.talking && self.history.iter().all(|&s| !s) {
+            self.talking = false;
+            return false;
+        }
+        true
+    }
+}
+
+/**
+ * Per-frame speech/non-speech decision, used with a Framer and an Endpointer
+ * by FramedDetector.
+ */
+pub trait FrameClassifier {
+    /**
+     * Classify a frame. While learning, the frame is known to be noise.
+     */
+    fn is_speech(&mut self, frame: &[f64], learning: bool, talking: bool) -> bool;
+
+    /**
+     * Level of the last frame and noise floor, in dBFS.
+     */
+    fn levels(&self) -> (f64, f64);
+
+    fn noise_profile(&self) -> NoiseProfile;
+
+    /**
+     * Use a known noise estimate instead of learning it. Returns false if it
+     * doesn't fit.
+     */
+    fn set_noise_profile(&mut self, profile: &NoiseProfile) -> bool;
+}
+
+pub struct FramedDetector<C> {
+    framer: Framer,
+    endpointer: Endpointer,
+    classifier: C
+}
+
+impl<C: FrameClassifier> FramedDetector<C> {
+    pub fn new(classifier: C, frame_len: uint, init_frames: uint, end_frames: uint) -> FramedDetector<C> {
+        FramedDetector {
+            framer: Framer::new(frame_len),
+            endpointer: Endpointer::new(init_frames, end_frames),
+            classifier: classifier
+        }
+    }
+}
+
+impl<C: FrameClassifier> VoiceDetector for FramedDetector<C> {
+    fn still_talking(&mut self, samples: &[i16]) -> bool {
+        let mut pos = 0;
+        let mut still_talking = true;
+        while self.framer.fill(samples, &mut pos) {
+            let learning = self.endpointer.learning();
+            let speech = self.classifier.is_speech(self.framer.frame(), learning, self.endpointer.talking);
+            if !self.endpointer.push(speech) {
+                still_talking = false;
+            }
+        }
+        still_talking
+    }
+
+    fn talking(&self) -> bool {
+        self.endpointer.talking
+    }
+
+    fn frames(&self) -> uint {
+        self.endpointer.frames
+    }
+
+    fn levels(&self) -> (f64, f64) {
+        self.classifier.levels()
+    }
+
+    fn noise_profile(&self) -> NoiseProfile {
+        self.classifier.noise_profile()
+    }
+
+    fn set_noise_profile(&mut self, profile: &NoiseProfile) -> bool {
+        let ok = self.classifier.set_noise_profile(profile);
+        if ok {
+            self.endpointer.skip_learning();
+        }
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Framer, Endpointer};
+
+    #[test]
+    fn frames_span_several_calls() {
+        let mut framer = Framer::new(4);
+        let mut pos = 0;
+        assert!(!framer.fill([1i16, 2, 3].as_slice(), &mut pos));
+        assert_eq!(pos, 3);
+        pos = 0;
+        assert!(framer.fill([4i16, 5, 6].as_slice(), &mut pos));
+        assert_eq!(pos, 1);
+        assert_eq!(framer.frame(), [1.0 / 32768.0, 2.0 / 32768.0, 3.0 / 32768.0, 4.0 / 32768.0].as_slice());
+        assert!(!framer.fill([4i16, 5, 6].as_slice(), &mut pos));
+        assert_eq!(framer.frame().len(), 2);
+    }
+
+    #[test]
+    fn speech_starts_and_ends() {
+        let mut endpointer = Endpointer::new(3, 12);
+        for _ in range(0, 3u) {
+            assert!(endpointer.learning());
+            assert!(endpointer.push(true));
+        }
+        assert!(!endpointer.learning());
+        for i in range(0, 10u) {
+            assert!(!endpointer.talking, "talking after {} speech frames", i);
+            assert!(endpointer.push(true));
+        }
+        assert!(endpointer.talking);
+        for _ in range(0, 11u) {
+            assert!(endpointer.push(false));
+        }
+        // the end of speech is end_frames non-speech frames in a row
+        assert!(!endpointer.push(false));
+        assert!(!endpointer.talking);
+    }
+}
@@ -0,0 +1,262 @@
+use std::num::Float;
+use std::f64::consts::PI;
+
+use dsp::{fft, dbfs};
+use frames::{running_average, FrameClassifier};
+use NoiseProfile;
+
+/// Sub-bands of the features, in Hz, as in WebRTC's VAD
+const BANDS: [(f64, f64), ..6] = [(80.0, 250.0), (250.0, 500.0), (500.0, 1000.0),
+                                   (1000.0, 2000.0), (2000.0, 3000.0), (3000.0, 4000.0)];
+/// Learning rates of the noise and speech models
+const NOISE_RATE: f64 = 0.05;
+const SPEECH_RATE: f64 = 0.02;
+/// Minimum distance between the speech and noise means, in dB
+const MIN_GAP: f64 = 6.0;
+const MIN_VARIANCE: f64 = 1.0;
+
+/**
+ * Two-component Gaussian mixture over the log energy of one band.
+ */
+#[deriving(Clone)]
+struct Mixture {
+    weights: [f64, ..2],
+    means: [f64, ..2],
+    variances: [f64, ..2]
+}
+
+impl Mixture {
+    fn new(mean: f64, spread: f64, variance: f64) -> Mixture {
+        Mixture {
+            weights: [0.5, 0.5],
+            means: [mean, mean + spread],
+            variances: [variance, variance]
+        }
+    }
+
+    fn densities(&self, x: f64) -> [f64, ..2] {
+        let mut d = [0f64, ..2];
+        for k in range(0, 2) {
+            let diff = x - self.means[k];
+            d[k] = self.weights[k] * (-diff * diff / (2.0 * self.variances[k])).exp() / (2.0 * PI * self.variances[k]).sqrt();
+        }
+        d
+    }
+
+    fn log_likelihood(&self, x: f64) -> f64 {
+        let d = self.densities(x);
+        (d[0] + d[1] + 1e-300).ln()
+    }
+
+    /**
+     * Online EM step towards x.
+     */
+    fn update(&mut self, x: f64, rate: f64) {
+        let d = self.densities(x);
+        let total = d[0] + d[1] + 1e-300;
+        for k in range(0, 2) {
+            let r = d[k] / total;
+            let diff = x - self.means[k];
+            self.means[k] += rate * r * diff;
+            self.variances[k] += rate * r * (diff * diff - self.variances[k]);
+            if self.variances[k] < MIN_VARIANCE {
+                self.variances[k] = MIN_VARIANCE;
+            }
+            self.weights[k] += rate * (r - self.weights[k]);
+        }
+    }
+
+    fn max_mean(&self) -> f64 {
+        self.means[0].max(self.means[1])
+    }
+
+    fn min_mean(&self) -> f64 {
+        self.means[0].min(self.means[1])
+    }
+
+    fn mean(&self) -> f64 {
+        (self.weights[0] * self.means[0] + self.weights[1] * self.means[1]) / (self.weights[0] + self.weights[1])
+    }
+}
+
+/**
+ * Speech and noise models over sub-band log energies, in the style of WebRTC's VAD.
+ * A frame is speech if the log-likelihood ratio of one band is above half the
+ * threshold, or if the sum over the bands is above the threshold. The noise
+ * model is learned during the first frames, then adapted on non-speech frames.
+ */
+pub struct GmmClassifier {
+    threshold: f64,
+    rate: u32,
+    fft_size: uint,
+    speech: Vec<Mixture>,
+    noise: Vec<Mixture>,
+    // average band energies while learning
+    initial: Vec<f64>,
+    learned_frames: uint,
+    energy: f64,
+    noise_floor: f64
+}
+
+impl GmmClassifier {
+    pub fn new(threshold: f64, rate: u32, frame_len: uint) -> GmmClassifier {
+        GmmClassifier {
+            threshold: threshold,
+            rate: rate,
+            fft_size: fft::fft_size(frame_len),
+            speech: Vec::new(),
+            noise: Vec::new(),
+            initial: Vec::from_elem(BANDS.len(), 0f64),
+            learned_frames: 0,
+            energy: -100.0,
+            noise_floor: -100.0
+        }
+    }
+
+    fn band_energies(&self, frame: &[f64]) -> Vec<f64> {
+        let spectrum = fft::power_spectrum(frame, self.fft_size);
+        let bin_hz = self.rate as f64 / self.fft_size as f64;
+        BANDS.iter().map(|&(low, high)| {
+            let first = (low / bin_hz).ceil() as uint;
+            let last = (high / bin_hz) as uint;
+            let power = if first < spectrum.len() && first <= last {
+                let last = if last >= spectrum.len() { spectrum.len() - 1 } else { last };
+                spectrum.slice(first, last + 1).iter().fold(0f64, |acc, &p| acc + p)
+            } else {
+                // band above the Nyquist frequency
+                0.0
+            };
+            10.0 * (power + 1e-10).log10()
+        }).collect()
+    }
+
+    fn init_models(&mut self) {
+        self.noise = self.initial.iter().map(|&m| Mixture::new(m - 2.0, 4.0, 9.0)).collect();
+        self.speech = self.initial.iter().map(|&m| Mixture::new(m + 10.0, 10.0, 36.0)).collect();
+    }
+}
+
+impl FrameClassifier for GmmClassifier {
+    fn is_speech(&mut self, frame: &[f64], learning: bool, talking: bool) -> bool {
+        let energy = dbfs(frame);
+        self.energy = energy;
+        let features = self.band_energies(frame);
+        if learning {
+            for (avg, &x) in self.initial.iter_mut().zip(features.iter()) {
+                *avg = running_average(*avg, x, self.learned_frames);
+            }
+            self.noise_floor = running_average(self.noise_floor, energy, self.learned_frames);
+            self.learned_frames += 1;
+            return false;
+        }
+        if self.noise.is_empty() {
+            self.init_models();
+        }
+        let mut total = 0f64;
+        let mut local = false;
+        for (b, &x) in features.iter().enumerate() {
+            let llr = self.speech[b].log_likelihood(x) - self.noise[b].log_likelihood(x);
+            total += llr;
+            if llr > self.threshold / 2.0 {
+                local = true;
+            }
+        }
+        let speech = local || total > self.threshold;
+        for (b, &x) in features.iter().enumerate() {
+            if speech {
+                self.speech[b].update(x, SPEECH_RATE);
+            } else if !talking {
+                self.noise[b].update(x, NOISE_RATE);
+            }
+            // keep the speech model above the noise model
+            let gap = self.speech[b].min_mean() - self.noise[b].max_mean();
+            if gap < MIN_GAP {
+                for mean in self.speech[b].means.iter_mut() {
+                    *mean += MIN_GAP - gap;
+                }
+            }
+        }
+        if !speech && !talking {
+            self.noise_floor = running_average(self.noise_floor, energy, 10);
+        }
+        speech
+    }
+
+    fn levels(&self) -> (f64, f64) {
+        (self.energy, self.noise_floor)
+    }
+
+    fn noise_profile(&self) -> NoiseProfile {
+        NoiseProfile {
+            noise_floor: self.noise_floor,
+            // the adapted noise model if there is one, else what was learned
+            bands: if self.noise.is_empty() {
+                self.initial.clone()
+            } else {
+                self.noise.iter().map(|m| m.mean()).collect()
+            }
+        }
+    }
+
+    fn set_noise_profile(&mut self, profile: &NoiseProfile) -> bool {
+        if profile.bands.len() != BANDS.len() {
+            return false;
+        }
+        self.initial = profile.bands.clone();
+        self.noise_floor = profile.noise_floor;
+        self.learned_frames = 10;
+        self.init_models();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+    use testing;
+    use frames::FrameClassifier;
+    use {new_detector, DetectorConfig, Algorithm};
+    use super::{GmmClassifier, BANDS};
+
+    fn learned() -> GmmClassifier {
+        let mut classifier = GmmClassifier::new(6.0, testing::RATE, 160);
+        let noise = testing::to_f64(testing::noise(0.01, 300).as_slice());
+        for frame in noise.chunks(160) {
+            assert!(!classifier.is_speech(frame, true, false));
+        }
+        classifier
+    }
+
+    #[test]
+    fn tone_is_speech_and_background_noise_is_not() {
+        let mut classifier = learned();
+        let quiet = testing::to_f64(testing::noise(0.01, 10).as_slice());
+        assert!(!classifier.is_speech(quiet.as_slice(), false, false));
+        let tone = testing::to_f64(testing::tone(440.0, 0.5, 10).as_slice());
+        assert!(classifier.is_speech(tone.as_slice(), false, false));
+    }
+
+    #[test]
+    fn the_noise_profile_has_the_band_energies() {
+        let classifier = learned();
+        let profile = classifier.noise_profile();
+        assert_eq!(profile.bands.len(), BANDS.len());
+        let mut other = GmmClassifier::new(6.0, testing::RATE, 160);
+        assert!(other.set_noise_profile(&profile));
+        let restored = other.noise_profile();
+        assert_eq!(restored.noise_floor, profile.noise_floor);
+        for (a, b) in restored.bands.iter().zip(profile.bands.iter()) {
+            assert!((*a - *b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn speech_ends_after_the_tone() {
+        let mut detector = new_detector(&DetectorConfig::new(Algorithm::Gmm), testing::RATE);
+        let signal = testing::concat(vec![testing::noise(0.01, 300), testing::tone(440.0, 0.5, 1000), testing::noise(0.01, 1000)]);
+        let (end, talked) = testing::end_of_speech(&mut *detector, signal.as_slice());
+        assert!(talked);
+        let end = end.expect("no end of speech");
+        assert!(end >= 1600 && end <= 1800, "end of speech at {}ms", end);
+    }
+}
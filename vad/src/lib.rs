@@ -1,9 +1,139 @@
 extern crate libc;
+extern crate dsp;
 
 use libc::{c_int, c_double, c_void};
 
+pub use energy::EnergyDetector;
+pub use spectral::SpectralClassifier;
+pub use gmm::GmmClassifier;
+pub use frames::{FrameClassifier, FramedDetector};
+pub use dsp::dbfs;
+
+mod frames;
+mod energy;
+mod spectral;
+mod gmm;
+#[cfg(test)]
+mod testing;
+
 extern {
     pub fn wvs_still_talking(state: *const c_void, samples: *const i16, nb_samples: c_int) -> c_int;
     pub fn wvs_init(threshold: c_double, sample_rate: c_int) -> *const c_void;
+    pub fn wvs_init_with(threshold: c_double, sample_rate: c_int, init_frames: c_int, end_frames: c_int) -> *const c_void;
+    pub fn wvs_frames(state: *const c_void) -> c_int;
+    pub fn wvs_energy(state: *const c_void) -> c_double;
+    pub fn wvs_min_energy(state: *const c_void) -> c_double;
+    pub fn wvs_set_min_energy(state: *const c_void, min_energy: c_double);
+    pub fn wvs_talking(state: *const c_void) -> c_int;
     pub fn wvs_clean(state: *const c_void);
 }
+
+/**
+ * End-of-speech detection over a stream of 16-bit mono samples.
+ */
+pub trait VoiceDetector {
+    /**
+     * Process the samples. Returns false when the end of speech is detected,
+     * like wvs_still_talking.
+     */
+    fn still_talking(&mut self, samples: &[i16]) -> bool;
+
+    /**
+     * Whether speech is currently detected.
+     */
+    fn talking(&self) -> bool;
+
+    /**
+     * Number of frames processed so far.
+     */
+    fn frames(&self) -> uint;
+
+    /**
+     * Level of the last frame and estimated noise floor, in dBFS. The energy
+     * detector gives the opposite of vad.c's average attenuation of the
+     * samples instead, which is a few dB below the dBFS of the same frame.
+     */
+    fn levels(&self) -> (f64, f64);
+
+    /**
+     * The noise estimate so far, to start later sessions from.
+     */
+    fn noise_profile(&self) -> NoiseProfile;
+
+    /**
+     * Start from a noise estimate of an earlier session, before any samples are
+     * processed: the init_frames learning frames are skipped. Returns false if
+     * the profile doesn't fit this detector, which then learns the noise as usual.
+     */
+    fn set_noise_profile(&mut self, profile: &NoiseProfile) -> bool;
+}
+
+/**
+ * What a detector learned about the background noise. The noise floor is in
+ * the detector's own unit (see levels); the band energies are only used by Gmm.
+ * Profiles are not interchangeable between algorithms.
+ */
+#[deriving(Clone, Show, PartialEq)]
+pub struct NoiseProfile {
+    pub noise_floor: f64,
+    pub bands: Vec<f64>
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum Algorithm {
+    /// Energy versus noise floor (vad.c)
+    Energy,
+    /// Energy, zero-crossing rate and spectral flatness
+    Spectral,
+    /// Gaussian mixtures of speech and noise over sub-band energies
+    Gmm
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "energy" => Some(Algorithm::Energy),
+            "spectral" => Some(Algorithm::Spectral),
+            "gmm" => Some(Algorithm::Gmm),
+            _ => None
+        }
+    }
+}
+
+/**
+ * The threshold is in dB above the noise floor for Energy and Spectral, and
+ * a log-likelihood ratio for Gmm. Frames are 10ms long.
+ */
+#[deriving(Clone, Show)]
+pub struct DetectorConfig {
+    pub algorithm: Algorithm,
+    pub threshold: f64,
+    pub init_frames: uint,
+    pub end_frames: uint
+}
+
+impl DetectorConfig {
+    pub fn new(algorithm: Algorithm) -> DetectorConfig {
+        DetectorConfig {
+            threshold: match algorithm {
+                Algorithm::Energy => 8.0,
+                Algorithm::Spectral => 8.0,
+                Algorithm::Gmm => 6.0
+            },
+            algorithm: algorithm,
+            init_frames: 30,
+            end_frames: 30
+        }
+    }
+}
+
+pub fn new_detector(config: &DetectorConfig, rate: u32) -> Box<VoiceDetector + Send> {
+    let frame_len = if rate >= 100 { rate as uint / 100 } else { 1 };
+    match config.algorithm {
+        Algorithm::Energy => box EnergyDetector::new(config, rate) as Box<VoiceDetector + Send>,
+        Algorithm::Spectral => box FramedDetector::new(SpectralClassifier::new(config.threshold, rate, frame_len),
+                                                       frame_len, config.init_frames, config.end_frames) as Box<VoiceDetector + Send>,
+        Algorithm::Gmm => box FramedDetector::new(GmmClassifier::new(config.threshold, rate, frame_len),
+                                                  frame_len, config.init_frames, config.end_frames) as Box<VoiceDetector + Send>
+    }
+}
@@ -0,0 +1,154 @@
+use std::num::Float;
+
+use dsp::{fft, dbfs};
+use frames::{running_average, FrameClassifier};
+use NoiseProfile;
+
+/// Above this spectral flatness, a frame is noise-like (fans, hiss, rumble)
+const FLATNESS_MAX: f64 = 0.35;
+/// Above this zero-crossing rate, a frame is mostly high-frequency noise
+const ZCR_MAX: f64 = 0.45;
+/// Band where the flatness is measured, in Hz
+const BAND_LOW: f64 = 100.0;
+const BAND_HIGH: f64 = 4000.0;
+
+/**
+ * A frame is speech if it is louder than the noise floor by the threshold (dB),
+ * its spectrum in the speech band is not flat, and its zero-crossing rate is not
+ * that of broadband noise. Steady background noise is tracked as the noise floor.
+ */
+pub struct SpectralClassifier {
+    threshold: f64,
+    rate: u32,
+    fft_size: uint,
+    energy: f64,
+    noise_floor: f64,
+    noise_frames: uint
+}
+
+impl SpectralClassifier {
+    pub fn new(threshold: f64, rate: u32, frame_len: uint) -> SpectralClassifier {
+        SpectralClassifier {
+            threshold: threshold,
+            rate: rate,
+            fft_size: fft::fft_size(frame_len),
+            energy: -100.0,
+            noise_floor: -100.0,
+            noise_frames: 0
+        }
+    }
+
+    fn update_noise_floor(&mut self, energy: f64) {
+        self.noise_floor = if self.noise_frames == 0 || energy < self.noise_floor {
+            energy
+        } else {
+            running_average(self.noise_floor, energy, self.noise_frames)
+        };
+        self.noise_frames += 1;
+    }
+}
+
+fn zero_crossing_rate(frame: &[f64]) -> f64 {
+    let crossings = frame.windows(2).filter(|w| (w[0] >= 0.0) != (w[1] >= 0.0)).count();
+    crossings as f64 / (frame.len() - 1) as f64
+}
+
+/**
+ * Geometric mean over arithmetic mean of the power spectrum: close to 1 for
+ * white noise, close to 0 for harmonic sounds.
+ */
+fn spectral_flatness(spectrum: &[f64]) -> f64 {
+    let n = spectrum.len() as f64;
+    let log_sum = spectrum.iter().fold(0f64, |acc, &p| acc + (p + 1e-12).ln());
+    let sum = spectrum.iter().fold(0f64, |acc, &p| acc + p + 1e-12);
+    (log_sum / n).exp() / (sum / n)
+}
+
+impl FrameClassifier for SpectralClassifier {
+    fn is_speech(&mut self, frame: &[f64], learning: bool, talking: bool) -> bool {
+        let energy = dbfs(frame);
+        self.energy = energy;
+        if learning {
+            self.update_noise_floor(energy);
+            return false;
+        }
+        let spectrum = fft::power_spectrum(frame, self.fft_size);
+        let bin_hz = self.rate as f64 / self.fft_size as f64;
+        let low = (BAND_LOW / bin_hz) as uint;
+        let high = (BAND_HIGH / bin_hz) as uint;
+        let high = if high >= spectrum.len() { spectrum.len() - 1 } else { high };
+        let flatness = spectral_flatness(spectrum.slice(low, high + 1));
+        let speech = energy - self.noise_floor >= self.threshold &&
+            flatness < FLATNESS_MAX &&
+            zero_crossing_rate(frame) < ZCR_MAX;
+        if !speech && !talking {
+            self.update_noise_floor(energy);
+        }
+        speech
+    }
+
+    fn levels(&self) -> (f64, f64) {
+        (self.energy, self.noise_floor)
+    }
+
+    fn noise_profile(&self) -> NoiseProfile {
+        NoiseProfile {
+            noise_floor: self.noise_floor,
+            bands: Vec::new()
+        }
+    }
+
+    fn set_noise_profile(&mut self, profile: &NoiseProfile) -> bool {
+        self.noise_floor = profile.noise_floor;
+        // weigh it like a fully learned floor
+        self.noise_frames = 10;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testing;
+    use frames::FrameClassifier;
+    use {new_detector, DetectorConfig, Algorithm};
+    use super::SpectralClassifier;
+
+    fn learned() -> SpectralClassifier {
+        let mut classifier = SpectralClassifier::new(8.0, testing::RATE, 160);
+        let noise = testing::to_f64(testing::noise(0.01, 300).as_slice());
+        for frame in noise.chunks(160) {
+            assert!(!classifier.is_speech(frame, true, false));
+        }
+        classifier
+    }
+
+    #[test]
+    fn tone_is_speech_and_loud_noise_is_not() {
+        let mut classifier = learned();
+        let tone = testing::to_f64(testing::tone(440.0, 0.5, 10).as_slice());
+        assert!(classifier.is_speech(tone.as_slice(), false, false));
+        // as loud as the tone, but flat
+        let noise = testing::to_f64(testing::noise(0.8, 10).as_slice());
+        assert!(!classifier.is_speech(noise.as_slice(), false, false));
+        let quiet = testing::to_f64(testing::noise(0.01, 10).as_slice());
+        assert!(!classifier.is_speech(quiet.as_slice(), false, false));
+    }
+
+    #[test]
+    fn speech_ends_after_the_tone() {
+        let mut detector = new_detector(&DetectorConfig::new(Algorithm::Spectral), testing::RATE);
+        let signal = testing::concat(vec![testing::noise(0.01, 300), testing::tone(440.0, 0.5, 1000), testing::noise(0.01, 1000)]);
+        let (end, talked) = testing::end_of_speech(&mut *detector, signal.as_slice());
+        assert!(talked);
+        // the tone ends at 1300ms, then 30 frames of background noise
+        let end = end.expect("no end of speech");
+        assert!(end >= 1600 && end <= 1650, "end of speech at {}ms", end);
+    }
+
+    #[test]
+    fn loud_noise_is_not_speech() {
+        let mut detector = new_detector(&DetectorConfig::new(Algorithm::Spectral), testing::RATE);
+        let signal = testing::concat(vec![testing::noise(0.01, 300), testing::noise(0.8, 1000)]);
+        assert_eq!(testing::end_of_speech(&mut *detector, signal.as_slice()), (None, false));
+    }
+}
@@ -0,0 +1,73 @@
+/*!
+ * Test signals, generated like wit's SyntheticSource: 16-bit samples of
+ * silence, sine tones and uniform noise with a fixed seed.
+ */
+
+use std::num::Float;
+use std::f64::consts::PI;
+
+use VoiceDetector;
+
+pub const RATE: u32 = 16000;
+
+fn samples(duration_ms: u32) -> uint {
+    (duration_ms as u64 * RATE as u64 / 1000) as uint
+}
+
+fn to_i16(value: f64) -> i16 {
+    (value.max(-1.0).min(1.0) * 32767.0) as i16
+}
+
+pub fn silence(duration_ms: u32) -> Vec<i16> {
+    Vec::from_elem(samples(duration_ms), 0i16)
+}
+
+pub fn tone(frequency: f64, amplitude: f64, duration_ms: u32) -> Vec<i16> {
+    range(0, samples(duration_ms)).map(|i| {
+        let t = i as f64 / RATE as f64;
+        to_i16(amplitude * (2.0 * PI * frequency * t).sin())
+    }).collect()
+}
+
+pub fn noise(amplitude: f64, duration_ms: u32) -> Vec<i16> {
+    let mut seed = 0x12345678u32;
+    range(0, samples(duration_ms)).map(|_| {
+        // xorshift
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        to_i16(amplitude * ((seed as f64 / ::std::u32::MAX as f64) * 2.0 - 1.0))
+    }).collect()
+}
+
+/**
+ * Samples in [-1, 1], as the frame classifiers get them.
+ */
+pub fn to_f64(samples: &[i16]) -> Vec<f64> {
+    samples.iter().map(|&s| s as f64 / 32768.0).collect()
+}
+
+pub fn concat(parts: Vec<Vec<i16>>) -> Vec<i16> {
+    let mut all = Vec::new();
+    for part in parts.iter() {
+        all.push_all(part.as_slice());
+    }
+    all
+}
+
+/**
+ * Feed the samples 10ms at a time. Returns the time of the end of speech in
+ * ms, and whether speech was detected at all before it.
+ */
+pub fn end_of_speech(detector: &mut VoiceDetector, signal: &[i16]) -> (Option<u32>, bool) {
+    let chunk = samples(10);
+    let mut talked = false;
+    for (i, frame) in signal.chunks(chunk).enumerate() {
+        let still_talking = detector.still_talking(frame);
+        talked = talked || detector.talking();
+        if !still_talking {
+            return (Some((i as u32 + 1) * 10), talked);
+        }
+    }
+    (None, talked)
+}
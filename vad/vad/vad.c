@@ -62,13 +62,14 @@ static int wvs_check(wvs_state *state, double *samples, int nb_samples)
     
     action = -1;
     energy = frames_detector_esf_energy(samples, nb_samples);
+    state->last_energy = energy;
     
-    if (state->sequence <= state->init_frames) {
+    if (state->sequence <= state->init_frames && !state->calibrated) {
         detector_esf_minimum(state, energy, state->sequence);
     }
     counter = detector_esf_check_frame(state, energy);
     if (state->sequence >= state->init_frames && !counter && !state->talking) {
-        detector_esf_minimum(state, energy, state->sequence);
+        detector_esf_minimum(state, energy, state->calibrated ? 10 : state->sequence);
     }
     memory_push(state->previous_state, state->previous_state_maxlen, counter);
     if (state->sequence < state->init_frames) {
@@ -90,15 +91,21 @@ static int wvs_check(wvs_state *state, double *samples, int nb_samples)
 
 
 wvs_state *wvs_init(double threshold, int sample_rate)
+{
+    return wvs_init_with(threshold, sample_rate, 30, 30);
+}
+
+wvs_state *wvs_init_with(double threshold, int sample_rate, int init_frames, int end_frames)
 {
     wvs_state *state;
     
     state = malloc(sizeof(*state));
     state->sequence = 0;
     state->min_initialized = 0;
-    state->init_frames = 30;
-    state->energy_threshold = 8.0;
-    state->previous_state_maxlen = 30;
+    state->calibrated = 0;
+    state->init_frames = init_frames;
+    state->energy_threshold = threshold;
+    state->previous_state_maxlen = end_frames < 10 ? 10 : end_frames;
     state->previous_state = malloc(sizeof(*state->previous_state) * state->previous_state_maxlen);
     state->talking = 0;
     state->sample_rate = sample_rate;
@@ -106,10 +113,40 @@ wvs_state *wvs_init(double threshold, int sample_rate)
     state->samples = malloc(sizeof(*state->samples) * state->samples_per_frame);
     state->current_nb_samples = 0;
     state->min_energy = 0.0;
+    state->last_energy = 0.0;
+    memset(state->previous_state, 0, sizeof(*state->previous_state) * state->previous_state_maxlen);
     
     return state;
 }
 
+int wvs_frames(wvs_state *state)
+{
+    return state->sequence;
+}
+
+double wvs_energy(wvs_state *state)
+{
+    return state->last_energy;
+}
+
+double wvs_min_energy(wvs_state *state)
+{
+    return state->min_energy;
+}
+
+void wvs_set_min_energy(wvs_state *state, double min_energy)
+{
+    state->min_energy = min_energy;
+    state->min_initialized = 1;
+    state->calibrated = 1;
+    state->init_frames = 0;
+}
+
+int wvs_talking(wvs_state *state)
+{
+    return state->talking;
+}
+
 void wvs_clean(wvs_state *state)
 {
     free(state->samples);
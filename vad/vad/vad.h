@@ -33,6 +33,9 @@ typedef struct  {
     /* is the environment initialized? */
     int min_initialized;
     
+    /* was min_energy set by wvs_set_min_energy? */
+    int calibrated;
+    
     /* frame number needed for initialization */
     int init_frames;
     
@@ -40,6 +43,9 @@ typedef struct  {
     
     double min_energy;
     
+    /* energy of the last frame */
+    double last_energy;
+    
     int *previous_state;
     
     int previous_state_maxlen;
@@ -62,6 +68,37 @@ int wvs_still_talking(wvs_state *state, int16_t *samples, int nb_samples);
 
 wvs_state *wvs_init(double threshold, int sample_rate);
 
+/**
+ * wvs_init_with - like wvs_init, with the detector windows
+ *  @init_frames: number of frames used to learn the noise floor
+ *  @end_frames: number of silent frames ending speech
+ */
+wvs_state *wvs_init_with(double threshold, int sample_rate, int init_frames, int end_frames);
+
+/**
+ * Accessors for analysis: number of frames processed, energy of the last frame
+ * and noise floor (both as positive attenuations, in dB below full scale).
+ */
+int wvs_frames(wvs_state *state);
+double wvs_energy(wvs_state *state);
+double wvs_min_energy(wvs_state *state);
+
+/**
+ * wvs_set_min_energy - start from a noise floor measured in an earlier session
+ *  @state: the detector state, before any samples are processed
+ *  @min_energy: the noise floor, as returned by wvs_min_energy
+ *
+ * The init_frames learning frames are skipped. The noise floor still adapts
+ * to the non-speech frames.
+ */
+void wvs_set_min_energy(wvs_state *state, double min_energy);
+
+/**
+ * wvs_talking - whether speech is currently detected
+ *  @state: the detector state
+ */
+int wvs_talking(wvs_state *state);
+
 /**
  * wvs_clean - clean a wvs_state* structure
  *  @state: the structure to free.